@@ -0,0 +1,109 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+//! Compares the size and speed of each [`ClaimStorageFormat`].
+//!
+//! Run with `make bench`, which also prints the size of each.
+
+#![feature(test)]
+
+extern crate test;
+
+use plasma_protocol::*;
+use std::str::FromStr;
+use test::{black_box, Bencher};
+
+const FORMATS: [ClaimStorageFormat; 3] = [
+    ClaimStorageFormat::Json,
+    ClaimStorageFormat::CompactJson,
+    ClaimStorageFormat::Bitcode,
+];
+
+/// A regular player of several games and realms.
+fn claims() -> ClaimSet {
+    let mut claims = ClaimSet::default();
+    let value = |value: u64| ClaimValue {
+        date_expires: None,
+        date_updated: NonZeroUnixMillis::from_i64(1_700_000_000_000 + value as i64 * 1000),
+        value,
+    };
+    claims.global.insert(ScopeClaimKey::streak().key, value(12));
+    let keys = [
+        ScopeClaimKey::high_score(),
+        ScopeClaimKey::rank(),
+        ScopeClaimKey::days(),
+        ScopeClaimKey::kills(),
+        ScopeClaimKey::superior_kills(),
+        ScopeClaimKey::victories(),
+    ];
+    for (i, game_id) in ["Mk48", "Kiomet", "Netquel", "Foobar"]
+        .into_iter()
+        .enumerate()
+    {
+        let game_id = GameId::from_str(game_id).unwrap();
+        for (j, key) in keys.into_iter().enumerate() {
+            let key = GameClaimKey {
+                game_id,
+                key: key.key,
+            };
+            claims.game.insert(key, value((i * 100 + j) as u64));
+            for realm in ["public/default", "named/foo", "named/bar"] {
+                let realm_id = RealmId::from_str(realm).unwrap();
+                claims
+                    .realm
+                    .insert(RealmClaimKey { realm_id, key }, value(j as u64));
+            }
+        }
+    }
+    claims
+}
+
+#[test]
+fn sizes() {
+    let claims = claims();
+    for format in FORMATS {
+        println!("{format:?}: {} bytes", claims.to_storage(format).len());
+    }
+}
+
+fn encode(b: &mut Bencher, format: ClaimStorageFormat) {
+    let claims = claims();
+    b.bytes = claims.to_storage(format).len() as u64;
+    b.iter(|| black_box(&claims).to_storage(format));
+}
+
+fn decode(b: &mut Bencher, format: ClaimStorageFormat) {
+    let stored = claims().to_storage(format);
+    b.bytes = stored.len() as u64;
+    b.iter(|| ClaimSet::from_storage(black_box(&stored)).unwrap());
+}
+
+#[bench]
+fn encode_json(b: &mut Bencher) {
+    encode(b, ClaimStorageFormat::Json);
+}
+
+#[bench]
+fn encode_compact_json(b: &mut Bencher) {
+    encode(b, ClaimStorageFormat::CompactJson);
+}
+
+#[bench]
+fn encode_bitcode(b: &mut Bencher) {
+    encode(b, ClaimStorageFormat::Bitcode);
+}
+
+#[bench]
+fn decode_json(b: &mut Bencher) {
+    decode(b, ClaimStorageFormat::Json);
+}
+
+#[bench]
+fn decode_compact_json(b: &mut Bencher) {
+    decode(b, ClaimStorageFormat::CompactJson);
+}
+
+#[bench]
+fn decode_bitcode(b: &mut Bencher) {
+    decode(b, ClaimStorageFormat::Bitcode);
+}
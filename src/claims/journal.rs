@@ -0,0 +1,316 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{ClaimSchemaError, ClaimValue, ScopeClaimKey};
+use crate::{is_default, ArenaId, GameId, NonZeroUnixMillis, RealmId, ServerId};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+
+/// What happened to a claim during a merge, see [`ClaimJournal`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum ClaimChange {
+    /// The claim didn't exist, so the proposed value was taken as is.
+    Inserted,
+    /// The proposed value was merged into the claim, which changed.
+    Merged,
+    /// Merging the proposed value didn't change the claim, e.g. a lower `Max`.
+    Ignored,
+    /// A [`ClaimRegistry`](crate::ClaimRegistry) rejected the proposed value.
+    Rejected { reason: ClaimSchemaError },
+    /// The claim expired, so it was evicted before merging.
+    Expired,
+}
+
+/// Where a merged [`ClaimSubset`](crate::ClaimSubset) came from.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ClaimSource {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub server_id: Option<ServerId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub arena_id: Option<ArenaId>,
+}
+
+/// A change to one claim of a [`ClaimSet`](crate::ClaimSet).
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ClaimJournalEntry {
+    pub timestamp: NonZeroUnixMillis,
+    pub change: ClaimChange,
+    /// Scope, name and the aggregation that was applied.
+    pub key: ScopeClaimKey,
+    /// Game of a game or realm claim.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub game_id: Option<GameId>,
+    /// Realm of a realm claim.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub realm_id: Option<RealmId>,
+    /// Default for expiries, which have no source.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub source: ClaimSource,
+    /// Before the change, if the claim existed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub old: Option<ClaimValue>,
+    /// Sent by the source, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proposed: Option<ClaimValue>,
+    /// After the change, if the claim still exists.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub new: Option<ClaimValue>,
+}
+
+/// Serializable contents of a [`ClaimJournal`], e.g. to attach to a support ticket.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ClaimJournalExport {
+    /// Oldest first.
+    pub entries: Vec<ClaimJournalEntry>,
+    /// Older entries that no longer fit.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub dropped: u64,
+}
+
+/// Records what [`ClaimSet::merge_journaled`](crate::ClaimSet::merge_journaled) and
+/// [`ClaimSet::merge_registered_journaled`](crate::ClaimSet::merge_registered_journaled)
+/// did, keeping the most recent `capacity` entries.
+#[derive(Clone, Debug)]
+pub struct ClaimJournal {
+    /// Oldest first.
+    entries: VecDeque<ClaimJournalEntry>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl Default for ClaimJournal {
+    fn default() -> Self {
+        Self::new(1024)
+    }
+}
+
+impl ClaimJournal {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity.min(1024)),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// How many entries were dropped to make room for newer ones.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Appends an entry, dropping the oldest if full.
+    pub fn record(&mut self, entry: ClaimJournalEntry) {
+        if self.capacity == 0 {
+            self.dropped += 1;
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+            self.dropped += 1;
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// Oldest first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &ClaimJournalEntry> + '_ {
+        self.entries.iter()
+    }
+
+    /// Entries of a claim, in any game or realm, oldest first.
+    pub fn history<'a>(
+        &'a self,
+        key: &'a ScopeClaimKey,
+    ) -> impl DoubleEndedIterator<Item = &'a ClaimJournalEntry> + 'a {
+        self.entries.iter().filter(move |e| e.key == *key)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.dropped = 0;
+    }
+
+    pub fn export(&self) -> ClaimJournalExport {
+        ClaimJournalExport {
+            entries: self.entries.iter().cloned().collect(),
+            dropped: self.dropped,
+        }
+    }
+}
+
+impl Extend<ClaimJournalEntry> for ClaimJournal {
+    fn extend<T: IntoIterator<Item = ClaimJournalEntry>>(&mut self, iter: T) {
+        for entry in iter {
+            self.record(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ClaimAggregation, ClaimChange, ClaimJournal, ClaimJournalEntry, ClaimJournalExport,
+        ClaimKey, ClaimName, ClaimRegistry, ClaimSchema, ClaimSchemaError, ClaimScope, ClaimSet,
+        ClaimSource, ClaimSubset, ClaimValue, GameId, NonZeroUnixMillis, RealmId, ScopeClaimKey,
+        ServerId, UnixTime,
+    };
+    use std::str::FromStr;
+
+    fn key(name: &str, aggregation: ClaimAggregation) -> ScopeClaimKey {
+        ScopeClaimKey {
+            scope: ClaimScope::Game,
+            key: ClaimKey {
+                name: ClaimName::new(name),
+                aggregation,
+            },
+        }
+    }
+
+    fn value(value: u64, date_expires: Option<NonZeroUnixMillis>) -> ClaimValue {
+        ClaimValue {
+            date_expires,
+            date_updated: NonZeroUnixMillis::now(),
+            value,
+        }
+    }
+
+    fn subset(claims: &[(ScopeClaimKey, ClaimValue)]) -> ClaimSubset {
+        ClaimSubset {
+            claims: claims.iter().cloned().collect(),
+            date_synchronized: NonZeroUnixMillis::MIN,
+        }
+    }
+
+    fn changes(journal: &ClaimJournal) -> Vec<(ClaimChange, Option<u64>, Option<u64>)> {
+        journal
+            .iter()
+            .map(|e| {
+                let value = |v: &Option<ClaimValue>| v.as_ref().map(|v| v.value);
+                (e.change.clone(), value(&e.old), value(&e.new))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn merge() {
+        let (game_id, realm_id) = (GameId::from_str("Mk48").unwrap(), RealmId::PublicDefault);
+        let score = key("score", ClaimAggregation::Max);
+        let boost = key("boost", ClaimAggregation::New);
+        let source = ClaimSource {
+            server_id: Some(ServerId::from_str("Cloud/1").unwrap()),
+            arena_id: None,
+        };
+        let mut claims = ClaimSet::default();
+        let mut journal = ClaimJournal::default();
+        let merge = |claims: &mut ClaimSet, journal: &mut ClaimJournal, new: &[_]| {
+            claims.merge_journaled(&subset(new), game_id, realm_id, source, journal);
+        };
+
+        let past = NonZeroUnixMillis::from_i64(1_700_000_000_000);
+        merge(
+            &mut claims,
+            &mut journal,
+            &[(score, value(5, None)), (boost, value(1, Some(past)))],
+        );
+        merge(&mut claims, &mut journal, &[(score, value(3, None))]);
+        merge(&mut claims, &mut journal, &[(score, value(7, None))]);
+
+        let mut changes = changes(&journal);
+        changes[..2].sort_by_key(|(_, _, new)| *new);
+        assert_eq!(
+            changes,
+            [
+                (ClaimChange::Inserted, None, Some(1)),
+                (ClaimChange::Inserted, None, Some(5)),
+                (ClaimChange::Expired, Some(1), None),
+                (ClaimChange::Ignored, Some(5), Some(5)),
+                (ClaimChange::Merged, Some(5), Some(7)),
+            ]
+        );
+        let history: Vec<&ClaimJournalEntry> = journal.history(&score).collect();
+        assert_eq!(history.len(), 3);
+        assert!(history
+            .iter()
+            .all(|e| e.source == source && e.game_id == Some(game_id) && e.realm_id.is_none()));
+        assert_eq!(history[1].proposed.as_ref().unwrap().value, 3);
+    }
+
+    #[test]
+    fn rejected() {
+        let (game_id, realm_id) = (GameId::from_str("Mk48").unwrap(), RealmId::PublicDefault);
+        let level = key("level", ClaimAggregation::Max);
+        let mut registry = ClaimRegistry::new();
+        registry
+            .register(ClaimSchema {
+                max: Some(10),
+                ..ClaimSchema::new(level)
+            })
+            .unwrap();
+        let mut claims = ClaimSet::default();
+        let mut journal = ClaimJournal::default();
+        claims.merge_registered_journaled(
+            &subset(&[(level, value(11, None))]),
+            &registry,
+            game_id,
+            realm_id,
+            Default::default(),
+            &mut journal,
+        );
+        assert!(claims.is_empty());
+        assert_eq!(
+            changes(&journal),
+            [(
+                ClaimChange::Rejected {
+                    reason: ClaimSchemaError::OutOfRange
+                },
+                None,
+                None
+            )]
+        );
+        assert_eq!(
+            journal.export().entries[0].proposed.as_ref().unwrap().value,
+            11
+        );
+    }
+
+    #[test]
+    fn ring() {
+        let score = key("score", ClaimAggregation::Max);
+        let mut journal = ClaimJournal::new(2);
+        journal.extend((0..5).map(|n| ClaimJournalEntry {
+            timestamp: NonZeroUnixMillis::now(),
+            change: ClaimChange::Inserted,
+            key: score,
+            game_id: None,
+            realm_id: None,
+            source: Default::default(),
+            old: None,
+            proposed: None,
+            new: Some(value(n, None)),
+        }));
+        assert_eq!(journal.len(), 2);
+        assert_eq!(journal.dropped(), 3);
+        let export = journal.export();
+        assert_eq!(changes(&journal)[0].2, Some(3));
+        let json = serde_json::to_string(&export).unwrap();
+        assert_eq!(
+            serde_json::from_str::<ClaimJournalExport>(&json).unwrap(),
+            export
+        );
+
+        journal.clear();
+        assert!(journal.is_empty());
+        assert_eq!(journal.dropped(), 0);
+    }
+}
@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use super::{ClaimAggregation, ClaimScope, ClaimValue};
-use crate::{impl_wrapper_str, serde_str, FromStrVisitor, GameId, RealmId, SkuId};
+use crate::{impl_wrapper_str, serde_str, FromStrVisitor, GameId, RealmId, ServerId, SkuId};
 use arrayvec::ArrayString;
 use bitcode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
@@ -33,6 +33,37 @@ pub struct ClaimKey {
     pub aggregation: ClaimAggregation,
 }
 
+impl ClaimKey {
+    /// The claim, named `name@server_id`, that holds the contribution of `server_id`
+    /// to this claim, if its aggregation [`ClaimAggregation::has_contributions`]. `None`
+    /// if the name would be too long, which [`ClaimRegistry::register`](crate::ClaimRegistry::register)
+    /// rejects.
+    pub fn contribution(self, server_id: ServerId) -> Option<Self> {
+        if !self.aggregation.has_contributions() || self.name.contains('@') {
+            return None;
+        }
+        Some(Self {
+            name: ClaimName::from_str(&format!("{}@{server_id}", self.name)).ok()?,
+            aggregation: self.aggregation,
+        })
+    }
+
+    /// The claim and server of a [`Self::contribution`].
+    pub fn contributor(&self) -> Option<(Self, ServerId)> {
+        if !self.aggregation.has_contributions() {
+            return None;
+        }
+        let (name, server_id) = self.name.split_once('@')?;
+        Some((
+            Self {
+                name: ClaimName::from_str(name).ok()?,
+                aggregation: self.aggregation,
+            },
+            ServerId::from_str(server_id).ok()?,
+        ))
+    }
+}
+
 impl Display for ClaimKey {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(f, "{}/{}", self.name, self.aggregation)
@@ -122,7 +153,7 @@ impl FromStr for RealmClaimKey {
     type Err = RealmClaimKeyError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let (realm_id, key) = split_once_nth(s, '/', 2).ok_or(RealmClaimKeyError::MissingSlash)?;
+        let (realm_id, key) = split_once_nth(s, '/', 1).ok_or(RealmClaimKeyError::MissingSlash)?;
         Ok(Self {
             realm_id: RealmId::from_str(realm_id).map_err(RealmClaimKeyError::RealmId)?,
             key: GameClaimKey::from_str(key).map_err(RealmClaimKeyError::Key)?,
@@ -265,6 +296,8 @@ impl ScopeClaimKey {
     /// - algorithm = if within 24h of expiration,
     ///   increment value and set expiration to
     ///   midnight tomorrow
+    ///
+    /// See [`ClaimSet::play`](crate::ClaimSet::play).
     pub fn streak() -> Self {
         Self {
             scope: ClaimScope::Global,
@@ -275,7 +308,8 @@ impl ScopeClaimKey {
         }
     }
 
-    /// Number of distinct calendar days a player played a given game.
+    /// Number of distinct calendar days a player played a given game, see
+    /// [`ClaimSet::play`](crate::ClaimSet::play).
     pub fn days() -> Self {
         Self {
             scope: ClaimScope::Game,
@@ -362,3 +396,26 @@ serde_str!(ClaimValue);
 serde_str!(GameClaimKey);
 serde_str!(RealmClaimKey);
 serde_str!(ScopeClaimKey);
+
+#[cfg(test)]
+mod tests {
+    use crate::{GameClaimKey, RealmClaimKey, RealmId};
+    use std::str::FromStr;
+
+    #[test]
+    fn realm_claim_key_string_forms() {
+        let game_key = GameClaimKey::from_str("Mk48/kills/Max").ok().unwrap();
+        for (realm_id, s) in [
+            ("public/default", "public/default/Mk48/kills/Max"),
+            ("named/foo", "named/foo/Mk48/kills/Max"),
+        ] {
+            let key = RealmClaimKey {
+                realm_id: RealmId::from_str(realm_id).unwrap(),
+                key: game_key,
+            };
+            assert_eq!(key.to_string(), s);
+            // The realm ID, which contains a slash, ends at the second slash.
+            assert_eq!(RealmClaimKey::from_str(s).ok(), Some(key));
+        }
+    }
+}
@@ -1,13 +1,21 @@
 // SPDX-FileCopyrightText: 2024 Softbear, Inc.
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+mod journal;
 mod keys;
+mod schema;
+mod storage;
+mod streak;
 mod subsets;
 mod values;
 
+pub use journal::{ClaimChange, ClaimJournal, ClaimJournalEntry, ClaimJournalExport, ClaimSource};
 pub use keys::{
     ClaimKey, ClaimKeyError, ClaimName, GameClaimKey, GameClaimKeyError, RealmClaimKey,
     RealmClaimKeyError, ScopeClaimKey, ScopeClaimKeyError,
 };
+pub use schema::{ClaimRegistry, ClaimSchema, ClaimSchemaError};
+pub use storage::{ClaimStorageError, ClaimStorageFormat};
+pub use streak::UtcOffset;
 pub use subsets::{ClaimScope, ClaimSet, ClaimSubset, PublicClaims};
 pub use values::{ClaimAggregation, ClaimValue, ClaimValueError};
@@ -0,0 +1,425 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{ClaimScope, ClaimSet, ClaimSubset, ClaimValue, PublicClaims, ScopeClaimKey};
+use crate::{
+    is_default, GameClaimKey, GameId, NonZeroUnixMillis, ServerId, ServerKind, ServerNumber,
+    UnixTime,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use strum::IntoEnumIterator;
+
+/// Declares the type, limits and visibility of a claim, see [`ClaimRegistry`].
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ClaimSchema {
+    /// Scope, name and aggregation.
+    pub key: ScopeClaimKey,
+    /// Smallest allowed value.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub min: u64,
+    /// Largest allowed value, if limited. For a
+    /// [`ClaimAggregation::Sum`](crate::ClaimAggregation::Sum) or
+    /// [`ClaimAggregation::Counter`](crate::ClaimAggregation::Counter), `min` and `max`
+    /// apply to each server's contribution, not to their sum.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<u64>,
+    /// Milliseconds after `date_updated` that the claim expires, if it doesn't say.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expiry: Option<i64>,
+    /// Included in [`PublicClaims`], which other players can see. Only for global and
+    /// game claims.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub public: bool,
+    /// Kept from the player's own client, e.g. for server-side bookkeeping.
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub hidden: bool,
+}
+
+impl ClaimSchema {
+    /// Any value, never expires, private but visible to the player.
+    pub fn new(key: ScopeClaimKey) -> Self {
+        Self {
+            key,
+            min: 0,
+            max: None,
+            expiry: None,
+            public: false,
+            hidden: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize, strum::Display)]
+pub enum ClaimSchemaError {
+    #[strum(to_string = "unregistered claim")]
+    Unregistered,
+    #[strum(to_string = "value out of range")]
+    OutOfRange,
+    #[strum(to_string = "empty range")]
+    EmptyRange,
+    #[strum(to_string = "registered with another aggregation")]
+    ConflictingAggregation,
+    /// A `Sum`, `Counter` or `Lww` was proposed without any of its contributions, which
+    /// it is derived from.
+    #[strum(to_string = "no contribution")]
+    NoContribution,
+    /// A `Sum`, `Counter` or `Lww` name leaves no room for the `@server_id` of its
+    /// [`ClaimKey::contribution`](crate::ClaimKey::contribution).
+    #[strum(to_string = "name too long")]
+    NameTooLong,
+    /// [`PublicClaims`] has no realm claims.
+    #[strum(to_string = "public realm claim")]
+    PublicRealmClaim,
+}
+
+/// Claims a game declares, to validate claims during [`ClaimSet::merge_registered`]
+/// and to tell Plasma about them.
+///
+/// Unregistered claims are allowed unless the registry is strict.
+#[derive(Clone, Debug, Default)]
+pub struct ClaimRegistry {
+    schemas: HashMap<ScopeClaimKey, ClaimSchema>,
+    strict: bool,
+}
+
+impl ClaimRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The claims of the [`ScopeClaimKey`] constructors, other than products.
+    pub fn well_known() -> Self {
+        let mut registry = Self::new();
+        let public = |key| ClaimSchema {
+            public: true,
+            ..ClaimSchema::new(key)
+        };
+        for schema in [
+            public(ScopeClaimKey::high_score()),
+            public(ScopeClaimKey::rank()),
+            public(ScopeClaimKey::streak()),
+            ClaimSchema {
+                max: Some(1),
+                ..ClaimSchema::new(ScopeClaimKey::announcement_preference())
+            },
+            ClaimSchema::new(ScopeClaimKey::days()),
+            ClaimSchema::new(ScopeClaimKey::kills()),
+            ClaimSchema::new(ScopeClaimKey::superior_kills()),
+            ClaimSchema::new(ScopeClaimKey::inferior_kills()),
+            ClaimSchema::new(ScopeClaimKey::victories()),
+            ClaimSchema::new(ScopeClaimKey::superior_victories()),
+            ClaimSchema::new(ScopeClaimKey::inferior_victories()),
+        ] {
+            registry.register(schema).unwrap();
+        }
+        registry
+    }
+
+    /// Rebuilds a registry from [`Self::dump`].
+    pub fn from_dump(
+        schemas: impl IntoIterator<Item = ClaimSchema>,
+    ) -> Result<Self, ClaimSchemaError> {
+        let mut registry = Self::new();
+        for schema in schemas {
+            registry.register(schema)?;
+        }
+        Ok(registry)
+    }
+
+    /// Whether unregistered claims are rejected.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Adds or replaces a claim. A name may only have one aggregation per scope.
+    pub fn register(&mut self, schema: ClaimSchema) -> Result<(), ClaimSchemaError> {
+        if schema.max.is_some_and(|max| max < schema.min) {
+            return Err(ClaimSchemaError::EmptyRange);
+        }
+        if schema.public && schema.key.scope == ClaimScope::Realm {
+            return Err(ClaimSchemaError::PublicRealmClaim);
+        }
+        let key = schema.key.key;
+        if key.aggregation.has_contributions()
+            && ServerKind::iter().any(|kind| {
+                let number = ServerNumber::new(u8::MAX).unwrap();
+                key.contribution(ServerId { kind, number }).is_none()
+            })
+        {
+            return Err(ClaimSchemaError::NameTooLong);
+        }
+        if self.schemas.keys().any(|k| {
+            k.scope == schema.key.scope && k.key.name == schema.key.key.name && *k != schema.key
+        }) {
+            return Err(ClaimSchemaError::ConflictingAggregation);
+        }
+        self.schemas.insert(schema.key, schema);
+        Ok(())
+    }
+
+    /// Contributions to a counter have the counter's schema.
+    pub fn get(&self, key: &ScopeClaimKey) -> Option<&ClaimSchema> {
+        self.schemas.get(key).or_else(|| {
+            let (counter, _) = key.key.contributor()?;
+            self.schemas.get(&ScopeClaimKey {
+                scope: key.scope,
+                key: counter,
+            })
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.schemas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schemas.is_empty()
+    }
+
+    /// Whether the player's own client may see a claim.
+    pub fn is_client_visible(&self, key: &ScopeClaimKey) -> bool {
+        !self.get(key).is_some_and(|s| s.hidden)
+    }
+
+    /// Checks a claim against its schema, applying its default expiry.
+    pub fn validate(
+        &self,
+        key: &ScopeClaimKey,
+        value: &ClaimValue,
+    ) -> Result<ClaimValue, ClaimSchemaError> {
+        let Some(schema) = self.get(key) else {
+            return if self.strict {
+                Err(ClaimSchemaError::Unregistered)
+            } else {
+                Ok(value.clone())
+            };
+        };
+        if value.value < schema.min || schema.max.is_some_and(|max| value.value > max) {
+            return Err(ClaimSchemaError::OutOfRange);
+        }
+        let mut value = value.clone();
+        if let Some(expiry) = schema.expiry {
+            value.date_expires.get_or_insert_with(|| {
+                NonZeroUnixMillis::from_i64(value.date_updated.to_i64().saturating_add(expiry))
+            });
+        }
+        Ok(value)
+    }
+
+    /// The valid claims of `subset`, and why the others aren't.
+    pub fn validate_subset(
+        &self,
+        subset: &ClaimSubset,
+    ) -> (ClaimSubset, Vec<(ScopeClaimKey, ClaimSchemaError)>) {
+        let mut invalid = Vec::new();
+        let claims = subset
+            .claims
+            .iter()
+            .filter_map(|(key, value)| match self.validate(key, value) {
+                Ok(value) => Some((*key, value)),
+                Err(e) => {
+                    invalid.push((*key, e));
+                    None
+                }
+            })
+            .collect();
+        let valid = ClaimSubset {
+            claims,
+            date_synchronized: subset.date_synchronized,
+        };
+        (valid, invalid)
+    }
+
+    /// [`ClaimSet::public_claims`] plus the other public game and global claims.
+    pub fn public_claims(&self, claims: &ClaimSet, game_id: GameId) -> PublicClaims {
+        let mut public_claims = claims.public_claims(game_id);
+        let built_in = [
+            ScopeClaimKey::high_score(),
+            ScopeClaimKey::rank(),
+            ScopeClaimKey::streak(),
+        ];
+        for schema in self
+            .schemas
+            .values()
+            .filter(|s| s.public && !built_in.contains(&s.key))
+        {
+            let ScopeClaimKey { scope, key } = schema.key;
+            let value = match scope {
+                ClaimScope::Global => claims.global.get(&key),
+                ClaimScope::Game => claims.game.get(&GameClaimKey { game_id, key }),
+                ClaimScope::Realm => continue,
+            };
+            if let Some(value) = value {
+                public_claims.claims.insert(key.name, value.value);
+            }
+        }
+        public_claims
+    }
+
+    /// Every schema, sorted by key, e.g. to send to Plasma.
+    pub fn dump(&self) -> Vec<ClaimSchema> {
+        let mut schemas: Vec<_> = self.schemas.values().cloned().collect();
+        schemas.sort_by_cached_key(|s| s.key.to_string());
+        schemas
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ClaimAggregation, ClaimKey, ClaimName, ClaimRegistry, ClaimSchema, ClaimSchemaError,
+        ClaimScope, ClaimSet, ClaimSubset, ClaimValue, GameId, NonZeroUnixMillis, RealmId,
+        ScopeClaimKey, UnixTime,
+    };
+    use std::str::FromStr;
+
+    fn key(name: &str, aggregation: ClaimAggregation) -> ScopeClaimKey {
+        ScopeClaimKey {
+            scope: ClaimScope::Game,
+            key: ClaimKey {
+                name: ClaimName::new(name),
+                aggregation,
+            },
+        }
+    }
+
+    fn value(value: u64) -> ClaimValue {
+        ClaimValue {
+            date_expires: None,
+            date_updated: NonZeroUnixMillis::now(),
+            value,
+        }
+    }
+
+    fn registry() -> ClaimRegistry {
+        let mut registry = ClaimRegistry::well_known();
+        registry
+            .register(ClaimSchema {
+                max: Some(0b111),
+                public: true,
+                ..ClaimSchema::new(key("medals", ClaimAggregation::Bitset))
+            })
+            .unwrap();
+        registry
+            .register(ClaimSchema {
+                expiry: Some(60 * 1000),
+                hidden: true,
+                ..ClaimSchema::new(key("cooldown", ClaimAggregation::New))
+            })
+            .unwrap();
+        registry
+    }
+
+    #[test]
+    fn register() {
+        let mut registry = registry();
+        assert_eq!(
+            registry.register(ClaimSchema::new(key("medals", ClaimAggregation::Max))),
+            Err(ClaimSchemaError::ConflictingAggregation)
+        );
+        assert_eq!(
+            registry.register(ClaimSchema {
+                min: 2,
+                max: Some(1),
+                ..ClaimSchema::new(key("empty", ClaimAggregation::Max))
+            }),
+            Err(ClaimSchemaError::EmptyRange)
+        );
+        assert_eq!(
+            registry.register(ClaimSchema {
+                public: true,
+                ..ClaimSchema::new(ScopeClaimKey {
+                    scope: ClaimScope::Realm,
+                    ..key("wins", ClaimAggregation::Max)
+                })
+            }),
+            Err(ClaimSchemaError::PublicRealmClaim)
+        );
+        // No room for "@Cloud/255".
+        assert_eq!(
+            registry.register(ClaimSchema::new(key(
+                "nineteen_characters",
+                ClaimAggregation::Counter
+            ))),
+            Err(ClaimSchemaError::NameTooLong)
+        );
+        registry
+            .register(ClaimSchema::new(key(
+                "eighteen_character",
+                ClaimAggregation::Lww,
+            )))
+            .unwrap();
+
+        let dump = registry.dump();
+        assert_eq!(dump.len(), registry.len());
+        let json = serde_json::to_string(&dump).unwrap();
+        let restored = ClaimRegistry::from_dump(serde_json::from_str::<Vec<_>>(&json).unwrap());
+        assert_eq!(restored.unwrap().dump(), dump);
+    }
+
+    #[test]
+    fn merge_registered() {
+        let mut registry = registry();
+        let (game_id, realm_id) = (GameId::from_str("Mk48").unwrap(), RealmId::PublicDefault);
+        let medals = key("medals", ClaimAggregation::Bitset);
+        let cooldown = key("cooldown", ClaimAggregation::New);
+        let unknown = key("unknown", ClaimAggregation::Max);
+        let subset = ClaimSubset {
+            claims: [
+                (medals, value(0b101)),
+                (cooldown, value(1)),
+                (unknown, value(1)),
+                (ScopeClaimKey::announcement_preference(), value(2)),
+            ]
+            .into(),
+            date_synchronized: NonZeroUnixMillis::MIN,
+        };
+
+        let (_, invalid) = registry.validate_subset(&subset);
+        assert_eq!(
+            invalid,
+            [(
+                ScopeClaimKey::announcement_preference(),
+                ClaimSchemaError::OutOfRange
+            )]
+        );
+
+        let mut claims = ClaimSet::default();
+        let (_, changed) = claims.merge_registered(&subset, &registry, game_id, realm_id);
+        assert!(changed);
+        let subset_claims = claims.subset(game_id, realm_id);
+        assert!(subset_claims[&cooldown].date_expires.is_some());
+        assert!(subset_claims.contains_key(&unknown));
+        assert!(!subset_claims.contains_key(&ScopeClaimKey::announcement_preference()));
+
+        // Both differ from what was sent, but only one is visible to the client.
+        let stale = ClaimValue {
+            date_updated: NonZeroUnixMillis::from_i64(NonZeroUnixMillis::now().to_i64() - 1000),
+            ..value(2)
+        };
+        let subset = ClaimSubset {
+            claims: [(medals, value(0b010)), (cooldown, stale)].into(),
+            date_synchronized: NonZeroUnixMillis::MIN,
+        };
+        let (change, _) = claims.merge_registered(&subset, &registry, game_id, realm_id);
+        let change = change.unwrap();
+        assert_eq!(change[&medals].value, 0b111);
+        assert!(!change.contains_key(&cooldown));
+
+        let public = registry.public_claims(&claims, game_id);
+        assert_eq!(public.claims[&ClaimName::new("medals")], 0b111);
+        assert_eq!(public.claims.len(), 1);
+
+        registry.set_strict(true);
+        let subset = ClaimSubset {
+            claims: [(medals, value(0b001)), (unknown, value(1))].into(),
+            date_synchronized: NonZeroUnixMillis::MIN,
+        };
+        let mut claims = ClaimSet::default();
+        claims.merge_registered(&subset, &registry, game_id, realm_id);
+        let subset_claims = claims.subset(game_id, realm_id);
+        assert!(subset_claims.contains_key(&medals));
+        assert!(!subset_claims.contains_key(&unknown));
+    }
+}
@@ -0,0 +1,375 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{ClaimKey, ClaimSet, ClaimValue, GameClaimKey, RealmClaimKey};
+use crate::{GameId, RealmId};
+use bitcode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::hash::Hash;
+
+/// How a [`ClaimSet`] is stored, see [`ClaimSet::to_storage`].
+///
+/// Compact formats start with their version, so they can change without breaking
+/// stored claims, and [`ClaimSet::from_storage`] reads every format, so claims
+/// migrate the next time they're saved.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ClaimStorageFormat {
+    /// The `serde` form of [`ClaimSet`], whose keys repeat game, realm and
+    /// aggregation as strings, e.g. `named/foo/Mk48/high_score/Max`.
+    Json,
+    /// A JSON array of the version and a [`ClaimSet`] whose claim keys, games and
+    /// realms are each stored once, and referred to by index.
+    CompactJson,
+    /// [`Self::BITCODE_TAG`], the version, and the bitcode of the same interned
+    /// [`ClaimSet`] as [`Self::CompactJson`].
+    Bitcode,
+}
+
+impl ClaimStorageFormat {
+    /// Can't start JSON.
+    pub const BITCODE_TAG: u8 = 0xC1;
+    /// Version of the compact formats written by [`ClaimSet::to_storage`].
+    pub const VERSION: u8 = 1;
+
+    /// Detects the format of stored claims, e.g. to find ones worth migrating.
+    pub fn of(stored: &[u8]) -> Self {
+        match stored.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(&Self::BITCODE_TAG) => Self::Bitcode,
+            Some(b'[') => Self::CompactJson,
+            _ => Self::Json,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ClaimStorageError {
+    Bitcode(String),
+    Json(String),
+    /// Written by a newer version.
+    UnsupportedVersion(u8),
+    /// A claim refers to a key, game or realm that isn't there.
+    InvalidIndex,
+}
+
+impl Display for ClaimStorageError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for ClaimStorageError {}
+
+impl From<serde_json::Error> for ClaimStorageError {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e.to_string())
+    }
+}
+
+impl From<bitcode::Error> for ClaimStorageError {
+    fn from(e: bitcode::Error) -> Self {
+        Self::Bitcode(e.to_string())
+    }
+}
+
+/// Version 1 of the compact formats. Claims refer to `keys`, `games` and `realms`
+/// by index, and are sorted so equal sets are stored identically.
+#[derive(Default, Serialize, Deserialize, Encode, Decode)]
+struct CompactClaimSet {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    keys: Vec<ClaimKey>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    games: Vec<GameId>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    realms: Vec<RealmId>,
+    /// Key and value.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    global: Vec<(u32, ClaimValue)>,
+    /// Game, key and value.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    game: Vec<(u32, u32, ClaimValue)>,
+    /// Realm, game, key and value.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    realm: Vec<(u32, u32, u32, ClaimValue)>,
+}
+
+/// Sorted, distinct values and the index of each.
+fn intern<T: Copy + Eq + Hash, K: Ord>(
+    values: impl IntoIterator<Item = T>,
+    sort_key: impl Fn(&T) -> K,
+) -> (Vec<T>, HashMap<T, u32>) {
+    let mut values: Vec<T> = values
+        .into_iter()
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect();
+    values.sort_by_cached_key(sort_key);
+    let indices = values
+        .iter()
+        .enumerate()
+        .map(|(i, v)| (*v, i as u32))
+        .collect();
+    (values, indices)
+}
+
+impl From<&ClaimSet> for CompactClaimSet {
+    fn from(claims: &ClaimSet) -> Self {
+        let (keys, key_indices) = intern(
+            claims
+                .global
+                .keys()
+                .chain(claims.game.keys().map(|k| &k.key))
+                .chain(claims.realm.keys().map(|k| &k.key.key))
+                .copied(),
+            ClaimKey::to_string,
+        );
+        let (games, game_indices) = intern(
+            claims
+                .game
+                .keys()
+                .map(|k| k.game_id)
+                .chain(claims.realm.keys().map(|k| k.key.game_id)),
+            |game_id| *game_id,
+        );
+        let (realms, realm_indices) = intern(claims.realm.keys().map(|k| k.realm_id), |realm_id| {
+            *realm_id
+        });
+
+        let mut global: Vec<_> = claims
+            .global
+            .iter()
+            .map(|(key, value)| (key_indices[key], value.clone()))
+            .collect();
+        let mut game: Vec<_> = claims
+            .game
+            .iter()
+            .map(|(GameClaimKey { game_id, key }, value)| {
+                (game_indices[game_id], key_indices[key], value.clone())
+            })
+            .collect();
+        let mut realm: Vec<_> = claims
+            .realm
+            .iter()
+            .map(
+                |(
+                    RealmClaimKey {
+                        realm_id,
+                        key: GameClaimKey { game_id, key },
+                    },
+                    value,
+                )| {
+                    (
+                        realm_indices[realm_id],
+                        game_indices[game_id],
+                        key_indices[key],
+                        value.clone(),
+                    )
+                },
+            )
+            .collect();
+        global.sort_by_key(|(k, _)| *k);
+        game.sort_by_key(|(g, k, _)| (*g, *k));
+        realm.sort_by_key(|(r, g, k, _)| (*r, *g, *k));
+
+        Self {
+            keys,
+            games,
+            realms,
+            global,
+            game,
+            realm,
+        }
+    }
+}
+
+impl TryFrom<CompactClaimSet> for ClaimSet {
+    type Error = ClaimStorageError;
+
+    fn try_from(compact: CompactClaimSet) -> Result<Self, Self::Error> {
+        fn get<T: Copy>(values: &[T], index: u32) -> Result<T, ClaimStorageError> {
+            values
+                .get(index as usize)
+                .copied()
+                .ok_or(ClaimStorageError::InvalidIndex)
+        }
+        let CompactClaimSet {
+            keys,
+            games,
+            realms,
+            global,
+            game,
+            realm,
+        } = compact;
+        Ok(Self {
+            global: global
+                .into_iter()
+                .map(|(k, value)| Ok((get(&keys, k)?, value)))
+                .collect::<Result<_, ClaimStorageError>>()?,
+            game: game
+                .into_iter()
+                .map(|(g, k, value)| {
+                    let key = GameClaimKey {
+                        game_id: get(&games, g)?,
+                        key: get(&keys, k)?,
+                    };
+                    Ok((key, value))
+                })
+                .collect::<Result<_, ClaimStorageError>>()?,
+            realm: realm
+                .into_iter()
+                .map(|(r, g, k, value)| {
+                    let key = RealmClaimKey {
+                        realm_id: get(&realms, r)?,
+                        key: GameClaimKey {
+                            game_id: get(&games, g)?,
+                            key: get(&keys, k)?,
+                        },
+                    };
+                    Ok((key, value))
+                })
+                .collect::<Result<_, ClaimStorageError>>()?,
+        })
+    }
+}
+
+impl ClaimSet {
+    /// Encodes claims for a database. New claims should use [`ClaimStorageFormat::Bitcode`],
+    /// or [`ClaimStorageFormat::CompactJson`] where a string is required.
+    pub fn to_storage(&self, format: ClaimStorageFormat) -> Vec<u8> {
+        match format {
+            ClaimStorageFormat::Json => serde_json::to_vec(self).unwrap(),
+            ClaimStorageFormat::CompactJson => {
+                serde_json::to_vec(&(ClaimStorageFormat::VERSION, CompactClaimSet::from(self)))
+                    .unwrap()
+            }
+            ClaimStorageFormat::Bitcode => {
+                let compact = bitcode::encode(&CompactClaimSet::from(self));
+                let mut stored = Vec::with_capacity(compact.len() + 2);
+                stored.push(ClaimStorageFormat::BITCODE_TAG);
+                stored.push(ClaimStorageFormat::VERSION);
+                stored.extend_from_slice(&compact);
+                stored
+            }
+        }
+    }
+
+    /// Decodes claims stored in any [`ClaimStorageFormat`] and version.
+    pub fn from_storage(stored: &[u8]) -> Result<Self, ClaimStorageError> {
+        match ClaimStorageFormat::of(stored) {
+            ClaimStorageFormat::Json => Ok(serde_json::from_slice(stored)?),
+            ClaimStorageFormat::CompactJson => {
+                let (version, compact): (u8, &RawValue) = serde_json::from_slice(stored)?;
+                match version {
+                    1 => serde_json::from_str::<CompactClaimSet>(compact.get())?.try_into(),
+                    _ => Err(ClaimStorageError::UnsupportedVersion(version)),
+                }
+            }
+            ClaimStorageFormat::Bitcode => match stored.get(1) {
+                Some(1) => bitcode::decode::<CompactClaimSet>(&stored[2..])?.try_into(),
+                Some(&version) => Err(ClaimStorageError::UnsupportedVersion(version)),
+                None => Err(ClaimStorageError::Bitcode("missing version".to_owned())),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ClaimSet, ClaimStorageError, ClaimStorageFormat, ClaimValue, GameClaimKey, GameId,
+        NonZeroUnixMillis, RealmClaimKey, RealmId, RealmName, ScopeClaimKey, UnixTime,
+    };
+    use std::str::FromStr;
+
+    /// Claims of a player of several games and realms.
+    fn claims() -> ClaimSet {
+        let mut claims = ClaimSet::default();
+        let value = |value: u64| ClaimValue {
+            date_expires: None,
+            date_updated: NonZeroUnixMillis::from_i64(1_700_000_000_000 + value as i64),
+            value,
+        };
+        claims.global.insert(ScopeClaimKey::streak().key, value(3));
+        for (i, game_id) in ["Mk48", "Kiomet", "Netquel"].into_iter().enumerate() {
+            let game_id = GameId::from_str(game_id).unwrap();
+            for (j, key) in [
+                ScopeClaimKey::high_score(),
+                ScopeClaimKey::days(),
+                ScopeClaimKey::kills(),
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                let key = GameClaimKey {
+                    game_id,
+                    key: key.key,
+                };
+                claims.game.insert(key, value((i * 10 + j) as u64));
+                for realm_id in [
+                    RealmId::PublicDefault,
+                    RealmId::Named(RealmName::new("foo")),
+                ] {
+                    claims
+                        .realm
+                        .insert(RealmClaimKey { realm_id, key }, value(j as u64));
+                }
+            }
+        }
+        claims
+    }
+
+    #[test]
+    fn round_trip() {
+        let claims = claims();
+        let legacy = claims.to_storage(ClaimStorageFormat::Json);
+        for format in [
+            ClaimStorageFormat::Json,
+            ClaimStorageFormat::CompactJson,
+            ClaimStorageFormat::Bitcode,
+        ] {
+            let stored = claims.to_storage(format);
+            assert_eq!(ClaimStorageFormat::of(&stored), format);
+            assert_eq!(
+                ClaimSet::from_storage(&stored).unwrap(),
+                claims,
+                "{format:?}"
+            );
+            // Equal sets are stored identically.
+            if format != ClaimStorageFormat::Json {
+                let migrated = ClaimSet::from_storage(&legacy).unwrap();
+                assert_eq!(migrated.to_storage(format), stored);
+                assert!(stored.len() < legacy.len(), "{format:?}");
+            }
+        }
+        let empty = ClaimSet::default();
+        for format in [ClaimStorageFormat::CompactJson, ClaimStorageFormat::Bitcode] {
+            assert_eq!(
+                ClaimSet::from_storage(&empty.to_storage(format)).unwrap(),
+                empty
+            );
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        let error = |stored: &[u8]| ClaimSet::from_storage(stored).unwrap_err();
+        assert_eq!(
+            error(br#"[2,{}]"#),
+            ClaimStorageError::UnsupportedVersion(2)
+        );
+        assert_eq!(
+            error(&[ClaimStorageFormat::BITCODE_TAG, 9]),
+            ClaimStorageError::UnsupportedVersion(9)
+        );
+        assert_eq!(
+            error(br#"[1,{"global":[[0,"1/1700000000000"]]}]"#),
+            ClaimStorageError::InvalidIndex
+        );
+        assert!(matches!(
+            error(&[ClaimStorageFormat::BITCODE_TAG]),
+            ClaimStorageError::Bitcode(_)
+        ));
+    }
+}
@@ -0,0 +1,280 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{ClaimSet, ClaimSubset, ClaimValue, ScopeClaimKey};
+use crate::{GameClaimKey, GameId, NonZeroUnixMillis, RegionId, UnixTime};
+use std::collections::HashMap;
+
+const DAY: i64 = 24 * 60 * 60 * 1000;
+/// Streaks expire this long after midnight, in case daylight saving moves midnight
+/// later before then.
+const GRACE: i64 = 60 * 60 * 1000;
+
+/// A player's offset from UTC in minutes, at the time they play (so including any
+/// daylight saving), which decides when their calendar days start.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct UtcOffset(pub i16);
+
+impl UtcOffset {
+    /// A typical offset for players of a region, for when theirs is unknown.
+    pub fn of_region(region_id: RegionId) -> Self {
+        Self(match region_id {
+            RegionId::Africa => 60,
+            RegionId::Asia => 8 * 60,
+            RegionId::Europe => 60,
+            RegionId::NorthAmerica => -5 * 60,
+            RegionId::Oceania => 10 * 60,
+            RegionId::SouthAmerica => -3 * 60,
+        })
+    }
+
+    fn millis(self) -> i64 {
+        self.0 as i64 * 60 * 1000
+    }
+
+    /// Calendar day, counted from the Unix epoch, containing `time`.
+    pub fn day(self, time: NonZeroUnixMillis) -> i64 {
+        (time.to_i64() + self.millis()).div_euclid(DAY)
+    }
+
+    /// Midnight at the start of `day`.
+    pub fn midnight(self, day: i64) -> NonZeroUnixMillis {
+        NonZeroUnixMillis::from_i64(day * DAY - self.millis())
+    }
+
+    /// The day starting around `midnight`, which may be a few hours off from this
+    /// offset's, e.g. if daylight saving changed since it was computed.
+    fn day_starting_at(self, midnight: NonZeroUnixMillis) -> i64 {
+        (midnight.to_i64() + self.millis() + DAY / 2).div_euclid(DAY)
+    }
+}
+
+/// The `streak` after playing, or `None` if it already counts today.
+///
+/// The streak expires at the midnight ending tomorrow, plus [`GRACE`]. Playing on
+/// the last day before it expires (i.e. a new day) increments it and pushes expiry
+/// back a day, while missing a day starts over at 1.
+///
+/// Whether it expired is decided by `date_expires`, like Plasma does, since `streak`
+/// is [`ClaimAggregation::Max`](crate::ClaimAggregation::Max) and a reset to 1 would
+/// lose to the old value until Plasma drops it. Playing within [`GRACE`] of the missed
+/// midnight changes nothing, and the next play after Plasma drops it starts over.
+fn streak(current: Option<&ClaimValue>, now: NonZeroUnixMillis, offset: UtcOffset) -> Option<u64> {
+    let today = offset.day(now);
+    let Some(current) = current else {
+        return Some(1);
+    };
+    let (expired, last_day) = if let Some(expires) = current.date_expires {
+        (expires <= now, offset.day_starting_at(expires) - 1)
+    } else {
+        let last_day = offset.day(current.date_updated) + 1;
+        (today > last_day, last_day)
+    };
+    if expired {
+        Some(1)
+    } else if today == last_day {
+        Some(current.value + 1)
+    } else {
+        // Already counts today, or missed a day but Plasma hasn't dropped it yet.
+        None
+    }
+}
+
+/// The `days` after playing, or `None` if it already counts today.
+fn days(current: Option<&ClaimValue>, now: NonZeroUnixMillis, offset: UtcOffset) -> Option<u64> {
+    match current {
+        None => Some(1),
+        Some(current) if offset.day(current.date_updated) < offset.day(now) => {
+            Some(current.value + 1)
+        }
+        Some(_) => None,
+    }
+}
+
+fn updates(
+    streak_value: Option<&ClaimValue>,
+    days_value: Option<&ClaimValue>,
+    now: NonZeroUnixMillis,
+    offset: UtcOffset,
+) -> HashMap<ScopeClaimKey, ClaimValue> {
+    let value = |value, date_expires| ClaimValue {
+        date_expires,
+        date_updated: now,
+        value,
+    };
+    let expires =
+        NonZeroUnixMillis::from_i64(offset.midnight(offset.day(now) + 2).to_i64() + GRACE);
+    let streak = streak(streak_value, now, offset).map(|s| value(s, Some(expires)));
+    let days = days(days_value, now, offset).map(|d| value(d, None));
+    [
+        (ScopeClaimKey::streak(), streak),
+        (ScopeClaimKey::days(), days),
+    ]
+    .into_iter()
+    .filter_map(|(key, value)| Some((key, value?)))
+    .collect()
+}
+
+impl ClaimSubset {
+    /// Updates to [`ScopeClaimKey::streak`] and [`ScopeClaimKey::days`] when the
+    /// player plays at `now`, to send to Plasma. Empty if they already played today.
+    pub fn play(&self, now: NonZeroUnixMillis, offset: UtcOffset) -> ClaimSubset {
+        let claims = updates(
+            self.get(&ScopeClaimKey::streak()),
+            self.get(&ScopeClaimKey::days()),
+            now,
+            offset,
+        );
+        ClaimSubset {
+            claims,
+            date_synchronized: self.date_synchronized,
+        }
+    }
+}
+
+impl ClaimSet {
+    /// Like [`ClaimSubset::play`], for merging with [`Self::merge`].
+    pub fn play(&self, game_id: GameId, now: NonZeroUnixMillis, offset: UtcOffset) -> ClaimSubset {
+        let days_key = GameClaimKey {
+            game_id,
+            key: ScopeClaimKey::days().key,
+        };
+        let claims = updates(
+            self.global.get(&ScopeClaimKey::streak().key),
+            self.game.get(&days_key),
+            now,
+            offset,
+        );
+        ClaimSubset {
+            claims,
+            date_synchronized: now,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ClaimSet, GameClaimKey, GameId, NonZeroUnixMillis, RealmId, RegionId, ScopeClaimKey,
+        UnixTime, UtcOffset,
+    };
+    use std::str::FromStr;
+
+    /// `hour` o'clock on `day` in UTC, where day 0 is Monday 2100-01-04, so that
+    /// [`ClaimSet::merge`] doesn't expire the claims.
+    fn at(day: i64, hour: i64) -> NonZeroUnixMillis {
+        NonZeroUnixMillis::from_i64(4_102_704_000_000 + (day * 24 + hour) * 60 * 60 * 1000)
+    }
+
+    /// Plays at each time in order, returning the streak and days after each.
+    fn play(times: &[(NonZeroUnixMillis, UtcOffset)]) -> Vec<(u64, u64)> {
+        let game_id = GameId::from_str("Mk48").unwrap();
+        let mut claims = ClaimSet::default();
+        times
+            .iter()
+            .map(|&(now, offset)| {
+                // Plasma drops expired claims.
+                claims
+                    .global
+                    .retain(|_, v| !v.date_expires.is_some_and(|e| e <= now));
+                let updates = claims.play(game_id, now, offset);
+                claims.merge(&updates, game_id, RealmId::PublicDefault);
+                let days = claims.game.get(&GameClaimKey {
+                    game_id,
+                    key: ScopeClaimKey::days().key,
+                });
+                (claims.streak(), days.map_or(0, |v| v.value))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn streak_and_days() {
+        let utc = UtcOffset::default();
+        assert_eq!(
+            play(&[
+                (at(0, 1), utc),
+                (at(0, 23), utc),
+                (at(1, 0), utc),
+                (at(2, 12), utc),
+                // Missed day 3.
+                (at(4, 12), utc),
+                (at(5, 0), utc),
+            ]),
+            [(1, 1), (1, 1), (2, 2), (3, 3), (1, 4), (2, 5)]
+        );
+    }
+
+    #[test]
+    fn missed_day() {
+        let utc = UtcOffset::default();
+        // Missed day 2, but Plasma hasn't dropped the streak yet, so it neither counts
+        // nor proposes a reset that the merge would ignore.
+        assert_eq!(
+            play(&[(at(0, 12), utc), (at(1, 12), utc), (at(3, 0), utc)]),
+            [(1, 1), (2, 2), (2, 3)]
+        );
+        // Once it has, playing starts over.
+        assert_eq!(
+            play(&[
+                (at(0, 12), utc),
+                (at(1, 12), utc),
+                (at(3, 1), utc),
+                (at(3, 2), utc)
+            ]),
+            [(1, 1), (2, 2), (1, 3), (1, 3)]
+        );
+    }
+
+    #[test]
+    fn offsets() {
+        // 23:00 UTC is already tomorrow in Europe, but still today in North America.
+        let europe = UtcOffset::of_region(RegionId::Europe);
+        let america = UtcOffset::of_region(RegionId::NorthAmerica);
+        assert_eq!(
+            play(&[(at(0, 12), europe), (at(0, 23), europe)]),
+            [(1, 1), (2, 2)]
+        );
+        assert_eq!(
+            play(&[(at(0, 12), america), (at(0, 23), america)]),
+            [(1, 1), (1, 1)]
+        );
+        assert_eq!(america.midnight(america.day(at(1, 3))), at(0, 5));
+    }
+
+    #[test]
+    fn daylight_saving() {
+        // Clocks change from UTC-4 to UTC-5 overnight, so the midnight that ends the
+        // streak was computed an hour off. Playing late the next local day must still
+        // count, and playing the day after that must not reset.
+        let (summer, winter) = (UtcOffset(-4 * 60), UtcOffset(-5 * 60));
+        assert_eq!(
+            play(&[
+                (at(0, 12), summer),
+                (at(2, 4), winter),
+                (at(2, 6), winter),
+                (at(3, 4), winter)
+            ]),
+            [(1, 1), (2, 2), (3, 3), (3, 3)]
+        );
+        // And the other way around.
+        assert_eq!(
+            play(&[(at(0, 12), winter), (at(1, 4), summer), (at(1, 23), summer)]),
+            [(1, 1), (2, 2), (2, 2)]
+        );
+    }
+
+    #[test]
+    fn claim_set() {
+        let game_id = GameId::from_str("Mk48").unwrap();
+        let mut claims = ClaimSet::default();
+        let now = NonZeroUnixMillis::now();
+        let updates = claims.play(game_id, now, UtcOffset::default());
+        claims.merge(&updates, game_id, RealmId::PublicDefault);
+        assert_eq!(claims.streak(), 1);
+        assert!(claims
+            .play(game_id, now, UtcOffset::default())
+            .claims
+            .is_empty());
+    }
+}
@@ -1,8 +1,12 @@
 // SPDX-FileCopyrightText: 2024 Softbear, Inc.
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use super::{ClaimKey, ClaimValue, GameClaimKey, RealmClaimKey, ScopeClaimKey};
-use crate::{is_default, GameId, NonZeroUnixMillis, RealmId, UnixTime};
+use super::{
+    ClaimAggregation, ClaimChange, ClaimJournal, ClaimJournalEntry, ClaimKey, ClaimName,
+    ClaimRegistry, ClaimSchemaError, ClaimSource, ClaimValue, GameClaimKey, RealmClaimKey,
+    ScopeClaimKey,
+};
+use crate::{is_default, GameId, NonZeroUnixMillis, RealmId, ServerId, UnixTime};
 use bitcode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
@@ -64,6 +68,88 @@ impl ClaimSubset {
             .max()
             .unwrap_or(NonZeroUnixMillis::MIN)
     }
+
+    /// Adds `amount` to the contribution of `server_id` to the [`ClaimAggregation::Counter`]
+    /// `key`, and to `key` itself, as of `now`. Plasma only merges the contribution.
+    ///
+    /// Returns `false`, changing nothing, if `key` has no [`ClaimKey::contribution`].
+    pub fn increment(
+        &mut self,
+        key: ScopeClaimKey,
+        server_id: ServerId,
+        amount: u64,
+        now: NonZeroUnixMillis,
+    ) -> bool {
+        if key.key.aggregation != ClaimAggregation::Counter {
+            return false;
+        }
+        let Some(contribution) = key.key.contribution(server_id) else {
+            return false;
+        };
+        let contribution = ScopeClaimKey {
+            scope: key.scope,
+            key: contribution,
+        };
+        for key in [contribution, key] {
+            let value = self.claims.entry(key).or_insert(ClaimValue {
+                date_expires: None,
+                date_updated: now,
+                value: 0,
+            });
+            value.value = value.value.saturating_add(amount);
+            value.date_updated = value.date_updated.max(now);
+        }
+        true
+    }
+
+    /// Sets the contribution of `server_id` to the [`ClaimAggregation::Sum`] or
+    /// [`ClaimAggregation::Lww`] `key` to `value` as of `now`, and updates `key` itself to
+    /// match. Plasma only merges the contribution.
+    ///
+    /// Returns `false`, changing nothing, if `key` has no [`ClaimKey::contribution`].
+    pub fn write(
+        &mut self,
+        key: ScopeClaimKey,
+        server_id: ServerId,
+        value: u64,
+        now: NonZeroUnixMillis,
+    ) -> bool {
+        if !matches!(
+            key.key.aggregation,
+            ClaimAggregation::Sum | ClaimAggregation::Lww
+        ) {
+            return false;
+        }
+        let Some(contribution) = key.key.contribution(server_id) else {
+            return false;
+        };
+        let contribution = ScopeClaimKey {
+            scope: key.scope,
+            key: contribution,
+        };
+        let value = ClaimValue {
+            date_expires: None,
+            date_updated: now,
+            value,
+        };
+        let old = self
+            .claims
+            .insert(contribution, value)
+            .map_or(0, |v| v.value);
+        let mut claim = value;
+        if key.key.aggregation == ClaimAggregation::Sum {
+            // The other contributions are still included.
+            let sum = self.claims.get(&key).map_or(0, |v| v.value);
+            claim.value = sum.saturating_sub(old).saturating_add(value.value);
+        }
+        self.claims.insert(key, claim);
+        true
+    }
+}
+
+/// Whether `key` is a [`ClaimKey::contribution`] to `claim`.
+fn is_contribution(key: &ScopeClaimKey, claim: &ScopeClaimKey) -> bool {
+    key.scope == claim.scope && key.key.contributor().is_some_and(|(c, _)| c == claim.key)
 }
 
 impl Deref for ClaimSubset {
@@ -94,6 +180,9 @@ pub struct PublicClaims {
     pub rank: u64,
     #[serde(default, skip_serializing_if = "is_default")]
     pub streak: u64,
+    /// Other public claims, see [`ClaimRegistry::public_claims`].
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub claims: HashMap<ClaimName, u64>,
 }
 
 #[derive(Clone, Default, Eq, Debug, PartialEq, Serialize, Deserialize)]
@@ -196,19 +285,76 @@ impl ClaimSet {
         new: &ClaimSubset,
         game_id: GameId,
         realm_id: RealmId,
+    ) -> (Option<ClaimSubset>, bool) {
+        self.merge_inner(new, game_id, realm_id, None)
+    }
+
+    /// Like [`Self::merge`], but records each change in `journal`.
+    pub fn merge_journaled(
+        &mut self,
+        new: &ClaimSubset,
+        game_id: GameId,
+        realm_id: RealmId,
+        source: ClaimSource,
+        journal: &mut ClaimJournal,
+    ) -> (Option<ClaimSubset>, bool) {
+        self.merge_inner(new, game_id, realm_id, Some((journal, source)))
+    }
+
+    fn merge_inner(
+        &mut self,
+        new: &ClaimSubset,
+        game_id: GameId,
+        realm_id: RealmId,
+        mut journal: Option<(&mut ClaimJournal, ClaimSource)>,
     ) -> (Option<ClaimSubset>, bool) {
         // `self` changed.
         let mut changed = false;
 
         let now = NonZeroUnixMillis::now();
+        let source = journal
+            .as_ref()
+            .map(|(_, source)| *source)
+            .unwrap_or_default();
+        let entry = |change, key, game_id, realm_id, old, proposed, new| ClaimJournalEntry {
+            timestamp: now,
+            change,
+            key,
+            game_id,
+            realm_id,
+            source,
+            old,
+            proposed,
+            new,
+        };
+        let mut entries = Vec::new();
+        let journaling = journal.is_some();
 
-        let mut retain = |value: &mut ClaimValue| -> bool {
+        let mut retain = |key: ScopeClaimKey,
+                          game_id: Option<GameId>,
+                          realm_id: Option<RealmId>,
+                          value: &mut ClaimValue|
+         -> bool {
             if value
                 .date_expires
                 .map(|expiration| expiration < now)
                 .unwrap_or(false)
             {
                 changed = true;
+                if journaling {
+                    entries.push(ClaimJournalEntry {
+                        source: Default::default(),
+                        ..entry(
+                            ClaimChange::Expired,
+                            key,
+                            game_id,
+                            realm_id,
+                            Some(value.clone()),
+                            None,
+                            None,
+                        )
+                    });
+                }
                 false
             } else {
                 true
@@ -216,9 +362,33 @@ impl ClaimSet {
         };
 
         // Expire items to avoid improper merging.
-        self.global.retain(|_, value| retain(value));
-        self.game.retain(|_, value| retain(value));
-        self.realm.retain(|_, value| retain(value));
+        self.global.retain(|key, value| {
+            let key = ScopeClaimKey {
+                scope: ClaimScope::Global,
+                key: *key,
+            };
+            retain(key, None, None, value)
+        });
+        self.game.retain(|GameClaimKey { game_id, key }, value| {
+            let key = ScopeClaimKey {
+                scope: ClaimScope::Game,
+                key: *key,
+            };
+            retain(key, Some(*game_id), None, value)
+        });
+        self.realm.retain(
+            |RealmClaimKey {
+                 realm_id,
+                 key: GameClaimKey { game_id, key },
+             },
+             value| {
+                let key = ScopeClaimKey {
+                    scope: ClaimScope::Realm,
+                    key: *key,
+                };
+                retain(key, Some(*game_id), Some(*realm_id), value)
+            },
+        );
 
         // Get recently-changed claims.
         let cutoff = new.first_updated().min(new.date_synchronized);
@@ -235,11 +405,38 @@ impl ClaimSet {
 
         for (scope_key, value) in &new.claims {
             let ScopeClaimKey { scope, key } = scope_key;
+            let (entry_game_id, entry_realm_id) = match scope {
+                ClaimScope::Global => (None, None),
+                ClaimScope::Game => (Some(game_id), None),
+                ClaimScope::Realm => (Some(game_id), Some(realm_id)),
+            };
+            let entry = |change, old, new| {
+                entry(
+                    change,
+                    *scope_key,
+                    entry_game_id,
+                    entry_realm_id,
+                    old,
+                    Some(value.clone()),
+                    new,
+                )
+            };
+            if key.aggregation.has_contributions() && key.contributor().is_none() {
+                // Derived from its contributions, but this has none.
+                if journaling && !new.keys().any(|k| is_contribution(k, scope_key)) {
+                    let reason = ClaimSchemaError::NoContribution;
+                    entries.push(entry(ClaimChange::Rejected { reason }, None, None));
+                }
+                continue;
+            }
             let occupied = match scope {
                 ClaimScope::Global => match self.global.entry(*key) {
                     Entry::Vacant(vacant) => {
                         vacant.insert(value.clone());
                         changed = true;
+                        if journaling {
+                            entries.push(entry(ClaimChange::Inserted, None, Some(value.clone())));
+                        }
                         continue;
                     }
                     Entry::Occupied(occupied) => occupied.into_mut(),
@@ -248,6 +445,9 @@ impl ClaimSet {
                     Entry::Vacant(vacant) => {
                         vacant.insert(value.clone());
                         changed = true;
+                        if journaling {
+                            entries.push(entry(ClaimChange::Inserted, None, Some(value.clone())));
+                        }
                         continue;
                     }
                     Entry::Occupied(occupied) => occupied.into_mut(),
@@ -259,13 +459,26 @@ impl ClaimSet {
                     Entry::Vacant(vacant) => {
                         vacant.insert(value.clone());
                         changed = true;
+                        if journaling {
+                            entries.push(entry(ClaimChange::Inserted, None, Some(value.clone())));
+                        }
                         continue;
                     }
                     Entry::Occupied(occupied) => occupied.into_mut(),
                 },
             };
 
-            changed |= occupied.merge(value, key.aggregation);
+            let old = journaling.then(|| occupied.clone());
+            let merged = occupied.merge(value, key.aggregation);
+            changed |= merged;
+            if let Some(old) = old {
+                let change = if merged {
+                    ClaimChange::Merged
+                } else {
+                    ClaimChange::Ignored
+                };
+                entries.push(entry(change, Some(old), Some(occupied.clone())));
+            }
             if occupied == value {
                 changed_recently.remove(scope_key);
             } else {
@@ -273,6 +486,102 @@ impl ClaimSet {
             }
         }
 
+        // `Sum` and `Counter` claims are the sum of their contributions, and the latest
+        // contribution, or else that of the greatest server ID, is the value of an `Lww`.
+        // Only those with new contributions can have changed.
+        let contributed = new
+            .keys()
+            .filter_map(|scope_key| {
+                let (key, _) = scope_key.key.contributor()?;
+                Some(ScopeClaimKey {
+                    scope: scope_key.scope,
+                    key,
+                })
+            })
+            .collect::<HashSet<_>>();
+        let mut derived = HashMap::<ScopeClaimKey, (ServerId, ClaimValue)>::new();
+        if !contributed.is_empty() {
+            let global = self
+                .global
+                .iter()
+                .map(|(key, value)| (ClaimScope::Global, key, value));
+            let game = self
+                .game
+                .iter()
+                .filter(|(key, _)| key.game_id == game_id)
+                .map(|(key, value)| (ClaimScope::Game, &key.key, value));
+            let realm = self
+                .realm
+                .iter()
+                .filter(|(key, _)| key.realm_id == realm_id && key.key.game_id == game_id)
+                .map(|(key, value)| (ClaimScope::Realm, &key.key.key, value));
+            for (scope, key, &value) in global.chain(game).chain(realm) {
+                let Some((key, server_id)) = key.contributor() else {
+                    continue;
+                };
+                let key = ScopeClaimKey { scope, key };
+                if !contributed.contains(&key) {
+                    continue;
+                }
+                derived
+                    .entry(key)
+                    .and_modify(|(latest, claim)| {
+                        if key.key.aggregation == ClaimAggregation::Lww {
+                            if (value.date_updated, server_id) > (claim.date_updated, *latest) {
+                                *latest = server_id;
+                                *claim = value;
+                            }
+                            return;
+                        }
+                        claim.value = claim.value.saturating_add(value.value);
+                        claim.date_updated = claim.date_updated.max(value.date_updated);
+                        // Until the last contribution expires.
+                        claim.date_expires = claim
+                            .date_expires
+                            .zip(value.date_expires)
+                            .map(|(a, b)| a.max(b));
+                    })
+                    .or_insert((server_id, value));
+            }
+        }
+        for (scope_key, (_, claim)) in derived {
+            if new.get(&scope_key) == Some(&claim) {
+                changed_recently.remove(&scope_key);
+            } else {
+                changed_recently.insert(scope_key);
+            }
+            let old = self.insert(scope_key, game_id, realm_id, claim);
+            if old == Some(claim) {
+                continue;
+            }
+            changed = true;
+            if journaling {
+                let (game_id, realm_id) = match scope_key.scope {
+                    ClaimScope::Global => (None, None),
+                    ClaimScope::Game => (Some(game_id), None),
+                    ClaimScope::Realm => (Some(game_id), Some(realm_id)),
+                };
+                let change = if old.is_some() {
+                    ClaimChange::Merged
+                } else {
+                    ClaimChange::Inserted
+                };
+                entries.push(entry(
+                    change,
+                    scope_key,
+                    game_id,
+                    realm_id,
+                    old,
+                    new.get(&scope_key).copied(),
+                    Some(claim),
+                ));
+            }
+        }
+
+        if let Some((journal, _)) = &mut journal {
+            journal.extend(entries);
+        }
+
         let subset = Some(self.filtered_subset(
             |key, _| changed_recently.contains(&key),
             Some(new.date_synchronized),
@@ -282,6 +591,86 @@ impl ClaimSet {
         (subset, changed)
     }
 
+    /// Replaces a claim of `game_id` and `realm_id`, returning the old value.
+    fn insert(
+        &mut self,
+        scope_key: ScopeClaimKey,
+        game_id: GameId,
+        realm_id: RealmId,
+        value: ClaimValue,
+    ) -> Option<ClaimValue> {
+        let ScopeClaimKey { scope, key } = scope_key;
+        match scope {
+            ClaimScope::Global => self.global.insert(key, value),
+            ClaimScope::Game => self.game.insert(GameClaimKey { game_id, key }, value),
+            ClaimScope::Realm => self.realm.insert(
+                RealmClaimKey {
+                    realm_id,
+                    key: GameClaimKey { game_id, key },
+                },
+                value,
+            ),
+        }
+    }
+
+    /// Like [`Self::merge`], but drops claims that `registry` rejects, applies default
+    /// expiries, and keeps hidden claims out of the change sent to the client.
+    pub fn merge_registered(
+        &mut self,
+        new: &ClaimSubset,
+        registry: &ClaimRegistry,
+        game_id: GameId,
+        realm_id: RealmId,
+    ) -> (Option<ClaimSubset>, bool) {
+        self.merge_registered_inner(new, registry, game_id, realm_id, None)
+    }
+
+    /// Like [`Self::merge_registered`], but records each change, including rejected
+    /// claims, in `journal`.
+    pub fn merge_registered_journaled(
+        &mut self,
+        new: &ClaimSubset,
+        registry: &ClaimRegistry,
+        game_id: GameId,
+        realm_id: RealmId,
+        source: ClaimSource,
+        journal: &mut ClaimJournal,
+    ) -> (Option<ClaimSubset>, bool) {
+        self.merge_registered_inner(new, registry, game_id, realm_id, Some((journal, source)))
+    }
+
+    fn merge_registered_inner(
+        &mut self,
+        new: &ClaimSubset,
+        registry: &ClaimRegistry,
+        game_id: GameId,
+        realm_id: RealmId,
+        mut journal: Option<(&mut ClaimJournal, ClaimSource)>,
+    ) -> (Option<ClaimSubset>, bool) {
+        let (new_valid, invalid) = registry.validate_subset(new);
+        if let Some((journal, source)) = &mut journal {
+            let now = NonZeroUnixMillis::now();
+            for (key, reason) in invalid {
+                journal.record(ClaimJournalEntry {
+                    timestamp: now,
+                    change: ClaimChange::Rejected { reason },
+                    key,
+                    game_id: (key.scope != ClaimScope::Global).then_some(game_id),
+                    realm_id: (key.scope == ClaimScope::Realm).then_some(realm_id),
+                    source: *source,
+                    old: None,
+                    proposed: new.get(&key).cloned(),
+                    new: None,
+                });
+            }
+        }
+        let (mut subset, changed) = self.merge_inner(&new_valid, game_id, realm_id, journal);
+        if let Some(subset) = &mut subset {
+            subset.retain(|key, _| registry.is_client_visible(key));
+        }
+        (subset, changed)
+    }
+
     pub fn public_claims(&self, game_id: GameId) -> PublicClaims {
         let (high_score, date_high_score) = self.high_score(game_id);
         let (rank, date_rank) = self.rank(game_id);
@@ -291,6 +680,7 @@ impl ClaimSet {
             high_score,
             rank,
             streak: self.streak(),
+            claims: Default::default(),
         }
     }
 
@@ -28,6 +28,30 @@ pub enum ClaimAggregation {
     Min,
     #[default]
     New,
+    /// Sum of each server's latest contribution, under
+    /// [`ClaimKey::contribution`](crate::ClaimKey::contribution), which unlike that of a
+    /// `Counter` may decrease. Safe to merge concurrently and repeatedly. See
+    /// [`ClaimSubset::write`](crate::ClaimSubset::write).
+    Sum,
+    /// Grow-only counter, e.g. for `kills`, that is safe to merge concurrently and
+    /// repeatedly. Each server proposes its own cumulative contribution, under
+    /// [`ClaimKey::contribution`](crate::ClaimKey::contribution), and the claim is the
+    /// sum of the contributions. See [`ClaimSubset::increment`](crate::ClaimSubset::increment).
+    Counter,
+    /// Bitwise OR, e.g. for sets of unlockables or achievements.
+    Bitset,
+    /// Last writer wins, like `New`, except ties in `date_updated` go to the greater
+    /// server ID, so every merge order agrees. Each server proposes its own value under
+    /// [`ClaimKey::contribution`](crate::ClaimKey::contribution), and the claim is the
+    /// latest of them. See [`ClaimSubset::write`](crate::ClaimSubset::write).
+    Lww,
+}
+
+impl ClaimAggregation {
+    /// Whether claims are derived from each server's [`ClaimKey::contribution`](crate::ClaimKey::contribution).
+    pub fn has_contributions(self) -> bool {
+        matches!(self, Self::Sum | Self::Counter | Self::Lww)
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Encode, Decode)]
@@ -42,7 +66,21 @@ impl ClaimValue {
         let replace = match aggregation {
             ClaimAggregation::New => new.date_updated >= self.date_updated,
             ClaimAggregation::Min => new.value < self.value,
-            ClaimAggregation::Max => new.value > self.value,
+            // Counter contributions only grow.
+            ClaimAggregation::Max | ClaimAggregation::Counter => new.value > self.value,
+            // Contributions of one server, so ties are only for determinism.
+            ClaimAggregation::Sum | ClaimAggregation::Lww => {
+                (new.date_updated, new.value) >= (self.date_updated, self.value)
+            }
+            ClaimAggregation::Bitset => {
+                let old = *self;
+                self.value |= new.value;
+                if new.date_updated >= self.date_updated {
+                    self.date_updated = new.date_updated;
+                    self.date_expires = new.date_expires;
+                }
+                return *self != old;
+            }
         };
         let mut changed = false;
         if replace && new.value != self.value {
@@ -115,3 +153,173 @@ pub enum ClaimValueError {
     #[strum(to_string = "{}")]
     Expiration(<NonZeroUnixMillis as FromStr>::Err),
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ClaimAggregation, ClaimKey, ClaimScope, ClaimSet, ClaimSubset, ClaimValue, GameId,
+        NonZeroUnixMillis, RealmId, ScopeClaimKey, ServerId, UnixTime,
+    };
+    use std::str::FromStr;
+
+    fn value(value: u64, seconds: i64) -> ClaimValue {
+        ClaimValue {
+            date_expires: None,
+            date_updated: NonZeroUnixMillis::from_i64(1_700_000_000_000 + seconds * 1000),
+            value,
+        }
+    }
+
+    /// Merges `values` in order into the first.
+    fn merged(aggregation: ClaimAggregation, values: &[ClaimValue]) -> ClaimValue {
+        let mut merged = values[0];
+        for value in &values[1..] {
+            merged.merge(value, aggregation);
+        }
+        merged
+    }
+
+    #[test]
+    fn counter() {
+        let kills = ScopeClaimKey {
+            scope: ClaimScope::Game,
+            key: ClaimKey::from_str("kills/Counter").ok().unwrap(),
+        };
+        // Both servers counted kills concurrently.
+        let server = |s| ServerId::from_str(s).unwrap();
+        let counted = |server_id, amount| {
+            let mut subset = ClaimSubset::default();
+            assert!(subset.increment(kills, server_id, amount, value(0, 1).date_updated));
+            subset
+        };
+        let (from_a, from_b) = (counted(server("Cloud/1"), 3), counted(server("Cloud/2"), 4));
+        let (game_id, realm_id) = (GameId::from_str("Mk48").unwrap(), RealmId::PublicDefault);
+        let merge_all = |subsets: &[&ClaimSubset]| {
+            let mut claims = ClaimSet::default();
+            for subset in subsets {
+                claims.merge(subset, game_id, realm_id);
+            }
+            claims
+        };
+        let ab = merge_all(&[&from_a, &from_b]);
+        assert_eq!(ab, merge_all(&[&from_b, &from_a]));
+        assert_eq!(ab.subset(game_id, realm_id).get(&kills).unwrap().value, 7);
+
+        // Merging again changes nothing.
+        let mut again = ab.clone();
+        assert!(!again.merge(&from_a, game_id, realm_id).1);
+        assert_eq!(again, ab);
+
+        // A counter can't be proposed without its contributions.
+        let mut subset = ClaimSubset::default();
+        subset.insert(kills, value(100, 2));
+        assert!(!again.merge(&subset, game_id, realm_id).1);
+
+        // Only counters can be incremented.
+        let now = value(0, 2).date_updated;
+        assert!(!subset.increment(ScopeClaimKey::kills(), server("Cloud/1"), 1, now));
+    }
+
+    #[test]
+    fn bitset_lww() {
+        let bits = merged(
+            ClaimAggregation::Bitset,
+            &[value(0b001, 1), value(0b100, 2), value(0b011, 0)],
+        );
+        assert_eq!(
+            (bits.value, bits.date_updated),
+            (0b111, value(0, 2).date_updated)
+        );
+        let mut again = bits;
+        assert!(!again.merge(&value(0b101, 0), ClaimAggregation::Bitset));
+
+        // A server's own contribution, so ties in date go to the greater value.
+        let lww = |first, second| merged(ClaimAggregation::Lww, &[first, second]).value;
+        assert_eq!(lww(value(1, 1), value(2, 1)), 2);
+        assert_eq!(lww(value(2, 1), value(1, 1)), 2);
+        assert_eq!(lww(value(2, 1), value(1, 2)), 1);
+    }
+
+    #[test]
+    fn lww() {
+        let skin = ScopeClaimKey {
+            scope: ClaimScope::Game,
+            key: ClaimKey::from_str("skin/Lww").ok().unwrap(),
+        };
+        let server = |s| ServerId::from_str(s).unwrap();
+        let written = |server_id, skin_id, seconds| {
+            let mut subset = ClaimSubset::default();
+            assert!(subset.write(skin, server_id, skin_id, value(0, seconds).date_updated));
+            subset
+        };
+        let (game_id, realm_id) = (GameId::from_str("Mk48").unwrap(), RealmId::PublicDefault);
+        let merge_all = |subsets: &[&ClaimSubset]| {
+            let mut claims = ClaimSet::default();
+            for subset in subsets {
+                claims.merge(subset, game_id, realm_id);
+            }
+            claims.subset(game_id, realm_id).get(&skin).unwrap().value
+        };
+
+        // Same date, so the greater server wins, whatever the values and order.
+        let (from_a, from_b) = (
+            written(server("Cloud/1"), 5, 1),
+            written(server("Cloud/2"), 3, 1),
+        );
+        assert_eq!(merge_all(&[&from_a, &from_b]), 3);
+        assert_eq!(merge_all(&[&from_b, &from_a]), 3);
+
+        // Otherwise, the latest write wins.
+        let later = written(server("Cloud/1"), 4, 2);
+        assert_eq!(merge_all(&[&from_a, &from_b, &later]), 4);
+        assert_eq!(merge_all(&[&later, &from_b, &from_a]), 4);
+
+        // Only `Sum` and `Lww` claims can be written.
+        let now = value(0, 2).date_updated;
+        let mut subset = ClaimSubset::default();
+        assert!(!subset.write(ScopeClaimKey::kills(), server("Cloud/1"), 1, now));
+    }
+
+    #[test]
+    fn sum() {
+        let coins = ScopeClaimKey {
+            scope: ClaimScope::Game,
+            key: ClaimKey::from_str("coins/Sum").ok().unwrap(),
+        };
+        let (server_a, server_b) = (
+            ServerId::from_str("Cloud/1").unwrap(),
+            ServerId::from_str("Cloud/2").unwrap(),
+        );
+        let mut subset = ClaimSubset::default();
+        assert!(subset.write(coins, server_a, 5, value(0, 1).date_updated));
+        assert!(subset.write(coins, server_b, 3, value(0, 1).date_updated));
+        assert_eq!(subset.get(&coins).unwrap().value, 8);
+
+        let (game_id, realm_id) = (GameId::from_str("Mk48").unwrap(), RealmId::PublicDefault);
+        let mut claims = ClaimSet::default();
+        claims.merge(&subset, game_id, realm_id);
+        let sum = |claims: &ClaimSet| claims.subset(game_id, realm_id).get(&coins).unwrap().value;
+        assert_eq!(sum(&claims), 8);
+
+        // Proposing the same contributions again, e.g. after a reconnect, changes nothing.
+        let merged = claims.clone();
+        assert!(!claims.merge(&subset, game_id, realm_id).1);
+        assert_eq!(claims, merged);
+
+        // Unlike a counter, a contribution may decrease.
+        assert!(subset.write(coins, server_a, 1, value(0, 2).date_updated));
+        assert_eq!(subset.get(&coins).unwrap().value, 4);
+        claims.merge(&subset, game_id, realm_id);
+        assert_eq!(sum(&claims), 4);
+    }
+
+    #[test]
+    fn string_forms() {
+        for aggregation in ["Sum", "Counter", "Bitset", "Lww"] {
+            let key = ClaimKey::from_str(&format!("kills/{aggregation}"))
+                .ok()
+                .unwrap();
+            assert_eq!(key.to_string(), format!("kills/{aggregation}"));
+        }
+    }
+}
@@ -1,10 +1,13 @@
 // SPDX-FileCopyrightText: 2024 Softbear, Inc.
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use crate::{is_default, ArenaId, LanguageId, PlayerAlias, RankNumber, ServerNumber, VisitorId};
+use crate::{
+    is_default, ArenaId, LanguageId, PlayerAlias, RankNumber, ServerNumber, TeamName, VisitorId,
+};
 use bitcode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Encode, Decode)]
@@ -44,6 +47,203 @@ pub enum ChatMessage {
     },
     /// "Either sign in or disable your VPN to chat"
     SignInOrDisableVpn,
+    /// "{alias} is on a {streak} kill streak!"
+    KillStreak { alias: PlayerAlias, streak: u32 },
+    /// "{alias} joined team {team_name}"
+    TeamJoin {
+        alias: PlayerAlias,
+        team_name: TeamName,
+    },
+    /// "Server restarting in {seconds} seconds"
+    RestartWarning { seconds: u32 },
+    /// "{alias} earned {achievement}", where `achievement` is itself a translation ID.
+    Achievement {
+        alias: PlayerAlias,
+        achievement: String,
+    },
+    /// "{alias} set a new high score of {score}"
+    HighScore { alias: PlayerAlias, score: u32 },
+    /// A [`ChatMessage::Raw`] with cached translations into languages other than
+    /// English, see [`ChatMessage::render`].
+    Translated {
+        message: String,
+        #[serde(default, skip_serializing_if = "is_default")]
+        detected_language_id: LanguageId,
+        /// Including English, if any.
+        #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+        translations: BTreeMap<LanguageId, String>,
+    },
+}
+
+/// The translation ID and English text of a system [`ChatMessage`], whose
+/// `{placeholders}` are named by [`ChatMessage::parameters`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ChatPhrase {
+    pub translation_id: &'static str,
+    pub english: &'static str,
+}
+
+/// The text of a [`ChatMessage::Raw`] or [`ChatMessage::Translated`] that a
+/// recipient should see.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RenderedChat<'a> {
+    pub text: &'a str,
+    /// Language of `text`, if known.
+    pub language_id: LanguageId,
+    /// Whether `text` is a translation of the original.
+    pub translated: bool,
+    /// The recipient's language differs from the original and there is no cached
+    /// translation, so `text` is the original. Plasma can be asked for one.
+    pub untranslated: bool,
+}
+
+impl ChatMessage {
+    /// Picks the original text or a cached translation, based on the recipient's
+    /// language. Returns `None` for system messages.
+    pub fn render(&self, language_id: LanguageId) -> Option<RenderedChat<'_>> {
+        let (Self::Raw {
+            message,
+            detected_language_id,
+            ..
+        }
+        | Self::Translated {
+            message,
+            detected_language_id,
+            ..
+        }) = self
+        else {
+            return None;
+        };
+        let original = RenderedChat {
+            text: message,
+            language_id: *detected_language_id,
+            translated: false,
+            untranslated: false,
+        };
+        Some(if language_id == *detected_language_id {
+            original
+        } else if let Some(text) = self.translation(language_id) {
+            RenderedChat {
+                text,
+                language_id,
+                translated: true,
+                untranslated: false,
+            }
+        } else {
+            RenderedChat {
+                untranslated: true,
+                ..original
+            }
+        })
+    }
+
+    /// The phrase of a system message, or `None` for [`ChatMessage::Raw`] and
+    /// [`ChatMessage::Translated`].
+    pub fn phrase(&self) -> Option<ChatPhrase> {
+        let (translation_id, english) = match self {
+            Self::Raw { .. } | Self::Translated { .. } => return None,
+            Self::Welcome { .. } => ("chat_welcome", "Welcome to server {server_number}!"),
+            Self::Join { .. } => ("chat_join", "{alias} joined"),
+            Self::SignInOrDisableVpn => (
+                "chat_sign_in_or_disable_vpn",
+                "Either sign in or disable your VPN to chat",
+            ),
+            Self::KillStreak { .. } => {
+                ("chat_kill_streak", "{alias} is on a {streak} kill streak!")
+            }
+            Self::TeamJoin { .. } => ("chat_team_join", "{alias} joined team {team_name}"),
+            Self::RestartWarning { .. } => (
+                "chat_restart_warning",
+                "Server restarting in {seconds} seconds",
+            ),
+            Self::Achievement { .. } => ("chat_achievement", "{alias} earned {achievement}"),
+            Self::HighScore { .. } => {
+                ("chat_high_score", "{alias} set a new high score of {score}")
+            }
+        };
+        Some(ChatPhrase {
+            translation_id,
+            english,
+        })
+    }
+
+    /// Values of the `{placeholders}` in [`ChatMessage::phrase`]. Parameters that
+    /// are translation IDs, like `achievement`, are listed untranslated.
+    pub fn parameters(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Self::Raw { .. } | Self::Translated { .. } | Self::SignInOrDisableVpn => Vec::new(),
+            Self::Welcome { server_number, .. } => {
+                vec![("server_number", server_number.to_string())]
+            }
+            Self::Join { alias, .. } => vec![("alias", alias.to_string())],
+            Self::KillStreak { alias, streak } => {
+                vec![("alias", alias.to_string()), ("streak", streak.to_string())]
+            }
+            Self::TeamJoin { alias, team_name } => vec![
+                ("alias", alias.to_string()),
+                ("team_name", team_name.to_string()),
+            ],
+            Self::RestartWarning { seconds } => vec![("seconds", seconds.to_string())],
+            Self::Achievement { alias, achievement } => vec![
+                ("alias", alias.to_string()),
+                ("achievement", achievement.clone()),
+            ],
+            Self::HighScore { alias, score } => {
+                vec![("alias", alias.to_string()), ("score", score.to_string())]
+            }
+        }
+    }
+
+    /// A cached translation of a [`ChatMessage::Raw`] or [`ChatMessage::Translated`].
+    pub fn translation(&self, language_id: LanguageId) -> Option<&str> {
+        match self {
+            Self::Raw {
+                english_translation,
+                ..
+            } => english_translation
+                .as_deref()
+                .filter(|_| language_id == LanguageId::default()),
+            Self::Translated { translations, .. } => {
+                translations.get(&language_id).map(String::as_str)
+            }
+            _ => None,
+        }
+    }
+
+    /// Caches a translation of a [`ChatMessage::Raw`] or [`ChatMessage::Translated`],
+    /// e.g. from `ChatTranslation`. A [`ChatMessage::Raw`] stays one, which older
+    /// clients understand, unless the translation isn't English.
+    pub fn insert_translation(&mut self, language_id: LanguageId, translation: String) {
+        match self {
+            Self::Raw {
+                english_translation,
+                ..
+            } if language_id == LanguageId::default() => {
+                *english_translation = Some(translation);
+            }
+            Self::Raw {
+                message,
+                detected_language_id,
+                english_translation,
+            } => {
+                let english = english_translation
+                    .take()
+                    .map(|t| (LanguageId::default(), t));
+                *self = Self::Translated {
+                    message: std::mem::take(message),
+                    detected_language_id: *detected_language_id,
+                    translations: english
+                        .into_iter()
+                        .chain([(language_id, translation)])
+                        .collect(),
+                };
+            }
+            Self::Translated { translations, .. } => {
+                translations.insert(language_id, translation);
+            }
+            _ => {}
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
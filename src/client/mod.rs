@@ -3,4 +3,4 @@
 
 mod dto;
 
-pub use dto::{ChatMessage, LanguageDto, LeaderboardScoreDto};
+pub use dto::{ChatMessage, ChatPhrase, LanguageDto, LeaderboardScoreDto, RenderedChat};
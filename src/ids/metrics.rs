@@ -1,6 +1,7 @@
 // SPDX-FileCopyrightText: 2024 Softbear, Inc.
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+use crate::{NonZeroUnixMillis, UnixTime};
 use bitcode::{Decode, Encode};
 use serde::{Deserialize, Serialize};
 use std::fmt::{self, Debug, Display, Formatter};
@@ -81,6 +82,19 @@ impl PeriodId {
     pub fn iter() -> impl Iterator<Item = Self> {
         <Self as IntoEnumIterator>::iter()
     }
+
+    /// Start of the period containing `now`. Days start at midnight UTC and weeks
+    /// on Monday.
+    pub fn start(self, now: NonZeroUnixMillis) -> NonZeroUnixMillis {
+        const DAY: i64 = 24 * 60 * 60 * 1000;
+        let day = now.to_i64().div_euclid(DAY);
+        match self {
+            Self::AllTime => NonZeroUnixMillis::MIN,
+            Self::Daily => NonZeroUnixMillis::from_i64(day * DAY),
+            // The Unix epoch was a Thursday.
+            Self::Weekly => NonZeroUnixMillis::from_i64((day - (day + 3).rem_euclid(7)) * DAY),
+        }
+    }
 }
 
 /// Mirrors <https://github.com/finnbear/db_ip>: `Region`.
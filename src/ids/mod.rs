@@ -24,6 +24,6 @@ pub use realm::{InvalidRealmId, RealmId};
 pub use scene::{InstanceNumber, InvalidSceneId, InvalidTierNumber, SceneId, TierNumber};
 pub use server::{InvalidServerId, ServerId, ServerKind, ServerNumber};
 pub use tokens::{
-    ClientHash, CohortId, ReconnectionToken, ServerToken, SessionId, SessionToken, SkuId,
+    ClientHash, CohortId, ReconnectionToken, RequestId, ServerToken, SessionId, SessionToken, SkuId,
 };
 pub use visitor::{PlayerId, TeamId, TeamToken, UserId, VisitorId};
@@ -3,7 +3,7 @@
 
 #[cfg(test)]
 mod tests {
-    use crate::{InvitationId, PlayerId, ServerNumber};
+    use crate::{InvitationId, NonZeroUnixMillis, PeriodId, PlayerId, ServerNumber, UnixTime};
     use std::str::FromStr;
 
     /*#[test]
@@ -43,4 +43,16 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn period_start() {
+        // Tuesday, November 14, 2023 22:13:20 UTC.
+        let now = NonZeroUnixMillis::from_i64(1_700_000_000_000);
+        assert_eq!(PeriodId::AllTime.start(now), NonZeroUnixMillis::MIN);
+        assert_eq!(PeriodId::Daily.start(now).to_i64(), 1_699_920_000_000);
+        assert_eq!(PeriodId::Weekly.start(now).to_i64(), 1_699_833_600_000);
+        let monday = PeriodId::Weekly.start(now);
+        assert_eq!(PeriodId::Weekly.start(monday), monday);
+        assert_eq!(PeriodId::Daily.start(monday), monday);
+    }
 }
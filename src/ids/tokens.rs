@@ -87,6 +87,27 @@ impl Distribution<ReconnectionToken> for Standard {
     }
 }
 
+/// Identifies a request to Plasma, so that updates can refer to it. Unique per connection.
+#[derive(
+    Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize, Encode, Decode,
+)]
+pub struct RequestId(pub NonZeroU32);
+impl_wrapper_display!(RequestId);
+impl_wrapper_from_str!(RequestId, NonZeroU32);
+
+impl RequestId {
+    /// The next ID in sequence, wrapping around (skipping zero).
+    pub fn next(self) -> Self {
+        Self(self.0.checked_add(1).unwrap_or(NonZeroU32::MIN))
+    }
+}
+
+impl Default for RequestId {
+    fn default() -> Self {
+        Self(NonZeroU32::MIN)
+    }
+}
+
 #[derive(
     Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize, Encode, Decode,
 )]
@@ -0,0 +1,488 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{PlasmaUpdateV1, RealmAcl};
+use crate::{ChatId, NonZeroUnixMillis, RealmId, UnixTime, VisitorId};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+
+/// When reports against a visitor escalate, see [`AbuseLedger`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AbuseThresholds {
+    /// How much a report from a moderator counts, where a player's counts 1.
+    pub moderator_weight: u32,
+    /// Total weight of open reports, counting each reporter once, that mutes the offender.
+    pub mute: u32,
+    /// Milliseconds.
+    pub mute_duration: i64,
+    /// Total weight of open reports, counting each reporter once, that bans the offender
+    /// from the realm.
+    pub ban: u32,
+    /// Milliseconds.
+    pub ban_duration: i64,
+    /// Milliseconds after which reports, and chats that can be reported, are forgotten.
+    pub expiry: i64,
+}
+
+impl Default for AbuseThresholds {
+    fn default() -> Self {
+        Self {
+            moderator_weight: 3,
+            mute: 3,
+            mute_duration: 10 * 60 * 1000,
+            ban: 6,
+            ban_duration: 60 * 60 * 1000,
+            expiry: 24 * 60 * 60 * 1000,
+        }
+    }
+}
+
+/// A `ModerateAbuse` of a chat.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct AbuseReport {
+    pub chat_id: ChatId,
+    pub reporter: VisitorId,
+    /// Whether `reporter` was a moderator at the time.
+    pub moderator: bool,
+    pub timestamp: NonZeroUnixMillis,
+}
+
+/// Consequence of a report that crossed a threshold.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AbuseEscalation {
+    /// The offender's chats in the realm should be dropped until then.
+    Mute {
+        realm_id: RealmId,
+        visitor_id: VisitorId,
+        until: NonZeroUnixMillis,
+    },
+    /// The offender is kept out of the realm until then, see [`AbuseLedger::acl`].
+    Ban {
+        realm_id: RealmId,
+        visitor_id: VisitorId,
+        until: NonZeroUnixMillis,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub enum InvalidAbuseReport {
+    /// The chat wasn't observed, has no visitor ID, or is too old.
+    UnknownChat,
+    /// Visitors can't report themselves.
+    SelfReport,
+    /// The reporter already reported the chat.
+    Duplicate,
+}
+
+impl Display for InvalidAbuseReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for InvalidAbuseReport {}
+
+/// Open reports against a visitor in a realm, for an admin view.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AbuseSummary {
+    pub realm_id: RealmId,
+    pub visitor_id: VisitorId,
+    /// Total weight of open reports.
+    pub weight: u32,
+    pub player_reports: usize,
+    pub moderator_reports: usize,
+    /// Reported chats, oldest first.
+    pub chat_ids: Vec<ChatId>,
+    pub muted_until: Option<NonZeroUnixMillis>,
+    pub banned_until: Option<NonZeroUnixMillis>,
+}
+
+#[derive(Clone, Debug, Default)]
+struct Offender {
+    /// Oldest first.
+    reports: Vec<AbuseReport>,
+    muted_until: Option<NonZeroUnixMillis>,
+    banned_until: Option<NonZeroUnixMillis>,
+}
+
+/// Tracks `ModerateAbuse` reports against the visitors who sent the reported chats,
+/// and escalates to mutes and temporary bans.
+#[derive(Clone, Debug, Default)]
+pub struct AbuseLedger {
+    thresholds: AbuseThresholds,
+    /// Sender of each recent chat.
+    chats: HashMap<ChatId, VisitorId>,
+    moderators: HashSet<VisitorId>,
+    offenders: HashMap<(RealmId, VisitorId), Offender>,
+}
+
+impl AbuseLedger {
+    pub fn new(thresholds: AbuseThresholds) -> Self {
+        Self {
+            thresholds,
+            ..Default::default()
+        }
+    }
+
+    pub fn thresholds(&self) -> &AbuseThresholds {
+        &self.thresholds
+    }
+
+    /// Learns who sent each `Chat`, and who is a moderator from `Player`.
+    pub fn observe(&mut self, update: &PlasmaUpdateV1) {
+        match update {
+            PlasmaUpdateV1::Chat {
+                chat_id,
+                visitor_id: Some(visitor_id),
+                ..
+            } => {
+                self.chats.insert(*chat_id, *visitor_id);
+            }
+            PlasmaUpdateV1::Player {
+                admin,
+                moderator,
+                visitor_id,
+                ..
+            } => {
+                if *admin || *moderator {
+                    self.moderators.insert(*visitor_id);
+                } else {
+                    self.moderators.remove(visitor_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn is_moderator(&self, visitor_id: VisitorId) -> bool {
+        self.moderators.contains(&visitor_id)
+    }
+
+    /// Records a `ModerateAbuse` of `chat_id` by `reporter`, returning the
+    /// escalation if a threshold was crossed.
+    pub fn report(
+        &mut self,
+        chat_id: ChatId,
+        reporter: VisitorId,
+        now: NonZeroUnixMillis,
+    ) -> Result<Option<AbuseEscalation>, InvalidAbuseReport> {
+        self.prune(now);
+        let visitor_id = *self
+            .chats
+            .get(&chat_id)
+            .ok_or(InvalidAbuseReport::UnknownChat)?;
+        if visitor_id == reporter {
+            return Err(InvalidAbuseReport::SelfReport);
+        }
+        let moderator = self.is_moderator(reporter);
+        let realm_id = chat_id.arena_id.realm_id;
+        let offender = self.offenders.entry((realm_id, visitor_id)).or_default();
+        if offender
+            .reports
+            .iter()
+            .any(|r| r.chat_id == chat_id && r.reporter == reporter)
+        {
+            return Err(InvalidAbuseReport::Duplicate);
+        }
+        offender.reports.push(AbuseReport {
+            chat_id,
+            reporter,
+            moderator,
+            timestamp: now,
+        });
+
+        let thresholds = &self.thresholds;
+        let weight = Self::weight(thresholds, offender);
+        let until = |duration: i64| NonZeroUnixMillis::from_i64(now.to_i64() + duration);
+        let active = |until: Option<NonZeroUnixMillis>| until.is_some_and(|until| now < until);
+        Ok(
+            if weight >= thresholds.ban && !active(offender.banned_until) {
+                let until = until(thresholds.ban_duration);
+                offender.banned_until = Some(until);
+                Some(AbuseEscalation::Ban {
+                    realm_id,
+                    visitor_id,
+                    until,
+                })
+            } else if weight >= thresholds.mute
+                && !active(offender.muted_until)
+                && !active(offender.banned_until)
+            {
+                let until = until(thresholds.mute_duration);
+                offender.muted_until = Some(until);
+                Some(AbuseEscalation::Mute {
+                    realm_id,
+                    visitor_id,
+                    until,
+                })
+            } else {
+                None
+            },
+        )
+    }
+
+    /// Each reporter counts once, however many chats they reported, with the weight of
+    /// their weightiest report.
+    fn weight(thresholds: &AbuseThresholds, offender: &Offender) -> u32 {
+        let mut reporters = HashMap::<VisitorId, u32>::new();
+        for report in &offender.reports {
+            let weight = if report.moderator {
+                thresholds.moderator_weight
+            } else {
+                1
+            };
+            let max = reporters.entry(report.reporter).or_default();
+            *max = (*max).max(weight);
+        }
+        reporters.into_values().sum()
+    }
+
+    pub fn is_muted(
+        &self,
+        realm_id: RealmId,
+        visitor_id: VisitorId,
+        now: NonZeroUnixMillis,
+    ) -> bool {
+        self.offenders
+            .get(&(realm_id, visitor_id))
+            .and_then(|o| o.muted_until.max(o.banned_until))
+            .is_some_and(|until| now < until)
+    }
+
+    pub fn is_banned(
+        &self,
+        realm_id: RealmId,
+        visitor_id: VisitorId,
+        now: NonZeroUnixMillis,
+    ) -> bool {
+        self.offenders
+            .get(&(realm_id, visitor_id))
+            .and_then(|o| o.banned_until)
+            .is_some_and(|until| now < until)
+    }
+
+    /// Visitors temporarily banned from `realm_id`, to check in addition to the
+    /// realm's own ACL.
+    pub fn acl(&self, realm_id: RealmId, now: NonZeroUnixMillis) -> RealmAcl {
+        RealmAcl::VisitorBlacklist(
+            self.offenders
+                .iter()
+                .filter(|((r, _), o)| *r == realm_id && o.banned_until.is_some_and(|u| now < u))
+                .map(|(&(_, visitor_id), _)| visitor_id)
+                .collect(),
+        )
+    }
+
+    /// Closes the reports against a visitor in a realm, e.g. after an admin reviewed
+    /// them, leaving any mute or ban in place.
+    pub fn dismiss(&mut self, realm_id: RealmId, visitor_id: VisitorId) {
+        if let Some(offender) = self.offenders.get_mut(&(realm_id, visitor_id)) {
+            offender.reports.clear();
+        }
+    }
+
+    /// Visitors with open reports, or who are muted or banned, most reported first.
+    pub fn summary(&self, now: NonZeroUnixMillis) -> Vec<AbuseSummary> {
+        let active = |until: Option<NonZeroUnixMillis>| until.filter(|&until| now < until);
+        let mut summaries: Vec<_> = self
+            .offenders
+            .iter()
+            .map(|(&(realm_id, visitor_id), offender)| {
+                let moderator_reports = offender.reports.iter().filter(|r| r.moderator).count();
+                AbuseSummary {
+                    realm_id,
+                    visitor_id,
+                    weight: Self::weight(&self.thresholds, offender),
+                    player_reports: offender.reports.len() - moderator_reports,
+                    moderator_reports,
+                    chat_ids: offender.reports.iter().map(|r| r.chat_id).collect(),
+                    muted_until: active(offender.muted_until),
+                    banned_until: active(offender.banned_until),
+                }
+            })
+            .filter(|s| s.weight > 0 || s.muted_until.is_some() || s.banned_until.is_some())
+            .collect();
+        summaries.sort_by(|a, b| {
+            b.weight
+                .cmp(&a.weight)
+                .then_with(|| (a.realm_id, a.visitor_id).cmp(&(b.realm_id, b.visitor_id)))
+        });
+        summaries
+    }
+
+    /// Forgets expired reports, chats, mutes and bans.
+    pub fn prune(&mut self, now: NonZeroUnixMillis) {
+        let since = now.to_i64() - self.thresholds.expiry;
+        self.chats
+            .retain(|chat_id, _| chat_id.message_id.to_i64() > since);
+        self.offenders.retain(|_, offender| {
+            offender.reports.retain(|r| r.timestamp.to_i64() > since);
+            for until in [&mut offender.muted_until, &mut offender.banned_until] {
+                if until.is_some_and(|until| until <= now) {
+                    *until = None;
+                }
+            }
+            !offender.reports.is_empty()
+                || offender.muted_until.is_some()
+                || offender.banned_until.is_some()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plasma::at;
+    use crate::{
+        AbuseEscalation, AbuseLedger, AbuseThresholds, ArenaId, ArenaToken, ChatId, ChatMessage,
+        InvalidAbuseReport, PlasmaUpdateV1, PlayerAlias, PlayerId, RealmAcl, RealmId, ServerId,
+        SessionToken, VisitorId,
+    };
+    use std::num::{NonZeroU16, NonZeroU32, NonZeroU64};
+    use std::str::FromStr;
+
+    fn visitor(n: u64) -> VisitorId {
+        VisitorId(NonZeroU64::new(n).unwrap())
+    }
+
+    /// A chat sent by `sender` at `at(seconds)`.
+    fn chat(sender: u64, seconds: i64) -> (ChatId, PlasmaUpdateV1) {
+        let chat_id = ChatId {
+            arena_id: ArenaId::default(),
+            message_id: at(seconds),
+            server_id: ServerId::from_str("Cloud/1").unwrap(),
+        };
+        let update = PlasmaUpdateV1::Chat {
+            admin: false,
+            alias: PlayerAlias::new_unsanitized("Guest"),
+            authentic: false,
+            chat_id,
+            ip_address: [127, 0, 0, 1].into(),
+            message: ChatMessage::Raw {
+                message: "abuse".to_owned(),
+                detected_language_id: Default::default(),
+                english_translation: None,
+            },
+            player_id: None,
+            recipient: Default::default(),
+            team_name: None,
+            visitor_id: Some(visitor(sender)),
+        };
+        (chat_id, update)
+    }
+
+    fn player(visitor_id: VisitorId, moderator: bool) -> PlasmaUpdateV1 {
+        PlasmaUpdateV1::Player {
+            active_heartbeat: true,
+            admin: false,
+            arena_id: ArenaId::default(),
+            arena_token: ArenaToken(NonZeroU32::MIN),
+            ban: false,
+            moderator,
+            nick_name: None,
+            player_id: PlayerId(NonZeroU16::MIN),
+            session_token: SessionToken(NonZeroU64::MIN),
+            visitor_id,
+        }
+    }
+
+    #[test]
+    fn escalation() {
+        let mut ledger = AbuseLedger::default();
+        let realm_id = RealmId::PublicDefault;
+        let offender = visitor(1);
+        let (first, update) = chat(1, 0);
+        ledger.observe(&update);
+
+        assert!(matches!(
+            ledger.report(first, offender, at(1)),
+            Err(InvalidAbuseReport::SelfReport)
+        ));
+        assert!(matches!(
+            ledger.report(chat(1, 1).0, visitor(3), at(1)),
+            Err(InvalidAbuseReport::UnknownChat)
+        ));
+        assert_eq!(ledger.report(first, visitor(2), at(1)).unwrap(), None);
+        assert!(matches!(
+            ledger.report(first, visitor(2), at(1)),
+            Err(InvalidAbuseReport::Duplicate)
+        ));
+        assert_eq!(ledger.report(first, visitor(3), at(1)).unwrap(), None);
+        assert_eq!(
+            ledger.report(first, visitor(4), at(2)).unwrap(),
+            Some(AbuseEscalation::Mute {
+                realm_id,
+                visitor_id: offender,
+                until: at(2 + 10 * 60),
+            })
+        );
+        assert!(ledger.is_muted(realm_id, offender, at(2)));
+        assert!(!ledger.is_banned(realm_id, offender, at(2)));
+
+        // A moderator's report counts for more.
+        let moderator = visitor(5);
+        ledger.observe(&player(moderator, true));
+        assert!(ledger.is_moderator(moderator));
+        let (second, update) = chat(1, 3);
+        ledger.observe(&update);
+        let Ok(Some(AbuseEscalation::Ban { until, .. })) = ledger.report(second, moderator, at(4))
+        else {
+            panic!();
+        };
+        assert_eq!(until, at(4 + 60 * 60));
+        assert!(ledger.is_banned(realm_id, offender, at(4)));
+        assert_eq!(
+            ledger.acl(realm_id, at(4)),
+            RealmAcl::VisitorBlacklist([offender].into())
+        );
+        assert!(ledger.acl(realm_id, until).allows(Some(offender), None));
+
+        ledger.observe(&player(moderator, false));
+        assert!(!ledger.is_moderator(moderator));
+    }
+
+    #[test]
+    fn one_reporter() {
+        let mut ledger = AbuseLedger::default();
+        let realm_id = RealmId::PublicDefault;
+        let offender = visitor(1);
+        for seconds in 0..6 {
+            let (chat_id, update) = chat(1, seconds);
+            ledger.observe(&update);
+            assert_eq!(ledger.report(chat_id, visitor(2), at(10)).unwrap(), None);
+        }
+        assert!(!ledger.is_muted(realm_id, offender, at(10)));
+        assert_eq!(ledger.summary(at(10))[0].weight, 1);
+    }
+
+    #[test]
+    fn summary_and_expiry() {
+        let mut ledger = AbuseLedger::new(AbuseThresholds {
+            expiry: 60 * 1000,
+            ..Default::default()
+        });
+        for sender in [1, 2] {
+            let (chat_id, update) = chat(sender, sender as i64);
+            ledger.observe(&update);
+            for reporter in 10..10 + sender {
+                ledger.report(chat_id, visitor(reporter), at(1)).unwrap();
+            }
+        }
+        let summary = ledger.summary(at(1));
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].visitor_id, visitor(2));
+        assert_eq!(summary[0].weight, 2);
+        assert_eq!(summary[0].player_reports, 2);
+        assert_eq!(summary[1].chat_ids, [chat(1, 1).0]);
+
+        ledger.dismiss(RealmId::PublicDefault, visitor(2));
+        assert_eq!(ledger.summary(at(1)).len(), 1);
+
+        // Reports and chats expire.
+        ledger.prune(at(62));
+        assert!(ledger.summary(at(62)).is_empty());
+        assert!(matches!(
+            ledger.report(chat(1, 1).0, visitor(20), at(62)),
+            Err(InvalidAbuseReport::UnknownChat)
+        ));
+    }
+}
@@ -0,0 +1,1104 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{
+    ActiveHeartbeat, ArenaHeartbeat, ChatRecipient, ClaimUpdateDto, LeaderboardDiff,
+    NegotiatedProtocol, PlasmaCapability, PlasmaRequest, PlasmaRequestV1, PlasmaRequestV2,
+    PlasmaUpdate, PlasmaUpdateV1, PlasmaUpdateV2, ProtocolVersion, RealmAcl, RealmHeartbeat,
+    RealmUseTopology, RealmUseTopologyDelta, SceneUseTopology, SceneUseTopologyDelta, ServerRole,
+    ServerUseTopology, ServerUseTopologyDelta, TopologyDelta,
+};
+use crate::{
+    ArenaId, ArenaToken, ChatId, ChatMessage, ClientHash, LeaderboardScoreDto, NickName,
+    NonZeroUnixMillis, PeriodId, PlayerAlias, PlayerId, RealmId, RegionId, RequestId, SceneId,
+    ServerId, SessionToken, TeamName, VisitorId,
+};
+use bitcode::{Decode, Encode};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Display, Formatter};
+use std::net::IpAddr;
+
+/// How a frame on the Plasma web socket is encoded.
+///
+/// JSON frames are plain JSON, as before. Bitcode frames start with [`Self::BITCODE_TAG`],
+/// which can't start JSON, so the encoding can change from one frame to the next, and
+/// then [`Self::BITCODE_VERSION`], so that a peer with another layout fails to decode
+/// them instead of decoding garbage.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum FrameEncoding {
+    #[default]
+    Json,
+    /// Only if both sides negotiated [`PlasmaCapability::Bitcode`].
+    Bitcode,
+}
+
+impl FrameEncoding {
+    pub const BITCODE_TAG: u8 = 0xB1;
+    /// Version of the bitcode layout. Must be bumped whenever it changes, e.g. a field
+    /// is added to a `Binary*` type.
+    ///
+    /// 2. Added `percentiles` to `Leaderboard`.
+    /// 3. Added `LeaderboardDiff`.
+    /// 4. Removed `server_id` and `counters` from `ClaimValue`.
+    /// 5. Added `SendChat`, `Arena`, `Chat` and `Player`, and sorted the variants.
+    pub const BITCODE_VERSION: u8 = 5;
+
+    /// Detects the encoding of a frame.
+    pub fn of(frame: &[u8]) -> Self {
+        if frame.first() == Some(&Self::BITCODE_TAG) {
+            Self::Bitcode
+        } else {
+            Self::Json
+        }
+    }
+}
+
+impl NegotiatedProtocol {
+    /// The most compact encoding both sides understand.
+    pub fn frame_encoding(&self) -> FrameEncoding {
+        if self.has(PlasmaCapability::Bitcode) {
+            FrameEncoding::Bitcode
+        } else {
+            FrameEncoding::Json
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum InvalidFrame {
+    Bitcode(String),
+    Json(String),
+    /// Bitcode of another [`FrameEncoding::BITCODE_VERSION`].
+    UnsupportedVersion(u8),
+}
+
+impl Display for InvalidFrame {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for InvalidFrame {}
+
+impl From<serde_json::Error> for InvalidFrame {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e.to_string())
+    }
+}
+
+impl From<bitcode::Error> for InvalidFrame {
+    fn from(e: bitcode::Error) -> Self {
+        Self::Bitcode(e.to_string())
+    }
+}
+
+impl PlasmaRequest {
+    pub fn to_frame(&self, encoding: FrameEncoding) -> Vec<u8> {
+        match encoding {
+            FrameEncoding::Json => serde_json::to_vec(self).unwrap(),
+            FrameEncoding::Bitcode => {
+                let frame = match self {
+                    Self::V1(request) => RequestFrame {
+                        request_id: None,
+                        request: request.into(),
+                    },
+                    Self::V2(PlasmaRequestV2 {
+                        request_id,
+                        request,
+                    }) => RequestFrame {
+                        request_id: Some(*request_id),
+                        request: request.into(),
+                    },
+                };
+                tagged(bitcode::encode(&frame))
+            }
+        }
+    }
+
+    pub fn from_frame(frame: &[u8]) -> Result<Self, InvalidFrame> {
+        match FrameEncoding::of(frame) {
+            FrameEncoding::Json => Ok(serde_json::from_slice(frame)?),
+            FrameEncoding::Bitcode => {
+                let frame: RequestFrame = bitcode::decode(untagged(frame)?)?;
+                let request = frame.request.try_into()?;
+                Ok(match frame.request_id {
+                    None => Self::V1(request),
+                    Some(request_id) => Self::V2(PlasmaRequestV2 {
+                        request_id,
+                        request,
+                    }),
+                })
+            }
+        }
+    }
+}
+
+impl PlasmaUpdate {
+    pub fn to_frame(&self, encoding: FrameEncoding) -> Vec<u8> {
+        match encoding {
+            FrameEncoding::Json => serde_json::to_vec(self).unwrap(),
+            FrameEncoding::Bitcode => {
+                let frame = match self {
+                    Self::V1(updates) => UpdateFrame {
+                        version: ProtocolVersion::V1,
+                        updates: updates.iter().map(|u| (None, u.into())).collect(),
+                    },
+                    Self::V2(updates) => UpdateFrame {
+                        version: ProtocolVersion::V2,
+                        updates: updates
+                            .iter()
+                            .map(|u| (u.correlation_id, (&u.update).into()))
+                            .collect(),
+                    },
+                };
+                tagged(bitcode::encode(&frame))
+            }
+        }
+    }
+
+    /// Like JSON frames, updates that fail to decode are skipped.
+    pub fn from_frame(frame: &[u8]) -> Result<Self, InvalidFrame> {
+        match FrameEncoding::of(frame) {
+            FrameEncoding::Json => Ok(serde_json::from_slice(frame)?),
+            FrameEncoding::Bitcode => {
+                let frame: UpdateFrame = bitcode::decode(untagged(frame)?)?;
+                let updates = frame
+                    .updates
+                    .into_iter()
+                    .filter_map(|(correlation_id, update)| {
+                        Some(PlasmaUpdateV2 {
+                            correlation_id,
+                            update: update.try_into().ok()?,
+                        })
+                    })
+                    .collect();
+                Ok(Self::new(frame.version, updates))
+            }
+        }
+    }
+}
+
+fn tagged(encoded: Vec<u8>) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(encoded.len() + 2);
+    frame.push(FrameEncoding::BITCODE_TAG);
+    frame.push(FrameEncoding::BITCODE_VERSION);
+    frame.extend(encoded);
+    frame
+}
+
+/// The bitcode after the tag and version, if the version is known.
+fn untagged(frame: &[u8]) -> Result<&[u8], InvalidFrame> {
+    match frame.get(1) {
+        Some(&FrameEncoding::BITCODE_VERSION) => Ok(&frame[2..]),
+        Some(&version) => Err(InvalidFrame::UnsupportedVersion(version)),
+        None => Err(InvalidFrame::Bitcode("missing version".to_owned())),
+    }
+}
+
+/// `serde_json::Value` can't be encoded directly, so it is sent as JSON text.
+fn settings_to_text(settings: &Option<serde_json::Value>) -> Option<String> {
+    settings.as_ref().map(|s| s.to_string())
+}
+
+fn settings_from_text(settings: Option<String>) -> Result<Option<serde_json::Value>, InvalidFrame> {
+    Ok(settings.map(|s| serde_json::from_str(&s)).transpose()?)
+}
+
+/// [`IpAddr`], which has no bitcode encoding of its own.
+#[derive(Encode, Decode)]
+enum BinaryIpAddr {
+    V4([u8; 4]),
+    V6([u8; 16]),
+}
+
+impl From<IpAddr> for BinaryIpAddr {
+    fn from(ip_address: IpAddr) -> Self {
+        match ip_address {
+            IpAddr::V4(ip_address) => Self::V4(ip_address.octets()),
+            IpAddr::V6(ip_address) => Self::V6(ip_address.octets()),
+        }
+    }
+}
+
+impl From<BinaryIpAddr> for IpAddr {
+    fn from(ip_address: BinaryIpAddr) -> Self {
+        match ip_address {
+            BinaryIpAddr::V4(octets) => octets.into(),
+            BinaryIpAddr::V6(octets) => octets.into(),
+        }
+    }
+}
+
+#[derive(Encode, Decode)]
+struct RequestFrame {
+    request_id: Option<RequestId>,
+    request: BinaryRequest,
+}
+
+/// Frequent requests, and JSON for the rest.
+#[derive(Encode, Decode)]
+enum BinaryRequest {
+    AuthenticatePlayer {
+        arena_id: ArenaId,
+        arena_token: ArenaToken,
+        player_id: PlayerId,
+        session_token: SessionToken,
+    },
+    Heartbeat {
+        claims: Vec<ClaimUpdateDto>,
+        client_hash: ClientHash,
+        cpu: f32,
+        ram: f32,
+        missed_ticks: f32,
+        date_certificate_expires: Option<NonZeroUnixMillis>,
+        realms: BTreeMap<RealmId, BinaryRealmHeartbeat>,
+    },
+    SendChat {
+        admin: bool,
+        alias: PlayerAlias,
+        arena_id: ArenaId,
+        authentic: bool,
+        ip_address: BinaryIpAddr,
+        message: String,
+        player_id: Option<PlayerId>,
+        team_name: Option<TeamName>,
+        timestamp: NonZeroUnixMillis,
+        visitor_id: Option<VisitorId>,
+        recipient: ChatRecipient,
+    },
+    Json(String),
+}
+
+impl From<&PlasmaRequestV1> for BinaryRequest {
+    fn from(request: &PlasmaRequestV1) -> Self {
+        match request {
+            PlasmaRequestV1::AuthenticatePlayer {
+                arena_id,
+                arena_token,
+                player_id,
+                session_token,
+            } => Self::AuthenticatePlayer {
+                arena_id: *arena_id,
+                arena_token: *arena_token,
+                player_id: *player_id,
+                session_token: *session_token,
+            },
+            PlasmaRequestV1::Heartbeat {
+                claims,
+                client_hash,
+                cpu,
+                ram,
+                missed_ticks,
+                date_certificate_expires,
+                realms,
+            } => Self::Heartbeat {
+                claims: claims.to_vec(),
+                client_hash: *client_hash,
+                cpu: *cpu,
+                ram: *ram,
+                missed_ticks: *missed_ticks,
+                date_certificate_expires: *date_certificate_expires,
+                realms: realms.iter().map(|(k, v)| (*k, v.into())).collect(),
+            },
+            PlasmaRequestV1::SendChat {
+                admin,
+                alias,
+                arena_id,
+                authentic,
+                ip_address,
+                message,
+                player_id,
+                team_name,
+                timestamp,
+                visitor_id,
+                recipient,
+            } => Self::SendChat {
+                admin: *admin,
+                alias: *alias,
+                arena_id: *arena_id,
+                authentic: *authentic,
+                ip_address: (*ip_address).into(),
+                message: message.clone(),
+                player_id: *player_id,
+                team_name: *team_name,
+                timestamp: *timestamp,
+                visitor_id: *visitor_id,
+                recipient: *recipient,
+            },
+            request => Self::Json(serde_json::to_string(request).unwrap()),
+        }
+    }
+}
+
+impl TryFrom<BinaryRequest> for PlasmaRequestV1 {
+    type Error = InvalidFrame;
+
+    fn try_from(request: BinaryRequest) -> Result<Self, Self::Error> {
+        Ok(match request {
+            BinaryRequest::AuthenticatePlayer {
+                arena_id,
+                arena_token,
+                player_id,
+                session_token,
+            } => Self::AuthenticatePlayer {
+                arena_id,
+                arena_token,
+                player_id,
+                session_token,
+            },
+            BinaryRequest::Heartbeat {
+                claims,
+                client_hash,
+                cpu,
+                ram,
+                missed_ticks,
+                date_certificate_expires,
+                realms,
+            } => Self::Heartbeat {
+                claims: claims.into_boxed_slice(),
+                client_hash,
+                cpu,
+                ram,
+                missed_ticks,
+                date_certificate_expires,
+                realms: realms
+                    .into_iter()
+                    .map(|(k, v)| Ok((k, v.try_into()?)))
+                    .collect::<Result<_, InvalidFrame>>()?,
+            },
+            BinaryRequest::SendChat {
+                admin,
+                alias,
+                arena_id,
+                authentic,
+                ip_address,
+                message,
+                player_id,
+                team_name,
+                timestamp,
+                visitor_id,
+                recipient,
+            } => Self::SendChat {
+                admin,
+                alias,
+                arena_id,
+                authentic,
+                ip_address: ip_address.into(),
+                message,
+                player_id,
+                team_name,
+                timestamp,
+                visitor_id,
+                recipient,
+            },
+            BinaryRequest::Json(json) => serde_json::from_str(&json)?,
+        })
+    }
+}
+
+#[derive(Encode, Decode)]
+struct BinaryRealmHeartbeat {
+    scenes: BTreeMap<SceneId, BinaryArenaHeartbeat>,
+}
+
+impl From<&RealmHeartbeat> for BinaryRealmHeartbeat {
+    fn from(realm: &RealmHeartbeat) -> Self {
+        Self {
+            scenes: realm.scenes.iter().map(|(k, v)| (*k, v.into())).collect(),
+        }
+    }
+}
+
+impl TryFrom<BinaryRealmHeartbeat> for RealmHeartbeat {
+    type Error = InvalidFrame;
+
+    fn try_from(realm: BinaryRealmHeartbeat) -> Result<Self, Self::Error> {
+        Ok(Self {
+            scenes: realm
+                .scenes
+                .into_iter()
+                .map(|(k, v)| Ok((k, v.try_into()?)))
+                .collect::<Result<_, InvalidFrame>>()?,
+        })
+    }
+}
+
+#[derive(Encode, Decode)]
+struct BinaryArenaHeartbeat {
+    actives: HashMap<PlayerId, ActiveHeartbeat>,
+    player_count: u16,
+    settings: Option<String>,
+    tick_duration: f32,
+}
+
+impl From<&ArenaHeartbeat> for BinaryArenaHeartbeat {
+    fn from(arena: &ArenaHeartbeat) -> Self {
+        Self {
+            actives: arena.actives.clone(),
+            player_count: arena.player_count,
+            settings: settings_to_text(&arena.settings),
+            tick_duration: arena.tick_duration,
+        }
+    }
+}
+
+impl TryFrom<BinaryArenaHeartbeat> for ArenaHeartbeat {
+    type Error = InvalidFrame;
+
+    fn try_from(arena: BinaryArenaHeartbeat) -> Result<Self, Self::Error> {
+        Ok(Self {
+            actives: arena.actives,
+            player_count: arena.player_count,
+            settings: settings_from_text(arena.settings)?,
+            tick_duration: arena.tick_duration,
+        })
+    }
+}
+
+#[derive(Encode, Decode)]
+struct UpdateFrame {
+    version: ProtocolVersion,
+    updates: Vec<(Option<RequestId>, BinaryUpdate)>,
+}
+
+/// Frequent updates, and JSON for the rest.
+#[derive(Encode, Decode)]
+enum BinaryUpdate {
+    Arena {
+        arena_id: ArenaId,
+        arena_token: ArenaToken,
+    },
+    Chat {
+        admin: bool,
+        alias: PlayerAlias,
+        authentic: bool,
+        chat_id: ChatId,
+        ip_address: BinaryIpAddr,
+        message: ChatMessage,
+        player_id: Option<PlayerId>,
+        recipient: ChatRecipient,
+        team_name: Option<TeamName>,
+        visitor_id: Option<VisitorId>,
+    },
+    Claims {
+        claims: Vec<ClaimUpdateDto>,
+    },
+    Heartbeat,
+    Leaderboard {
+        period_id: PeriodId,
+        realm_id: RealmId,
+        scores: Vec<LeaderboardScoreDto>,
+        percentiles: Option<Vec<u32>>,
+    },
+    LeaderboardDiff {
+        period_id: PeriodId,
+        realm_id: RealmId,
+        upserted: Vec<LeaderboardScoreDto>,
+        removed: Vec<PlayerAlias>,
+        percentiles: Option<Vec<u32>>,
+    },
+    Player {
+        active_heartbeat: bool,
+        admin: bool,
+        arena_id: ArenaId,
+        arena_token: ArenaToken,
+        ban: bool,
+        moderator: bool,
+        nick_name: Option<NickName>,
+        player_id: PlayerId,
+        session_token: SessionToken,
+        visitor_id: VisitorId,
+    },
+    Role {
+        role: ServerRole,
+    },
+    Topology {
+        servers: HashMap<ServerId, BinaryServerTopology>,
+        sequence: u64,
+    },
+    TopologyDelta {
+        sequence: u64,
+        added: HashMap<ServerId, BinaryServerTopology>,
+        removed: HashSet<ServerId>,
+        changed: HashMap<ServerId, BinaryServerTopologyDelta>,
+    },
+    Json(String),
+}
+
+impl From<&PlasmaUpdateV1> for BinaryUpdate {
+    fn from(update: &PlasmaUpdateV1) -> Self {
+        match update {
+            PlasmaUpdateV1::Arena {
+                arena_id,
+                arena_token,
+            } => Self::Arena {
+                arena_id: *arena_id,
+                arena_token: *arena_token,
+            },
+            PlasmaUpdateV1::Chat {
+                admin,
+                alias,
+                authentic,
+                chat_id,
+                ip_address,
+                message,
+                player_id,
+                recipient,
+                team_name,
+                visitor_id,
+            } => Self::Chat {
+                admin: *admin,
+                alias: *alias,
+                authentic: *authentic,
+                chat_id: *chat_id,
+                ip_address: (*ip_address).into(),
+                message: message.clone(),
+                player_id: *player_id,
+                recipient: *recipient,
+                team_name: *team_name,
+                visitor_id: *visitor_id,
+            },
+            PlasmaUpdateV1::Claims { claims } => Self::Claims {
+                claims: claims.to_vec(),
+            },
+            PlasmaUpdateV1::Heartbeat {} => Self::Heartbeat,
+            PlasmaUpdateV1::Leaderboard {
+                period_id,
+                realm_id,
+                scores,
+                percentiles,
+            } => Self::Leaderboard {
+                period_id: *period_id,
+                realm_id: *realm_id,
+                scores: scores.to_vec(),
+                percentiles: percentiles.as_ref().map(|p| p.to_vec()),
+            },
+            PlasmaUpdateV1::LeaderboardDiff {
+                period_id,
+                realm_id,
+                diff,
+                percentiles,
+            } => Self::LeaderboardDiff {
+                period_id: *period_id,
+                realm_id: *realm_id,
+                upserted: diff.upserted.to_vec(),
+                removed: diff.removed.to_vec(),
+                percentiles: percentiles.as_ref().map(|p| p.to_vec()),
+            },
+            PlasmaUpdateV1::Player {
+                active_heartbeat,
+                admin,
+                arena_id,
+                arena_token,
+                ban,
+                moderator,
+                nick_name,
+                player_id,
+                session_token,
+                visitor_id,
+            } => Self::Player {
+                active_heartbeat: *active_heartbeat,
+                admin: *admin,
+                arena_id: *arena_id,
+                arena_token: *arena_token,
+                ban: *ban,
+                moderator: *moderator,
+                nick_name: *nick_name,
+                player_id: *player_id,
+                session_token: *session_token,
+                visitor_id: *visitor_id,
+            },
+            PlasmaUpdateV1::Role { role } => Self::Role { role: *role },
+            PlasmaUpdateV1::Topology { servers, sequence } => Self::Topology {
+                servers: servers.iter().map(|(k, v)| (*k, v.into())).collect(),
+                sequence: *sequence,
+            },
+            PlasmaUpdateV1::TopologyDelta { delta } => Self::TopologyDelta {
+                sequence: delta.sequence,
+                added: delta.added.iter().map(|(k, v)| (*k, v.into())).collect(),
+                removed: delta.removed.clone(),
+                changed: delta.changed.iter().map(|(k, v)| (*k, v.into())).collect(),
+            },
+            update => Self::Json(serde_json::to_string(update).unwrap()),
+        }
+    }
+}
+
+impl TryFrom<BinaryUpdate> for PlasmaUpdateV1 {
+    type Error = InvalidFrame;
+
+    fn try_from(update: BinaryUpdate) -> Result<Self, Self::Error> {
+        Ok(match update {
+            BinaryUpdate::Arena {
+                arena_id,
+                arena_token,
+            } => Self::Arena {
+                arena_id,
+                arena_token,
+            },
+            BinaryUpdate::Chat {
+                admin,
+                alias,
+                authentic,
+                chat_id,
+                ip_address,
+                message,
+                player_id,
+                recipient,
+                team_name,
+                visitor_id,
+            } => Self::Chat {
+                admin,
+                alias,
+                authentic,
+                chat_id,
+                ip_address: ip_address.into(),
+                message,
+                player_id,
+                recipient,
+                team_name,
+                visitor_id,
+            },
+            BinaryUpdate::Claims { claims } => Self::Claims {
+                claims: claims.into_boxed_slice(),
+            },
+            BinaryUpdate::Heartbeat => Self::Heartbeat {},
+            BinaryUpdate::Leaderboard {
+                period_id,
+                realm_id,
+                scores,
+                percentiles,
+            } => Self::Leaderboard {
+                period_id,
+                realm_id,
+                scores: scores.into_boxed_slice(),
+                percentiles: percentiles.map(Vec::into_boxed_slice),
+            },
+            BinaryUpdate::LeaderboardDiff {
+                period_id,
+                realm_id,
+                upserted,
+                removed,
+                percentiles,
+            } => Self::LeaderboardDiff {
+                period_id,
+                realm_id,
+                diff: LeaderboardDiff {
+                    upserted: upserted.into_boxed_slice(),
+                    removed: removed.into_boxed_slice(),
+                },
+                percentiles: percentiles.map(Vec::into_boxed_slice),
+            },
+            BinaryUpdate::Player {
+                active_heartbeat,
+                admin,
+                arena_id,
+                arena_token,
+                ban,
+                moderator,
+                nick_name,
+                player_id,
+                session_token,
+                visitor_id,
+            } => Self::Player {
+                active_heartbeat,
+                admin,
+                arena_id,
+                arena_token,
+                ban,
+                moderator,
+                nick_name,
+                player_id,
+                session_token,
+                visitor_id,
+            },
+            BinaryUpdate::Role { role } => Self::Role { role },
+            BinaryUpdate::Topology { servers, sequence } => Self::Topology {
+                servers: servers
+                    .into_iter()
+                    .map(|(k, v)| Ok((k, v.try_into()?)))
+                    .collect::<Result<_, InvalidFrame>>()?,
+                sequence,
+            },
+            BinaryUpdate::TopologyDelta {
+                sequence,
+                added,
+                removed,
+                changed,
+            } => Self::TopologyDelta {
+                delta: TopologyDelta {
+                    sequence,
+                    added: added
+                        .into_iter()
+                        .map(|(k, v)| Ok((k, v.try_into()?)))
+                        .collect::<Result<_, InvalidFrame>>()?,
+                    removed,
+                    changed: changed
+                        .into_iter()
+                        .map(|(k, v)| Ok((k, v.try_into()?)))
+                        .collect::<Result<_, InvalidFrame>>()?,
+                },
+            },
+            BinaryUpdate::Json(json) => serde_json::from_str(&json)?,
+        })
+    }
+}
+
+#[derive(Encode, Decode)]
+struct BinaryServerTopology {
+    datacenter: String,
+    default_realm: Option<BinaryRealmTopology>,
+    other_realms: HashMap<RealmId, BinaryRealmTopology>,
+    region_id: RegionId,
+}
+
+impl From<&ServerUseTopology> for BinaryServerTopology {
+    fn from(server: &ServerUseTopology) -> Self {
+        Self {
+            datacenter: server.datacenter.clone(),
+            default_realm: server.default_realm.as_ref().map(Into::into),
+            other_realms: server
+                .other_realms
+                .iter()
+                .map(|(k, v)| (*k, v.into()))
+                .collect(),
+            region_id: server.region_id,
+        }
+    }
+}
+
+impl TryFrom<BinaryServerTopology> for ServerUseTopology {
+    type Error = InvalidFrame;
+
+    fn try_from(server: BinaryServerTopology) -> Result<Self, Self::Error> {
+        Ok(Self {
+            datacenter: server.datacenter,
+            default_realm: server.default_realm.map(TryInto::try_into).transpose()?,
+            other_realms: server
+                .other_realms
+                .into_iter()
+                .map(|(k, v)| Ok((k, v.try_into()?)))
+                .collect::<Result<_, InvalidFrame>>()?,
+            region_id: server.region_id,
+        })
+    }
+}
+
+#[derive(Encode, Decode)]
+struct BinaryRealmTopology {
+    acl: RealmAcl,
+    scenes: HashMap<SceneId, BinarySceneTopology>,
+}
+
+impl From<&RealmUseTopology> for BinaryRealmTopology {
+    fn from(realm: &RealmUseTopology) -> Self {
+        Self {
+            acl: realm.acl.clone(),
+            scenes: realm
+                .scenes
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        *k,
+                        BinarySceneTopology {
+                            player_count: v.player_count,
+                            settings: settings_to_text(&v.settings),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+impl TryFrom<BinaryRealmTopology> for RealmUseTopology {
+    type Error = InvalidFrame;
+
+    fn try_from(realm: BinaryRealmTopology) -> Result<Self, Self::Error> {
+        Ok(Self {
+            acl: realm.acl,
+            scenes: realm
+                .scenes
+                .into_iter()
+                .map(|(k, v)| {
+                    Ok((
+                        k,
+                        SceneUseTopology {
+                            player_count: v.player_count,
+                            settings: settings_from_text(v.settings)?,
+                        },
+                    ))
+                })
+                .collect::<Result<_, InvalidFrame>>()?,
+        })
+    }
+}
+
+#[derive(Encode, Decode)]
+struct BinarySceneTopology {
+    player_count: u16,
+    settings: Option<String>,
+}
+
+#[derive(Encode, Decode)]
+struct BinaryServerTopologyDelta {
+    realms: HashMap<RealmId, BinaryRealmTopologyDelta>,
+    removed_realms: HashSet<RealmId>,
+}
+
+impl From<&ServerUseTopologyDelta> for BinaryServerTopologyDelta {
+    fn from(server: &ServerUseTopologyDelta) -> Self {
+        Self {
+            realms: server.realms.iter().map(|(k, v)| (*k, v.into())).collect(),
+            removed_realms: server.removed_realms.clone(),
+        }
+    }
+}
+
+impl TryFrom<BinaryServerTopologyDelta> for ServerUseTopologyDelta {
+    type Error = InvalidFrame;
+
+    fn try_from(server: BinaryServerTopologyDelta) -> Result<Self, Self::Error> {
+        Ok(Self {
+            realms: server
+                .realms
+                .into_iter()
+                .map(|(k, v)| Ok((k, v.try_into()?)))
+                .collect::<Result<_, InvalidFrame>>()?,
+            removed_realms: server.removed_realms,
+        })
+    }
+}
+
+#[derive(Encode, Decode)]
+struct BinaryRealmTopologyDelta {
+    acl: Option<RealmAcl>,
+    scenes: HashMap<SceneId, BinarySceneTopologyDelta>,
+    removed_scenes: HashSet<SceneId>,
+}
+
+impl From<&RealmUseTopologyDelta> for BinaryRealmTopologyDelta {
+    fn from(realm: &RealmUseTopologyDelta) -> Self {
+        Self {
+            acl: realm.acl.clone(),
+            scenes: realm
+                .scenes
+                .iter()
+                .map(|(k, v)| {
+                    (
+                        *k,
+                        BinarySceneTopologyDelta {
+                            player_count: v.player_count,
+                            settings: settings_to_text(&v.settings),
+                        },
+                    )
+                })
+                .collect(),
+            removed_scenes: realm.removed_scenes.clone(),
+        }
+    }
+}
+
+impl TryFrom<BinaryRealmTopologyDelta> for RealmUseTopologyDelta {
+    type Error = InvalidFrame;
+
+    fn try_from(realm: BinaryRealmTopologyDelta) -> Result<Self, Self::Error> {
+        Ok(Self {
+            acl: realm.acl,
+            scenes: realm
+                .scenes
+                .into_iter()
+                .map(|(k, v)| {
+                    Ok((
+                        k,
+                        SceneUseTopologyDelta {
+                            player_count: v.player_count,
+                            settings: settings_from_text(v.settings)?,
+                        },
+                    ))
+                })
+                .collect::<Result<_, InvalidFrame>>()?,
+            removed_scenes: realm.removed_scenes,
+        })
+    }
+}
+
+#[derive(Encode, Decode)]
+struct BinarySceneTopologyDelta {
+    player_count: Option<u16>,
+    settings: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ArenaHeartbeat, ArenaId, ChatId, ChatMessage, ChatRecipient, FrameEncoding, InvalidFrame,
+        NickName, NonZeroUnixMillis, PlasmaRequest, PlasmaRequestV1, PlasmaRequestV2, PlasmaUpdate,
+        PlasmaUpdateV1, PlasmaUpdateV2, PlayerAlias, PlayerId, RealmHeartbeat, RealmId,
+        RealmUseTopology, RegionId, RequestId, SceneId, SceneUseTopology, ServerId, ServerRole,
+        ServerUseTopology, SessionToken, TeamName, UnixTime, VisitorId,
+    };
+    use std::collections::HashMap;
+    use std::num::{NonZeroU16, NonZeroU64};
+    use std::str::FromStr;
+
+    fn heartbeat(arenas: u8) -> PlasmaRequestV1 {
+        let mut realm = RealmHeartbeat::default();
+        for i in 0..arenas {
+            realm.scenes.insert(
+                SceneId::new(None, crate::InstanceNumber(i)),
+                ArenaHeartbeat {
+                    actives: Default::default(),
+                    player_count: 40 + i as u16,
+                    settings: (i == 0).then(|| serde_json::json!({ "max_players": 50 })),
+                    tick_duration: 0.1,
+                },
+            );
+        }
+        PlasmaRequestV1::Heartbeat {
+            claims: Default::default(),
+            client_hash: 42,
+            cpu: 0.5,
+            ram: 0.25,
+            missed_ticks: 0.0,
+            date_certificate_expires: None,
+            realms: [(RealmId::PublicDefault, realm)].into_iter().collect(),
+        }
+    }
+
+    fn json<T: serde::Serialize>(value: &T) -> serde_json::Value {
+        serde_json::to_value(value).unwrap()
+    }
+
+    #[test]
+    fn requests() {
+        for request in [
+            PlasmaRequest::V1(heartbeat(3)),
+            PlasmaRequest::V1(PlasmaRequestV1::SendChat {
+                admin: false,
+                alias: PlayerAlias::new_unsanitized("Guest"),
+                arena_id: ArenaId::default(),
+                authentic: false,
+                ip_address: [127, 0, 0, 1].into(),
+                message: "hi".to_owned(),
+                player_id: Some(PlayerId(NonZeroU16::MIN)),
+                team_name: Some(TeamName::new_input_sanitized("foo")),
+                timestamp: NonZeroUnixMillis::from_i64(1_700_000_000_000),
+                visitor_id: None,
+                recipient: ChatRecipient::Broadcast,
+            }),
+            PlasmaRequest::V2(PlasmaRequestV2 {
+                request_id: RequestId::default(),
+                request: PlasmaRequestV1::RequestTopology {},
+            }),
+        ] {
+            for encoding in [FrameEncoding::Json, FrameEncoding::Bitcode] {
+                let frame = request.to_frame(encoding);
+                assert_eq!(FrameEncoding::of(&frame), encoding);
+                let decoded = PlasmaRequest::from_frame(&frame).unwrap();
+                assert_eq!(json(&decoded), json(&request));
+            }
+        }
+
+        let mut frame = PlasmaRequest::V1(heartbeat(1)).to_frame(FrameEncoding::Bitcode);
+        frame[1] = FrameEncoding::BITCODE_VERSION + 1;
+        assert!(matches!(
+            PlasmaRequest::from_frame(&frame),
+            Err(InvalidFrame::UnsupportedVersion(v)) if v == frame[1]
+        ));
+        assert!(PlasmaUpdate::from_frame(&[FrameEncoding::BITCODE_TAG]).is_err());
+
+        let request = PlasmaRequest::V1(heartbeat(100));
+        let json_len = request.to_frame(FrameEncoding::Json).len();
+        let bitcode_len = request.to_frame(FrameEncoding::Bitcode).len();
+        assert!(bitcode_len * 4 < json_len, "{bitcode_len} vs {json_len}");
+    }
+
+    #[test]
+    fn updates() {
+        let server = ServerUseTopology {
+            datacenter: "dc".to_owned(),
+            default_realm: Some(RealmUseTopology {
+                acl: Default::default(),
+                scenes: [(
+                    SceneId::default(),
+                    SceneUseTopology {
+                        player_count: 3,
+                        settings: Some(serde_json::json!({ "max_players": 50 })),
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            }),
+            other_realms: Default::default(),
+            region_id: RegionId::Europe,
+        };
+        let update = PlasmaUpdate::V2(
+            vec![
+                PlasmaUpdateV2 {
+                    correlation_id: Some(RequestId::default()),
+                    update: PlasmaUpdateV1::Topology {
+                        servers: HashMap::from([(ServerId::from_str("Cloud/1").unwrap(), server)]),
+                        sequence: 7,
+                    },
+                },
+                PlasmaUpdateV1::Role {
+                    role: ServerRole::Public,
+                }
+                .into(),
+                PlasmaUpdateV1::Arena {
+                    arena_id: ArenaId::default(),
+                    arena_token: crate::ArenaToken(std::num::NonZeroU32::MIN),
+                }
+                .into(),
+                PlasmaUpdateV1::Chat {
+                    admin: false,
+                    alias: PlayerAlias::new_unsanitized("Guest"),
+                    authentic: true,
+                    chat_id: ChatId {
+                        arena_id: ArenaId::default(),
+                        message_id: NonZeroUnixMillis::from_i64(1_700_000_000_000),
+                        server_id: ServerId::from_str("Cloud/1").unwrap(),
+                    },
+                    ip_address: "::1".parse().unwrap(),
+                    message: ChatMessage::Raw {
+                        message: "hi".to_owned(),
+                        detected_language_id: Default::default(),
+                        english_translation: None,
+                    },
+                    player_id: Some(PlayerId(NonZeroU16::MIN)),
+                    recipient: ChatRecipient::TeamOf(PlayerId(NonZeroU16::MIN)),
+                    team_name: None,
+                    visitor_id: None,
+                }
+                .into(),
+                PlasmaUpdateV1::Player {
+                    active_heartbeat: true,
+                    admin: false,
+                    arena_id: ArenaId::default(),
+                    arena_token: crate::ArenaToken(std::num::NonZeroU32::MIN),
+                    ban: false,
+                    moderator: true,
+                    nick_name: Some(NickName::new("nick")),
+                    player_id: PlayerId(NonZeroU16::MIN),
+                    session_token: SessionToken(NonZeroU64::MIN),
+                    visitor_id: VisitorId(NonZeroU64::MIN),
+                }
+                .into(),
+                // Falls back to JSON.
+                PlasmaUpdateV1::TeamNameUnavailable {
+                    arena_id: ArenaId::default(),
+                    player_id: PlayerId(NonZeroU16::MIN),
+                    team_name: crate::TeamName::new_input_sanitized("foo"),
+                }
+                .into(),
+            ]
+            .into_boxed_slice(),
+        );
+        for encoding in [FrameEncoding::Json, FrameEncoding::Bitcode] {
+            let frame = update.to_frame(encoding);
+            let decoded = PlasmaUpdate::from_frame(&frame).unwrap();
+            assert_eq!(json(&decoded), json(&update));
+        }
+    }
+}
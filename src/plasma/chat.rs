@@ -0,0 +1,301 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{ChatRecipient, PlasmaUpdateV1};
+use crate::{ArenaId, PlayerId, ServerId, TeamName, VisitorId};
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+
+/// A local player that can receive chats, see [`ChatRouter`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChatMember {
+    pub arena_id: ArenaId,
+    pub team_name: Option<TeamName>,
+    pub visitor_id: Option<VisitorId>,
+    /// Chats from these visitors are hidden.
+    pub blocked_visitors: HashSet<VisitorId>,
+    /// Chats from these IP addresses are hidden.
+    pub blocked_ips: HashSet<IpAddr>,
+}
+
+impl ChatMember {
+    pub fn new(arena_id: ArenaId, visitor_id: Option<VisitorId>) -> Self {
+        Self {
+            arena_id,
+            team_name: None,
+            visitor_id,
+            blocked_visitors: Default::default(),
+            blocked_ips: Default::default(),
+        }
+    }
+
+    /// Whether this member blocked the sender of a chat.
+    pub fn blocks(&self, visitor_id: Option<VisitorId>, ip_address: IpAddr) -> bool {
+        visitor_id.is_some_and(|v| self.blocked_visitors.contains(&v))
+            || self.blocked_ips.contains(&ip_address)
+    }
+}
+
+/// Decides which local players receive each `Chat` update, according to its
+/// [`ChatRecipient`] and the recipients' block lists.
+///
+/// Admin chats can't be blocked, and senders always receive their own chats.
+/// [`PlayerId`]s are only unique within a server, so chats from other servers only
+/// reach local players by [`ChatRecipient::Broadcast`], and never as their senders.
+#[derive(Clone, Debug)]
+pub struct ChatRouter {
+    server_id: ServerId,
+    members: BTreeMap<PlayerId, ChatMember>,
+}
+
+impl ChatRouter {
+    /// For the players of the server `server_id`.
+    pub fn new(server_id: ServerId) -> Self {
+        Self {
+            server_id,
+            members: Default::default(),
+        }
+    }
+
+    pub fn server_id(&self) -> ServerId {
+        self.server_id
+    }
+
+    /// Adds or replaces a player, e.g. when they join an arena.
+    pub fn insert(&mut self, player_id: PlayerId, member: ChatMember) {
+        self.members.insert(player_id, member);
+    }
+
+    pub fn remove(&mut self, player_id: PlayerId) -> Option<ChatMember> {
+        self.members.remove(&player_id)
+    }
+
+    pub fn get(&self, player_id: PlayerId) -> Option<&ChatMember> {
+        self.members.get(&player_id)
+    }
+
+    pub fn get_mut(&mut self, player_id: PlayerId) -> Option<&mut ChatMember> {
+        self.members.get_mut(&player_id)
+    }
+
+    /// Local players that receive `update`, in ascending order. Empty for updates
+    /// other than `Chat`.
+    pub fn route(&self, update: &PlasmaUpdateV1) -> Vec<PlayerId> {
+        let PlasmaUpdateV1::Chat {
+            admin,
+            chat_id,
+            ip_address,
+            player_id: sender,
+            recipient,
+            visitor_id,
+            ..
+        } = update
+        else {
+            return Vec::new();
+        };
+        let arena_id = chat_id.arena_id;
+        let local = chat_id.server_id == self.server_id;
+        let team_of = |player_id: &PlayerId| {
+            self.members
+                .get(player_id)
+                .filter(|m| m.arena_id == arena_id)
+                .and_then(|m| m.team_name)
+        };
+        self.members
+            .iter()
+            .filter(|&(&player_id, member)| {
+                if local && Some(player_id) == *sender && member.arena_id == arena_id {
+                    return *recipient != ChatRecipient::None;
+                }
+                let addressed = match recipient {
+                    ChatRecipient::Broadcast => member.arena_id.realm_id == arena_id.realm_id,
+                    _ if !local => false,
+                    ChatRecipient::Arena => member.arena_id == arena_id,
+                    ChatRecipient::Player(target) => player_id == *target,
+                    ChatRecipient::TeamOf(target) => {
+                        player_id == *target
+                            || (member.arena_id == arena_id
+                                && team_of(target).is_some_and(|t| member.team_name == Some(t)))
+                    }
+                    ChatRecipient::None => false,
+                };
+                addressed && (*admin || !member.blocks(*visitor_id, *ip_address))
+            })
+            .map(|(&player_id, _)| player_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ArenaId, ChatId, ChatMember, ChatMessage, ChatRecipient, ChatRouter, InstanceNumber,
+        NonZeroUnixMillis, PlasmaUpdateV1, PlayerAlias, PlayerId, RealmId, RealmName, SceneId,
+        ServerId, ServerKind, ServerNumber, TeamName, UnixTime, VisitorId,
+    };
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::num::{NonZeroU16, NonZeroU64};
+
+    fn player(n: u16) -> PlayerId {
+        PlayerId(NonZeroU16::new(n).unwrap())
+    }
+
+    fn visitor(n: u64) -> VisitorId {
+        VisitorId(NonZeroU64::new(n).unwrap())
+    }
+
+    fn ip(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, n))
+    }
+
+    fn arena(realm_id: RealmId, scene: u8) -> ArenaId {
+        ArenaId::new(realm_id, SceneId::new(None, InstanceNumber::new(scene)))
+    }
+
+    fn server(n: u8) -> ServerId {
+        ServerId {
+            number: ServerNumber::new(n).unwrap(),
+            kind: ServerKind::Cloud,
+        }
+    }
+
+    /// A chat from player `n` of server 1, whose visitor ID and IP address are also `n`.
+    fn chat(n: u16, arena_id: ArenaId, recipient: ChatRecipient) -> PlasmaUpdateV1 {
+        PlasmaUpdateV1::Chat {
+            admin: false,
+            alias: PlayerAlias::new_unsanitized("Sender"),
+            authentic: false,
+            chat_id: ChatId {
+                arena_id,
+                message_id: NonZeroUnixMillis::from_i64(1_700_000_000_000),
+                server_id: server(1),
+            },
+            ip_address: ip(n as u8),
+            message: ChatMessage::Raw {
+                message: "Hi".to_owned(),
+                detected_language_id: Default::default(),
+                english_translation: None,
+            },
+            player_id: Some(player(n)),
+            recipient,
+            team_name: None,
+            visitor_id: Some(visitor(n as u64)),
+        }
+    }
+
+    /// Players of server 1. Players 1-3 are in `main`, 1 and 2 on team "A"; 4 is in
+    /// another arena of the same realm; 5 is in another realm.
+    fn router(main: ArenaId) -> ChatRouter {
+        let other = arena(RealmId::PublicDefault, 1);
+        let named = arena(RealmId::Named(RealmName::new("named")), 0);
+        let mut router = ChatRouter::new(server(1));
+        for (n, arena_id) in [(1, main), (2, main), (3, main), (4, other), (5, named)] {
+            router.insert(
+                player(n),
+                ChatMember::new(arena_id, Some(visitor(n as u64))),
+            );
+        }
+        for n in [1, 2] {
+            router.get_mut(player(n)).unwrap().team_name = Some(TeamName::new_unsanitized("A"));
+        }
+        router
+    }
+
+    #[test]
+    fn recipients() {
+        let main = arena(RealmId::PublicDefault, 0);
+        let router = router(main);
+        let route = |n, recipient| router.route(&chat(n, main, recipient));
+
+        assert_eq!(
+            route(1, ChatRecipient::Arena),
+            [player(1), player(2), player(3)]
+        );
+        assert_eq!(
+            route(1, ChatRecipient::Broadcast),
+            [player(1), player(2), player(3), player(4)]
+        );
+        assert_eq!(
+            route(1, ChatRecipient::Player(player(4))),
+            [player(1), player(4)]
+        );
+        assert_eq!(
+            route(3, ChatRecipient::TeamOf(player(1))),
+            [player(1), player(2), player(3)]
+        );
+        assert_eq!(
+            route(1, ChatRecipient::TeamOf(player(1))),
+            [player(1), player(2)]
+        );
+        // A player without a team is a team of one.
+        assert_eq!(
+            route(1, ChatRecipient::TeamOf(player(3))),
+            [player(1), player(3)]
+        );
+        assert!(route(1, ChatRecipient::None).is_empty());
+        assert!(router.route(&PlasmaUpdateV1::Heartbeat {}).is_empty());
+
+        // Player IDs of other servers don't refer to local players.
+        let mut remote = chat(1, main, ChatRecipient::Broadcast);
+        if let PlasmaUpdateV1::Chat { chat_id, .. } = &mut remote {
+            chat_id.server_id = server(2);
+        }
+        assert_eq!(
+            router.route(&remote),
+            [player(1), player(2), player(3), player(4)]
+        );
+        for recipient in [
+            ChatRecipient::Arena,
+            ChatRecipient::Player(player(2)),
+            ChatRecipient::TeamOf(player(1)),
+        ] {
+            if let PlasmaUpdateV1::Chat { recipient: r, .. } = &mut remote {
+                *r = recipient;
+            }
+            assert!(router.route(&remote).is_empty(), "{recipient:?}");
+        }
+    }
+
+    #[test]
+    fn block_lists() {
+        let main = arena(RealmId::PublicDefault, 0);
+        let mut router = router(main);
+        router
+            .get_mut(player(2))
+            .unwrap()
+            .blocked_visitors
+            .insert(visitor(1));
+        router.get_mut(player(3)).unwrap().blocked_ips.insert(ip(1));
+
+        assert_eq!(
+            router.route(&chat(1, main, ChatRecipient::Arena)),
+            [player(1)]
+        );
+        assert_eq!(
+            router.route(&chat(1, main, ChatRecipient::TeamOf(player(1)))),
+            [player(1)]
+        );
+        // Player 4 isn't in `main`, so it didn't send this (on this server).
+        assert_eq!(
+            router.route(&chat(4, main, ChatRecipient::Arena)),
+            [player(1), player(2), player(3)]
+        );
+        let mut remote = chat(4, main, ChatRecipient::Arena);
+        if let PlasmaUpdateV1::Chat { chat_id, .. } = &mut remote {
+            chat_id.server_id = server(2);
+        }
+        assert_eq!(router.route(&remote), []);
+
+        let mut admin = chat(1, main, ChatRecipient::Arena);
+        if let PlasmaUpdateV1::Chat { admin, .. } = &mut admin {
+            *admin = true;
+        }
+        assert_eq!(router.route(&admin), [player(1), player(2), player(3)]);
+
+        router.remove(player(1));
+        assert_eq!(
+            router.route(&chat(1, main, ChatRecipient::Player(player(1)))),
+            []
+        );
+    }
+}
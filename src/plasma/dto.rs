@@ -12,7 +12,24 @@ use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Debug, Formatter};
 use std::str::FromStr;
 
+/// Why Plasma sent `AuthenticationFailed`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub enum AuthenticationFailure {
+    /// Player is banned.
+    Banned,
+    /// The realm's ACL doesn't allow the player.
+    Denied,
+    /// The session token is unknown or expired.
+    InvalidSession,
+    /// Plasma couldn't process the request. It may be retried.
+    Unavailable,
+    /// Sent by a newer Plasma.
+    #[serde(other)]
+    Unknown,
+}
+
 #[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "plasma_bitcode", derive(bitcode::Encode, bitcode::Decode))]
 pub enum ChatRecipient {
     /// Broadcast to the arena (on a the server) that sent it.
     Arena,
@@ -27,6 +44,7 @@ pub enum ChatRecipient {
 
 /// Sent in the `Heartbeat` for every player which has relevant claims, and in `Claims` update.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "plasma_bitcode", derive(bitcode::Encode, bitcode::Decode))]
 pub struct ClaimUpdateDto {
     pub arena_id: ArenaId,
     pub claims: ClaimSubset,
@@ -55,6 +73,7 @@ pub enum LogLevel {
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "plasma_bitcode", derive(bitcode::Encode, bitcode::Decode))]
 pub enum RealmAcl {
     UserBlacklist(HashSet<UserId>),
     VisitorBlacklist(HashSet<VisitorId>),
@@ -67,7 +86,20 @@ impl Default for RealmAcl {
     }
 }
 
+impl RealmAcl {
+    /// Whether a player may enter the realm. Players without a visitor ID never pass
+    /// a whitelist.
+    pub fn allows(&self, visitor_id: Option<VisitorId>, user_id: Option<UserId>) -> bool {
+        match self {
+            Self::UserBlacklist(users) => !user_id.is_some_and(|u| users.contains(&u)),
+            Self::VisitorBlacklist(visitors) => !visitor_id.is_some_and(|v| visitors.contains(&v)),
+            Self::VisitorWhitelist(visitors) => visitor_id.is_some_and(|v| visitors.contains(&v)),
+        }
+    }
+}
+
 #[derive(Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "plasma_bitcode", derive(bitcode::Encode, bitcode::Decode))]
 pub struct ServerFailureDiagnostic(ArrayString<60>);
 impl_wrapper_str!(ServerFailureDiagnostic);
 
@@ -90,6 +122,7 @@ pub struct ServerLogDto {
 }
 
 #[derive(Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "plasma_bitcode", derive(bitcode::Encode, bitcode::Decode))]
 pub enum ServerRole {
     /// Server is being deleted from virtual hosting and database.
     Deleting,
@@ -1,13 +1,17 @@
 // SPDX-FileCopyrightText: 2024 Softbear, Inc.
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use crate::{is_default, PlayerId, SceneId, VisitorId};
+use super::{ClaimUpdateDto, HeartbeatTracker, PlasmaRequestV1};
+use crate::{
+    is_default, ArenaId, ClientHash, NonZeroUnixMillis, PlayerId, RealmId, SceneId, VisitorId,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap};
-use std::fmt::Debug;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::{self, Debug, Display, Formatter};
 
 /// Sent in `ArenaHeartbeat`for each signed in player in the arena.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(feature = "plasma_bitcode", derive(bitcode::Encode, bitcode::Decode))]
 pub struct ActiveHeartbeat {
     /// TODO: this is an Option for backward compatibility.
     pub visitor_id: Option<VisitorId>,
@@ -36,3 +40,396 @@ pub struct RealmHeartbeat {
     #[serde(default, skip_serializing_if = "is_default")]
     pub scenes: BTreeMap<SceneId, ArenaHeartbeat>,
 }
+
+#[derive(Debug, Clone)]
+pub enum InvalidHeartbeat {
+    /// `player_count` would be less than the number of `actives`, or an active
+    /// player never joined.
+    ActiveNotPresent {
+        arena_id: ArenaId,
+        player_id: PlayerId,
+    },
+    /// More players than `player_count` can represent.
+    TooManyPlayers { arena_id: ArenaId },
+    /// A utilization (cpu, ram, missed ticks) wasn't between 0 and 1.
+    UtilizationOutOfRange { name: &'static str, value: f32 },
+}
+
+impl Display for InvalidHeartbeat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for InvalidHeartbeat {}
+
+/// Live state of an arena, maintained incrementally by the game server.
+#[derive(Debug, Default)]
+struct ArenaState {
+    players: HashSet<PlayerId>,
+    actives: HashMap<PlayerId, ActiveHeartbeat>,
+    settings: Option<serde_json::Value>,
+    /// Incremented whenever settings change.
+    settings_version: u64,
+    /// The latest `settings_version` that Plasma acknowledged.
+    settings_acknowledged: u64,
+    tick_duration: f32,
+    tick_duration_sum: f32,
+    tick_duration_samples: u32,
+}
+
+/// What a sent but unacknowledged heartbeat contained.
+#[derive(Debug, Default)]
+struct InFlightHeartbeat {
+    claims: Vec<ClaimUpdateDto>,
+    settings_versions: Vec<(ArenaId, u64)>,
+}
+
+/// Assembles `PlasmaRequestV1::Heartbeat` from arena state that is registered incrementally.
+///
+/// Settings are only included if they changed since the last acknowledged heartbeat, and
+/// claims are re-sent if their heartbeat is never acknowledged.
+#[derive(Debug, Default)]
+pub struct HeartbeatBuilder {
+    arenas: HashMap<ArenaId, ArenaState>,
+    claims: HashMap<(ArenaId, PlayerId), ClaimUpdateDto>,
+    client_hash: ClientHash,
+    cpu: f32,
+    ram: f32,
+    missed_ticks: f32,
+    date_certificate_expires: Option<NonZeroUnixMillis>,
+    /// Oldest first, matched to acknowledgements in order. At most
+    /// [`HeartbeatTracker::MAX_UNACKNOWLEDGED`], like the tracker's.
+    in_flight: VecDeque<InFlightHeartbeat>,
+}
+
+impl HeartbeatBuilder {
+    pub fn new(client_hash: ClientHash) -> Self {
+        Self {
+            client_hash,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_client_hash(&mut self, client_hash: ClientHash) {
+        self.client_hash = client_hash;
+    }
+
+    /// CPU and RAM utilization from 0 to 1.
+    pub fn set_utilization(&mut self, cpu: f32, ram: f32) {
+        self.cpu = cpu;
+        self.ram = ram;
+    }
+
+    /// Fraction of game ticks missed from 0 to 1.
+    pub fn set_missed_ticks(&mut self, missed_ticks: f32) {
+        self.missed_ticks = missed_ticks;
+    }
+
+    pub fn set_date_certificate_expires(&mut self, date: Option<NonZeroUnixMillis>) {
+        self.date_certificate_expires = date;
+    }
+
+    pub fn arena_opened(&mut self, arena_id: ArenaId) {
+        self.arenas.entry(arena_id).or_default();
+    }
+
+    pub fn arena_closed(&mut self, arena_id: ArenaId) {
+        self.arenas.remove(&arena_id);
+    }
+
+    pub fn player_joined(&mut self, arena_id: ArenaId, player_id: PlayerId) {
+        self.arenas
+            .entry(arena_id)
+            .or_default()
+            .players
+            .insert(player_id);
+    }
+
+    /// Includes the player in `actives` e.g. after `Player { active_heartbeat: true, .. }`.
+    pub fn player_activated(
+        &mut self,
+        arena_id: ArenaId,
+        player_id: PlayerId,
+        visitor_id: Option<VisitorId>,
+    ) {
+        self.arenas
+            .entry(arena_id)
+            .or_default()
+            .actives
+            .insert(player_id, ActiveHeartbeat { visitor_id });
+    }
+
+    /// Players in every arena.
+    pub fn player_count(&self) -> usize {
+        self.arenas.values().map(|arena| arena.players.len()).sum()
+    }
+
+    pub fn player_left(&mut self, arena_id: ArenaId, player_id: PlayerId) {
+        if let Some(arena) = self.arenas.get_mut(&arena_id) {
+            arena.players.remove(&player_id);
+            arena.actives.remove(&player_id);
+        }
+    }
+
+    pub fn settings_changed(&mut self, arena_id: ArenaId, settings: serde_json::Value) {
+        let arena = self.arenas.entry(arena_id).or_default();
+        if arena.settings.as_ref() != Some(&settings) {
+            arena.settings = Some(settings);
+            arena.settings_version += 1;
+        }
+    }
+
+    /// Samples the seconds per tick, which are averaged until the next heartbeat.
+    pub fn tick_sampled(&mut self, arena_id: ArenaId, tick_duration: f32) {
+        let arena = self.arenas.entry(arena_id).or_default();
+        arena.tick_duration_sum += tick_duration;
+        arena.tick_duration_samples += 1;
+    }
+
+    /// Queues claims to piggy back on the next heartbeat, replacing any older update
+    /// for the same player.
+    pub fn claims_dirtied(&mut self, update: ClaimUpdateDto) {
+        self.claims
+            .insert((update.arena_id, update.player_id), update);
+    }
+
+    /// Checks invariants without building.
+    pub fn validate(&self) -> Result<(), InvalidHeartbeat> {
+        for (name, value) in [
+            ("cpu", self.cpu),
+            ("ram", self.ram),
+            ("missed_ticks", self.missed_ticks),
+        ] {
+            if !(0.0..=1.0).contains(&value) {
+                return Err(InvalidHeartbeat::UtilizationOutOfRange { name, value });
+            }
+        }
+        for (arena_id, arena) in &self.arenas {
+            if arena.players.len() > u16::MAX as usize {
+                return Err(InvalidHeartbeat::TooManyPlayers {
+                    arena_id: *arena_id,
+                });
+            }
+            if let Some(player_id) = arena
+                .actives
+                .keys()
+                .find(|player_id| !arena.players.contains(player_id))
+            {
+                return Err(InvalidHeartbeat::ActiveNotPresent {
+                    arena_id: *arena_id,
+                    player_id: *player_id,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the next heartbeat, which is considered in flight until [`Self::acknowledged`].
+    pub fn build(&mut self) -> Result<PlasmaRequestV1, InvalidHeartbeat> {
+        self.validate()?;
+
+        let mut in_flight = InFlightHeartbeat::default();
+        let mut realms = BTreeMap::<RealmId, RealmHeartbeat>::new();
+        for (arena_id, arena) in &mut self.arenas {
+            if arena.tick_duration_samples > 0 {
+                arena.tick_duration = arena.tick_duration_sum / arena.tick_duration_samples as f32;
+                arena.tick_duration_sum = 0.0;
+                arena.tick_duration_samples = 0;
+            }
+            let settings = if arena.settings_version > arena.settings_acknowledged {
+                in_flight
+                    .settings_versions
+                    .push((*arena_id, arena.settings_version));
+                arena.settings.clone()
+            } else {
+                None
+            };
+            let player_count = arena.players.len() as u16;
+            debug_assert!(player_count as usize >= arena.actives.len());
+            realms.entry(arena_id.realm_id).or_default().scenes.insert(
+                arena_id.scene_id,
+                ArenaHeartbeat {
+                    actives: arena.actives.clone(),
+                    player_count,
+                    settings,
+                    tick_duration: arena.tick_duration,
+                },
+            );
+        }
+        in_flight.claims = self.claims.drain().map(|(_, update)| update).collect();
+        let claims = in_flight.claims.clone().into_boxed_slice();
+        if self.in_flight.len() >= HeartbeatTracker::MAX_UNACKNOWLEDGED {
+            // The tracker forgets it too, so it will never be matched to an acknowledgement.
+            let oldest = self.in_flight.pop_front().unwrap();
+            self.requeue(oldest);
+        }
+        self.in_flight.push_back(in_flight);
+
+        Ok(PlasmaRequestV1::Heartbeat {
+            claims,
+            client_hash: self.client_hash,
+            cpu: self.cpu,
+            ram: self.ram,
+            missed_ticks: self.missed_ticks,
+            date_certificate_expires: self.date_certificate_expires,
+            realms,
+        })
+    }
+
+    /// Plasma acknowledged the oldest heartbeat in flight.
+    pub fn acknowledged(&mut self) {
+        let Some(in_flight) = self.in_flight.pop_front() else {
+            return;
+        };
+        for (arena_id, version) in in_flight.settings_versions {
+            if let Some(arena) = self.arenas.get_mut(&arena_id) {
+                arena.settings_acknowledged = arena.settings_acknowledged.max(version);
+            }
+        }
+    }
+
+    /// Heartbeats in flight will never be acknowledged (e.g. the connection was lost),
+    /// so their claims are queued again. Their settings are re-sent regardless, since
+    /// they were never acknowledged.
+    pub fn unacknowledged(&mut self) {
+        for in_flight in std::mem::take(&mut self.in_flight) {
+            self.requeue(in_flight);
+        }
+    }
+
+    /// Connected to a Plasma that may not have seen any settings, so every arena's
+    /// settings are re-sent in the next heartbeat. Implies [`Self::unacknowledged`].
+    pub fn connected(&mut self) {
+        self.unacknowledged();
+        for arena in self.arenas.values_mut() {
+            arena.settings_acknowledged = 0;
+        }
+    }
+
+    fn requeue(&mut self, in_flight: InFlightHeartbeat) {
+        for update in in_flight.claims {
+            // Don't overwrite newer claims.
+            self.claims
+                .entry((update.arena_id, update.player_id))
+                .or_insert(update);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ArenaId, ClaimSubset, ClaimUpdateDto, HeartbeatBuilder, HeartbeatTracker, InvalidHeartbeat,
+        PlasmaRequestV1, PlayerId, RealmHeartbeat, VisitorId,
+    };
+    use std::collections::BTreeMap;
+    use std::num::{NonZeroU16, NonZeroU64};
+
+    fn realms(request: PlasmaRequestV1) -> (usize, BTreeMap<crate::RealmId, RealmHeartbeat>) {
+        let PlasmaRequestV1::Heartbeat { claims, realms, .. } = request else {
+            panic!("not a heartbeat");
+        };
+        (claims.len(), realms)
+    }
+
+    #[test]
+    fn settings_until_acknowledged() {
+        let arena_id = ArenaId::default();
+        let player_id = PlayerId(NonZeroU16::new(1).unwrap());
+        let mut builder = HeartbeatBuilder::new(0);
+        builder.player_joined(arena_id, player_id);
+        builder.player_activated(arena_id, player_id, None);
+        builder.settings_changed(arena_id, serde_json::json!({"max_players": 10}));
+        builder.tick_sampled(arena_id, 0.1);
+        builder.tick_sampled(arena_id, 0.2);
+
+        let (_, r) = realms(builder.build().unwrap());
+        let scene = &r[&arena_id.realm_id].scenes[&arena_id.scene_id];
+        assert_eq!(scene.player_count, 1);
+        assert_eq!(scene.actives.len(), 1);
+        assert!(scene.settings.is_some());
+        assert!((scene.tick_duration - 0.15).abs() < 0.001);
+
+        // Not yet acknowledged, so settings are sent again.
+        let (_, r) = realms(builder.build().unwrap());
+        assert!(r[&arena_id.realm_id].scenes[&arena_id.scene_id]
+            .settings
+            .is_some());
+
+        builder.acknowledged();
+        builder.acknowledged();
+        let (_, r) = realms(builder.build().unwrap());
+        assert!(r[&arena_id.realm_id].scenes[&arena_id.scene_id]
+            .settings
+            .is_none());
+    }
+
+    #[test]
+    fn claims_resent_when_unacknowledged() {
+        let arena_id = ArenaId::default();
+        let player_id = PlayerId(NonZeroU16::new(1).unwrap());
+        let mut builder = HeartbeatBuilder::new(0);
+        builder.claims_dirtied(ClaimUpdateDto {
+            arena_id,
+            claims: ClaimSubset::default(),
+            player_id,
+            visitor_id: VisitorId(NonZeroU64::new(1).unwrap()),
+        });
+        assert_eq!(realms(builder.build().unwrap()).0, 1);
+        assert_eq!(realms(builder.build().unwrap()).0, 0);
+        builder.unacknowledged();
+        assert_eq!(realms(builder.build().unwrap()).0, 1);
+    }
+
+    #[test]
+    fn in_flight_bounded() {
+        let arena_id = ArenaId::default();
+        let player_id = PlayerId(NonZeroU16::new(1).unwrap());
+        let mut builder = HeartbeatBuilder::new(0);
+        builder.claims_dirtied(ClaimUpdateDto {
+            arena_id,
+            claims: ClaimSubset::default(),
+            player_id,
+            visitor_id: VisitorId(NonZeroU64::new(1).unwrap()),
+        });
+        let build = |builder: &mut HeartbeatBuilder| realms(builder.build().unwrap()).0;
+        assert_eq!(build(&mut builder), 1);
+        for _ in 1..HeartbeatTracker::MAX_UNACKNOWLEDGED {
+            assert_eq!(build(&mut builder), 0);
+        }
+        // The first heartbeat is forgotten, so its claims are queued again.
+        assert_eq!(build(&mut builder), 0);
+        assert_eq!(build(&mut builder), 1);
+
+        // Settings are credited to the heartbeat that carried them.
+        builder.settings_changed(arena_id, serde_json::json!({"max_players": 10}));
+        for _ in 0..HeartbeatTracker::MAX_UNACKNOWLEDGED {
+            builder.acknowledged();
+        }
+        builder.build().unwrap();
+        builder.acknowledged();
+        let (_, r) = realms(builder.build().unwrap());
+        assert!(r[&arena_id.realm_id].scenes[&arena_id.scene_id]
+            .settings
+            .is_none());
+    }
+
+    #[test]
+    fn invariants() {
+        let arena_id = ArenaId::default();
+        let player_id = PlayerId(NonZeroU16::new(1).unwrap());
+        let mut builder = HeartbeatBuilder::new(0);
+        builder.player_activated(arena_id, player_id, None);
+        assert!(matches!(
+            builder.build(),
+            Err(InvalidHeartbeat::ActiveNotPresent { .. })
+        ));
+        builder.player_left(arena_id, player_id);
+        builder.set_utilization(1.5, 0.5);
+        assert!(matches!(
+            builder.build(),
+            Err(InvalidHeartbeat::UtilizationOutOfRange { name: "cpu", .. })
+        ));
+    }
+}
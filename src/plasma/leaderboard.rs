@@ -0,0 +1,638 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::PlasmaUpdateV1;
+use crate::{LeaderboardScoreDto, NonZeroUnixMillis, PeriodId, PlayerAlias, RealmId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Top scores of one realm and period, best first, with at most one score per alias.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Leaderboard {
+    realm_id: RealmId,
+    period_id: PeriodId,
+    capacity: usize,
+    /// Start of the current period, see [`PeriodId::start`].
+    period_start: NonZeroUnixMillis,
+    scores: Vec<LeaderboardScoreDto>,
+    /// Best score of each alias merged this period, including those that didn't make
+    /// `scores`, up to [`Self::MAX_REMEMBERED`] aliases.
+    best: HashMap<PlayerAlias, u32>,
+    /// Of `best`, so each alias counts once.
+    histogram: ScoreHistogram,
+}
+
+/// Where a score places on a leaderboard.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LeaderboardRank {
+    /// 1-based position among the top scores.
+    Exact(usize),
+    /// Estimated fraction of the period's aliases whose best score is lower, from 0 to 1.
+    Percentile(f32),
+}
+
+impl LeaderboardRank {
+    /// Estimates the rank of `alias` with its best `score` of the period, given the
+    /// `scores` and `percentiles` of a `Leaderboard` update, and the `capacity` of the
+    /// leaderboard. Returns [`None`] if the score didn't make the leaderboard and there
+    /// are no percentiles.
+    pub fn estimate(
+        scores: &[LeaderboardScoreDto],
+        percentiles: &[u32],
+        capacity: usize,
+        alias: PlayerAlias,
+        score: u32,
+    ) -> Option<Self> {
+        exact(scores, scores.len() >= capacity, alias, score)
+            .map(Self::Exact)
+            .or_else(|| {
+                (!percentiles.is_empty()).then(|| {
+                    Self::Percentile(
+                        percentiles.partition_point(|&p| p < score) as f32
+                            / (percentiles.len() + 1) as f32,
+                    )
+                })
+            })
+    }
+}
+
+/// Position of `alias`, or of `score` if it would make `scores` (which it always does
+/// unless they're `full`).
+fn exact(
+    scores: &[LeaderboardScoreDto],
+    full: bool,
+    alias: PlayerAlias,
+    score: u32,
+) -> Option<usize> {
+    if let Some(index) = scores.iter().position(|s| s.alias == alias) {
+        Some(index + 1)
+    } else if !full || !scores.last().is_some_and(|worst| score <= worst.score) {
+        Some(scores.partition_point(|s| s.score >= score) + 1)
+    } else {
+        None
+    }
+}
+
+/// Up to `radius` entries above and below `alias` (inclusive), or above and below
+/// where `score` would place if `alias` isn't among `scores`.
+pub(crate) fn near(
+    scores: &[LeaderboardScoreDto],
+    alias: PlayerAlias,
+    score: u32,
+    radius: usize,
+) -> &[LeaderboardScoreDto] {
+    let (index, len) = match scores.iter().position(|s| s.alias == alias) {
+        Some(index) => (index, 1),
+        None => (scores.partition_point(|s| s.score >= score), 0),
+    };
+    &scores[index.saturating_sub(radius)..(index + len + radius).min(scores.len())]
+}
+
+/// Counts of scores in buckets that double in width, for estimating percentiles
+/// without remembering every score.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScoreHistogram {
+    /// Bucket 0 counts zeros and bucket `i` counts `2^(i-1)..2^i`.
+    buckets: [u64; u32::BITS as usize + 1],
+}
+
+impl Default for ScoreHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: [0; u32::BITS as usize + 1],
+        }
+    }
+}
+
+impl ScoreHistogram {
+    fn bucket(score: u32) -> usize {
+        (u32::BITS - score.leading_zeros()) as usize
+    }
+
+    /// Inclusive start and exclusive end of a bucket.
+    fn bounds(bucket: usize) -> (f64, f64) {
+        if bucket == 0 {
+            (0.0, 1.0)
+        } else {
+            ((1u64 << (bucket - 1)) as f64, (1u64 << bucket) as f64)
+        }
+    }
+
+    pub fn insert(&mut self, score: u32) {
+        self.buckets[Self::bucket(score)] += 1;
+    }
+
+    /// Undoes [`Self::insert`] of `score`.
+    pub fn remove(&mut self, score: u32) {
+        let bucket = &mut self.buckets[Self::bucket(score)];
+        *bucket = bucket.saturating_sub(1);
+    }
+
+    pub fn len(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Estimated fraction of scores lower than `score`, from 0 to 1.
+    pub fn fraction_below(&self, score: u32) -> f32 {
+        let len = self.len();
+        if len == 0 {
+            return 0.0;
+        }
+        let bucket = Self::bucket(score);
+        let (start, end) = Self::bounds(bucket);
+        let below = self.buckets[..bucket].iter().sum::<u64>() as f64
+            + self.buckets[bucket] as f64 * (score as f64 - start) / (end - start);
+        (below / len as f64) as f32
+    }
+
+    /// Estimated score below which `fraction` of scores fall.
+    pub fn quantile(&self, fraction: f32) -> u32 {
+        let target = fraction.clamp(0.0, 1.0) as f64 * self.len() as f64;
+        let mut below = 0.0;
+        for (bucket, &count) in self.buckets.iter().enumerate() {
+            let count = count as f64;
+            if count > 0.0 && below + count >= target {
+                let (start, end) = Self::bounds(bucket);
+                return (start + (target - below) / count * (end - start)).min(u32::MAX as f64)
+                    as u32;
+            }
+            below += count;
+        }
+        0
+    }
+
+    /// Scores at the 1st through 99th percentiles, lowest first, or none if empty.
+    pub fn percentiles(&self) -> Box<[u32]> {
+        if self.is_empty() {
+            return Box::default();
+        }
+        (1..100).map(|p| self.quantile(p as f32 / 100.0)).collect()
+    }
+}
+
+/// Minimal change between two versions of a [`Leaderboard`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LeaderboardDiff {
+    /// New or improved scores, best first.
+    #[serde(default, skip_serializing_if = "<[_]>::is_empty")]
+    pub upserted: Box<[LeaderboardScoreDto]>,
+    /// Aliases that fell off the leaderboard.
+    #[serde(default, skip_serializing_if = "<[_]>::is_empty")]
+    pub removed: Box<[PlayerAlias]>,
+}
+
+impl LeaderboardDiff {
+    /// Diff that turns `old` into `new`.
+    pub fn between(old: &[LeaderboardScoreDto], new: &[LeaderboardScoreDto]) -> Self {
+        Self {
+            upserted: new.iter().filter(|s| !old.contains(s)).cloned().collect(),
+            removed: old
+                .iter()
+                .filter(|o| !new.iter().any(|n| n.alias == o.alias))
+                .map(|o| o.alias)
+                .collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upserted.is_empty() && self.removed.is_empty()
+    }
+
+    /// Applies the diff to scores sorted best first, such as those of the previous
+    /// `Leaderboard` update.
+    pub fn apply(&self, scores: &mut Vec<LeaderboardScoreDto>) {
+        scores.retain(|s| {
+            !self.removed.contains(&s.alias) && !self.upserted.iter().any(|u| u.alias == s.alias)
+        });
+        scores.extend(self.upserted.iter().cloned());
+        scores.sort_by(|a, b| b.cmp(a));
+    }
+}
+
+impl Leaderboard {
+    /// Most aliases whose best score is remembered, beyond which the worse half are
+    /// forgotten, since [`PeriodId::AllTime`] never rolls over.
+    pub const MAX_REMEMBERED: usize = 1 << 14;
+
+    pub fn new(
+        realm_id: RealmId,
+        period_id: PeriodId,
+        capacity: usize,
+        now: NonZeroUnixMillis,
+    ) -> Self {
+        Self {
+            realm_id,
+            period_id,
+            capacity,
+            period_start: period_id.start(now),
+            scores: Vec::new(),
+            best: HashMap::new(),
+            histogram: ScoreHistogram::default(),
+        }
+    }
+
+    pub fn realm_id(&self) -> RealmId {
+        self.realm_id
+    }
+
+    pub fn period_id(&self) -> PeriodId {
+        self.period_id
+    }
+
+    pub fn period_start(&self) -> NonZeroUnixMillis {
+        self.period_start
+    }
+
+    /// Best first.
+    pub fn scores(&self) -> &[LeaderboardScoreDto] {
+        &self.scores
+    }
+
+    /// The best score of each alias merged this period.
+    pub fn histogram(&self) -> &ScoreHistogram {
+        &self.histogram
+    }
+
+    /// Rank of `alias` with its best `score` of the period: exact if it made the
+    /// leaderboard, otherwise estimated from the histogram.
+    pub fn rank(&self, alias: PlayerAlias, score: u32) -> LeaderboardRank {
+        exact(
+            &self.scores,
+            self.scores.len() >= self.capacity,
+            alias,
+            score,
+        )
+        .map(LeaderboardRank::Exact)
+        .unwrap_or_else(|| LeaderboardRank::Percentile(self.histogram.fraction_below(score)))
+    }
+
+    /// Up to `radius` entries just above and below `alias`, including its own.
+    pub fn near(&self, alias: PlayerAlias, score: u32, radius: usize) -> &[LeaderboardScoreDto] {
+        near(&self.scores, alias, score, radius)
+    }
+
+    /// Merges a batch of scores, e.g. from `UpdateLeaderboards`, keeping the best
+    /// score of each alias. Rolls over first if a new period started.
+    pub fn merge(
+        &mut self,
+        scores: &[LeaderboardScoreDto],
+        now: NonZeroUnixMillis,
+    ) -> LeaderboardDiff {
+        let old = self.scores.clone();
+        self.roll_over(now);
+        for score in scores {
+            let previous = self.best.get(&score.alias).copied();
+            if previous.map_or(true, |best| score.score > best) {
+                if let Some(previous) = previous {
+                    self.histogram.remove(previous);
+                }
+                self.histogram.insert(score.score);
+                self.best.insert(score.alias, score.score);
+            }
+            if let Some(existing) = self.scores.iter_mut().find(|s| s.alias == score.alias) {
+                existing.score = existing.score.max(score.score);
+            } else if self.scores.len() < self.capacity
+                || self.scores.last().is_some_and(|worst| score > worst)
+            {
+                self.scores.push(score.clone());
+            } else {
+                continue;
+            }
+            self.scores.sort_by(|a, b| b.cmp(a));
+            self.scores.truncate(self.capacity);
+        }
+        if self.best.len() > Self::MAX_REMEMBERED {
+            self.forget_worst();
+        }
+        LeaderboardDiff::between(&old, &self.scores)
+    }
+
+    /// Forgets the worse half of `best`, far below `scores`. Their scores stay in the
+    /// histogram, so if they improve they count twice, which barely moves percentiles.
+    fn forget_worst(&mut self) {
+        let mut best: Vec<_> = self.best.drain().collect();
+        best.sort_unstable_by(|a, b| b.1.cmp(&a.1));
+        best.truncate(Self::MAX_REMEMBERED / 2);
+        self.best = best.into_iter().collect();
+    }
+
+    /// Clears the scores if a new period started since the last merge or roll over.
+    pub fn roll_over(&mut self, now: NonZeroUnixMillis) -> LeaderboardDiff {
+        let period_start = self.period_id.start(now);
+        if period_start <= self.period_start {
+            return LeaderboardDiff::default();
+        }
+        self.period_start = period_start;
+        self.best.clear();
+        self.histogram = ScoreHistogram::default();
+        let old = std::mem::take(&mut self.scores);
+        LeaderboardDiff::between(&old, &self.scores)
+    }
+
+    /// The full leaderboard, to send to servers.
+    pub fn update(&self) -> PlasmaUpdateV1 {
+        PlasmaUpdateV1::Leaderboard {
+            period_id: self.period_id,
+            realm_id: self.realm_id,
+            scores: self.scores.clone().into(),
+            percentiles: self.percentiles(),
+        }
+    }
+
+    /// `diff`, as returned by [`Self::merge`] or [`Self::roll_over`], to send to servers
+    /// that already have the previous version.
+    pub fn diff_update(&self, diff: LeaderboardDiff) -> PlasmaUpdateV1 {
+        PlasmaUpdateV1::LeaderboardDiff {
+            period_id: self.period_id,
+            realm_id: self.realm_id,
+            diff,
+            percentiles: self.percentiles(),
+        }
+    }
+
+    fn percentiles(&self) -> Option<Box<[u32]>> {
+        (!self.histogram.is_empty()).then(|| self.histogram.percentiles())
+    }
+}
+
+/// A [`Leaderboard`] for each [`RealmId`] and [`PeriodId`] that has scores.
+#[derive(Clone, Debug)]
+pub struct Leaderboards {
+    capacity: usize,
+    leaderboards: HashMap<(RealmId, PeriodId), Leaderboard>,
+}
+
+impl Leaderboards {
+    /// Length of leaderboards by default.
+    pub const CAPACITY: usize = 10;
+
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            leaderboards: Default::default(),
+        }
+    }
+
+    pub fn get(&self, realm_id: RealmId, period_id: PeriodId) -> Option<&Leaderboard> {
+        self.leaderboards.get(&(realm_id, period_id))
+    }
+
+    /// In a stable order, by realm and then period.
+    pub fn iter(&self) -> impl Iterator<Item = &Leaderboard> {
+        let mut leaderboards: Vec<_> = self.leaderboards.values().collect();
+        leaderboards.sort_by_key(|l| (l.realm_id, l.period_id as usize));
+        leaderboards.into_iter()
+    }
+
+    /// Merges a batch of scores for `realm_id` into every period, returning the
+    /// leaderboards that changed and how.
+    pub fn merge(
+        &mut self,
+        realm_id: RealmId,
+        scores: &[LeaderboardScoreDto],
+        now: NonZeroUnixMillis,
+    ) -> Vec<(&Leaderboard, LeaderboardDiff)> {
+        let mut diffs: Vec<_> = PeriodId::iter()
+            .filter_map(|period_id| {
+                let diff = self
+                    .leaderboards
+                    .entry((realm_id, period_id))
+                    .or_insert_with(|| Leaderboard::new(realm_id, period_id, self.capacity, now))
+                    .merge(scores, now);
+                (!diff.is_empty()).then_some((period_id, diff))
+            })
+            .collect();
+        diffs.sort_by_key(|(period_id, _)| *period_id as usize);
+        diffs
+            .into_iter()
+            .map(|(period_id, diff)| (&self.leaderboards[&(realm_id, period_id)], diff))
+            .collect()
+    }
+
+    /// Rolls over every leaderboard whose period ended, returning those that were
+    /// cleared, in a stable order.
+    pub fn roll_over(&mut self, now: NonZeroUnixMillis) -> Vec<(&Leaderboard, LeaderboardDiff)> {
+        let mut diffs: Vec<_> = self
+            .leaderboards
+            .iter_mut()
+            .filter_map(|(key, leaderboard)| {
+                let diff = leaderboard.roll_over(now);
+                (!diff.is_empty()).then_some((*key, diff))
+            })
+            .collect();
+        diffs.sort_by_key(|((realm_id, period_id), _)| (*realm_id, *period_id as usize));
+        diffs
+            .into_iter()
+            .map(|(key, diff)| (&self.leaderboards[&key], diff))
+            .collect()
+    }
+}
+
+impl Default for Leaderboards {
+    fn default() -> Self {
+        Self::new(Self::CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plasma::at;
+    use crate::{
+        Leaderboard, LeaderboardDiff, LeaderboardRank, LeaderboardScoreDto, Leaderboards,
+        NonZeroUnixMillis, PeriodId, PlasmaUpdateV1, PlayerAlias, RealmId, ScoreHistogram,
+        UnixTime,
+    };
+
+    fn score(alias: &str, score: u32) -> LeaderboardScoreDto {
+        LeaderboardScoreDto {
+            alias: PlayerAlias::new_unsanitized(alias),
+            score,
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let mut leaderboards = Leaderboards::new(3);
+        let realm_id = RealmId::PublicDefault;
+        let diffs = leaderboards.merge(
+            realm_id,
+            &[score("a", 5), score("b", 9), score("a", 7), score("c", 1)],
+            at(0),
+        );
+        assert_eq!(diffs.len(), 3);
+        assert_eq!(
+            diffs[0].0.scores(),
+            [score("b", 9), score("a", 7), score("c", 1)]
+        );
+
+        // Worse scores of the same alias, and scores below a full leaderboard, change nothing.
+        assert!(leaderboards
+            .merge(realm_id, &[score("a", 6), score("d", 0)], at(1))
+            .is_empty());
+
+        let diffs = leaderboards.merge(realm_id, &[score("d", 8), score("b", 10)], at(2));
+        let (leaderboard, diff) = &diffs[0];
+        assert_eq!(
+            leaderboard.scores(),
+            [score("b", 10), score("d", 8), score("a", 7)]
+        );
+        assert_eq!(
+            diff,
+            &LeaderboardDiff {
+                upserted: [score("b", 10), score("d", 8)].into(),
+                removed: [PlayerAlias::new_unsanitized("c")].into(),
+            }
+        );
+
+        let mut scores = vec![score("b", 9), score("a", 7), score("c", 1)];
+        diff.apply(&mut scores);
+        assert_eq!(scores, leaderboard.scores());
+    }
+
+    #[test]
+    fn roll_over() {
+        let mut leaderboards = Leaderboards::default();
+        let realm_id = RealmId::PublicDefault;
+        let tuesday = at(0);
+        leaderboards.merge(realm_id, &[score("a", 5)], tuesday);
+        let midnight = PeriodId::Daily.start(tuesday).to_i64() + 24 * 60 * 60 * 1000;
+
+        assert!(leaderboards
+            .roll_over(NonZeroUnixMillis::from_i64(midnight - 1))
+            .is_empty());
+        let wednesday = NonZeroUnixMillis::from_i64(midnight);
+        let cleared: Vec<_> = leaderboards
+            .roll_over(wednesday)
+            .into_iter()
+            .map(|(l, _)| l.period_id())
+            .collect();
+        assert_eq!(cleared, [PeriodId::Daily]);
+
+        // A merge after the week ends rolls over first.
+        let monday = NonZeroUnixMillis::from_i64(wednesday.to_i64() + 5 * 24 * 60 * 60 * 1000);
+        let diffs = leaderboards.merge(realm_id, &[score("b", 1)], monday);
+        let weekly = &diffs[2];
+        assert_eq!(weekly.0.period_id(), PeriodId::Weekly);
+        assert_eq!(weekly.0.scores(), [score("b", 1)]);
+        assert_eq!(&*weekly.1.removed, [PlayerAlias::new_unsanitized("a")]);
+        assert_eq!(
+            leaderboards
+                .get(realm_id, PeriodId::AllTime)
+                .unwrap()
+                .scores(),
+            [score("a", 5), score("b", 1)]
+        );
+    }
+
+    #[test]
+    fn all_time_memory() {
+        // All-time leaderboards never roll over, so they forget the worst aliases instead.
+        let mut leaderboard = Leaderboard::new(RealmId::PublicDefault, PeriodId::AllTime, 3, at(0));
+        let scores: Vec<_> = (0..=Leaderboard::MAX_REMEMBERED as u32)
+            .map(|i| score(&i.to_string(), i))
+            .collect();
+        leaderboard.merge(&scores, at(0));
+        assert_eq!(leaderboard.best.len(), Leaderboard::MAX_REMEMBERED / 2);
+        assert_eq!(
+            leaderboard.histogram().len(),
+            Leaderboard::MAX_REMEMBERED as u64 + 1
+        );
+        let best = Leaderboard::MAX_REMEMBERED as u32;
+        assert_eq!(leaderboard.scores()[0], score(&best.to_string(), best));
+        assert!(!leaderboard
+            .best
+            .contains_key(&PlayerAlias::new_unsanitized("0")));
+    }
+
+    #[test]
+    fn histogram() {
+        let mut histogram = ScoreHistogram::default();
+        assert!(histogram.percentiles().is_empty());
+        for score in 0..1000 {
+            histogram.insert(score);
+        }
+        assert_eq!(histogram.len(), 1000);
+        assert_eq!(histogram.fraction_below(0), 0.0);
+        assert!((histogram.fraction_below(500) - 0.5).abs() < 0.05);
+        assert_eq!(histogram.fraction_below(u32::MAX), 1.0);
+
+        let percentiles = histogram.percentiles();
+        assert_eq!(percentiles.len(), 99);
+        assert!(percentiles.windows(2).all(|w| w[0] <= w[1]));
+        assert!(percentiles[49].abs_diff(500) < 50);
+    }
+
+    #[test]
+    fn rank_and_near() {
+        let alias = PlayerAlias::new_unsanitized;
+        let mut leaderboard = Leaderboard::new(RealmId::PublicDefault, PeriodId::Daily, 3, at(0));
+        assert_eq!(leaderboard.rank(alias("a"), 0), LeaderboardRank::Exact(1));
+        // Any score places on a leaderboard that isn't full.
+        leaderboard.merge(&[score("0", 0), score("1", 1)], at(0));
+        assert_eq!(leaderboard.rank(alias("a"), 0), LeaderboardRank::Exact(3));
+        let scores: Vec<_> = (0..100).map(|i| score(&i.to_string(), i)).collect();
+        leaderboard.merge(&scores, at(0));
+        // Each alias counts once, with its best score.
+        for i in 0..100 {
+            leaderboard.merge(&[score("1", i % 50)], at(0));
+        }
+        assert_eq!(leaderboard.histogram().len(), 100);
+
+        assert_eq!(leaderboard.rank(alias("98"), 98), LeaderboardRank::Exact(2));
+        assert_eq!(
+            leaderboard.rank(alias("new"), 200),
+            LeaderboardRank::Exact(1)
+        );
+        let LeaderboardRank::Percentile(percentile) = leaderboard.rank(alias("50"), 50) else {
+            panic!();
+        };
+        assert!((percentile - 0.5).abs() < 0.1, "{percentile}");
+
+        assert_eq!(
+            leaderboard.near(alias("98"), 98, 1),
+            [score("99", 99), score("98", 98), score("97", 97)]
+        );
+        assert_eq!(
+            leaderboard.near(alias("99"), 99, 1),
+            [score("99", 99), score("98", 98)]
+        );
+        assert_eq!(
+            leaderboard.near(alias("50"), 50, 2),
+            [score("98", 98), score("97", 97)]
+        );
+        assert_eq!(leaderboard.near(alias("new"), 200, 1), [score("99", 99)]);
+
+        // Game servers estimate ranks from the update alone.
+        let PlasmaUpdateV1::Leaderboard {
+            scores,
+            percentiles: Some(percentiles),
+            ..
+        } = leaderboard.update()
+        else {
+            panic!();
+        };
+        assert_eq!(
+            LeaderboardRank::estimate(&scores, &percentiles, 3, alias("97"), 97),
+            Some(LeaderboardRank::Exact(3))
+        );
+        let Some(LeaderboardRank::Percentile(percentile)) =
+            LeaderboardRank::estimate(&scores, &percentiles, 3, alias("50"), 50)
+        else {
+            panic!();
+        };
+        assert!((percentile - 0.5).abs() < 0.1, "{percentile}");
+        assert_eq!(
+            LeaderboardRank::estimate(&scores, &[], 3, alias("50"), 50),
+            None
+        );
+
+        // Rolling over forgets the histogram too.
+        let tomorrow = NonZeroUnixMillis::from_i64(at(0).to_i64() + 24 * 60 * 60 * 1000);
+        leaderboard.roll_over(tomorrow);
+        assert!(leaderboard.histogram().is_empty());
+    }
+}
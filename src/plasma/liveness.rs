@@ -0,0 +1,187 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::{NonZeroUnixMillis, UnixTime};
+use std::collections::VecDeque;
+
+/// How the connection to Plasma appears from the game server's side.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Liveness {
+    /// Nothing sent yet, or every heartbeat acknowledged in time.
+    Healthy,
+    /// Some heartbeats have gone unacknowledged, but Plasma probably still
+    /// considers the server alive.
+    Degraded { missed: usize },
+    /// Plasma has likely considered the server dead. The server should reset
+    /// to `ServerRole::Unlisted` until it hears from Plasma again.
+    Dead,
+}
+
+impl Liveness {
+    pub fn is_healthy(self) -> bool {
+        matches!(self, Self::Healthy)
+    }
+
+    pub fn is_dead(self) -> bool {
+        matches!(self, Self::Dead)
+    }
+}
+
+/// Schedules `Heartbeat` requests and matches them to `Heartbeat {}` acknowledgements.
+///
+/// Acknowledgements don't identify which heartbeat they answer, so they are matched in
+/// the order heartbeats were sent.
+#[derive(Clone, Debug, Default)]
+pub struct HeartbeatTracker {
+    /// When the first heartbeat of this connection was sent.
+    date_first_sent: Option<NonZeroUnixMillis>,
+    /// When the most recent heartbeat was sent.
+    date_sent: Option<NonZeroUnixMillis>,
+    /// When the most recently acknowledged heartbeat was sent, i.e. the last time
+    /// Plasma is known to have heard from the server.
+    date_delivered: Option<NonZeroUnixMillis>,
+    /// Round trip time of the most recent acknowledgement, in milliseconds.
+    rtt: Option<i64>,
+    /// Heartbeats that were sent but not yet acknowledged, oldest first.
+    unacknowledged: VecDeque<NonZeroUnixMillis>,
+}
+
+impl HeartbeatTracker {
+    /// Milliseconds between heartbeats.
+    pub const INTERVAL: i64 = 60 * 1000;
+    /// Milliseconds after which Plasma considers a server dead without a heartbeat.
+    pub const DEAD: i64 = 180 * 1000;
+    /// Milliseconds after which an unacknowledged heartbeat counts as missed.
+    pub const ACKNOWLEDGEMENT_TIMEOUT: i64 = Self::INTERVAL;
+    /// Upper bound on remembered unacknowledged heartbeats. Older ones are forgotten,
+    /// as if they were never sent.
+    pub const MAX_UNACKNOWLEDGED: usize = 16;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Forgets everything, e.g. when the connection to Plasma is re-opened. The next
+    /// heartbeat will be due immediately.
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// When the next heartbeat should be sent.
+    pub fn next_due(&self, now: NonZeroUnixMillis) -> NonZeroUnixMillis {
+        self.date_sent
+            .map(|sent| NonZeroUnixMillis::from_i64(sent.to_i64() + Self::INTERVAL))
+            .unwrap_or(now)
+    }
+
+    pub fn is_due(&self, now: NonZeroUnixMillis) -> bool {
+        self.next_due(now) <= now
+    }
+
+    /// Records that a heartbeat was sent.
+    pub fn sent(&mut self, now: NonZeroUnixMillis) {
+        self.date_first_sent.get_or_insert(now);
+        self.date_sent = Some(now);
+        if self.unacknowledged.len() >= Self::MAX_UNACKNOWLEDGED {
+            self.unacknowledged.pop_front();
+        }
+        self.unacknowledged.push_back(now);
+    }
+
+    /// Records a `Heartbeat {}` acknowledgement, returning the round trip time in
+    /// milliseconds, or `None` if no heartbeat was outstanding.
+    pub fn acknowledged(&mut self, now: NonZeroUnixMillis) -> Option<i64> {
+        let sent = self.unacknowledged.pop_front()?;
+        let rtt = (now.to_i64() - sent.to_i64()).max(0);
+        self.date_delivered = Some(self.date_delivered.map_or(sent, |d| d.max(sent)));
+        self.rtt = Some(rtt);
+        Some(rtt)
+    }
+
+    /// Number of outstanding heartbeats whose acknowledgement is overdue.
+    pub fn missed(&self, now: NonZeroUnixMillis) -> usize {
+        self.unacknowledged
+            .iter()
+            .filter(|sent| now.to_i64() - sent.to_i64() >= Self::ACKNOWLEDGEMENT_TIMEOUT)
+            .count()
+    }
+
+    /// Round trip time of the most recent acknowledgement, in milliseconds.
+    pub fn rtt(&self) -> Option<i64> {
+        self.rtt
+    }
+
+    /// Estimated milliseconds since Plasma last heard from the server, or `None` if
+    /// no heartbeat has been sent.
+    pub fn staleness(&self, now: NonZeroUnixMillis) -> Option<i64> {
+        self.date_delivered
+            .or(self.date_first_sent)
+            .map(|date| (now.to_i64() - date.to_i64()).max(0))
+    }
+
+    pub fn liveness(&self, now: NonZeroUnixMillis) -> Liveness {
+        if self
+            .staleness(now)
+            .map(|staleness| staleness >= Self::DEAD)
+            .unwrap_or(false)
+        {
+            return Liveness::Dead;
+        }
+        match self.missed(now) {
+            0 => Liveness::Healthy,
+            missed => Liveness::Degraded { missed },
+        }
+    }
+
+    /// Whether the server should fall back to `ServerRole::Unlisted`.
+    pub fn should_reset_role(&self, now: NonZeroUnixMillis) -> bool {
+        self.liveness(now).is_dead()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plasma::at;
+    use crate::{HeartbeatTracker, Liveness};
+
+    #[test]
+    fn schedule() {
+        let mut tracker = HeartbeatTracker::new();
+        assert!(tracker.is_due(at(0)));
+        tracker.sent(at(0));
+        assert!(!tracker.is_due(at(59)));
+        assert!(tracker.is_due(at(60)));
+        assert_eq!(tracker.next_due(at(10)), at(60));
+    }
+
+    #[test]
+    fn acknowledgements() {
+        let mut tracker = HeartbeatTracker::new();
+        assert_eq!(tracker.acknowledged(at(0)), None);
+        tracker.sent(at(0));
+        assert_eq!(tracker.acknowledged(at(1)), Some(1000));
+        assert_eq!(tracker.liveness(at(50)), Liveness::Healthy);
+        assert_eq!(tracker.staleness(at(50)), Some(50_000));
+
+        tracker.sent(at(60));
+        tracker.sent(at(120));
+        assert_eq!(tracker.liveness(at(150)), Liveness::Degraded { missed: 1 });
+        assert_eq!(tracker.liveness(at(180)), Liveness::Dead);
+        assert!(tracker.should_reset_role(at(180)));
+
+        // Late acknowledgement of the heartbeat sent at 60s.
+        tracker.acknowledged(at(185));
+        assert_eq!(tracker.staleness(at(185)), Some(125_000));
+        assert!(!tracker.should_reset_role(at(185)));
+    }
+
+    #[test]
+    fn never_acknowledged() {
+        let mut tracker = HeartbeatTracker::new();
+        tracker.sent(at(0));
+        assert!(!tracker.should_reset_role(at(179)));
+        assert!(tracker.should_reset_role(at(180)));
+        tracker.reset();
+        assert_eq!(tracker.liveness(at(500)), Liveness::Healthy);
+    }
+}
@@ -0,0 +1,1403 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{
+    AbuseLedger, AuthenticationFailure, ChatPolicy, ChatRateLimiter, ChatRecipient, ClaimUpdateDto,
+    Leaderboard, Leaderboards, NegotiatedProtocol, PlasmaCapability, PlasmaHandshake,
+    PlasmaRequest, PlasmaRequestV1, PlasmaUpdate, PlasmaUpdateV1, PlasmaUpdateV2, RealmHeartbeat,
+    RealmUseTopology, Sanction, SanctionIndex, SceneUseTopology, ServerRole, ServerUseTopology,
+    TopologyDelta,
+};
+use crate::{
+    ArenaId, ChatId, ChatMessage, ClaimSet, GameId, LanguageId, LeaderboardScoreDto, NickName,
+    NonZeroUnixMillis, PeriodId, RealmId, RequestId, ServerId, ServerKind, SessionToken, TeamName,
+    TeamToken, UnixTime, VisitorId,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::num::NonZeroU16;
+
+/// A player that [`MockPlasma`] can authenticate.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockAccount {
+    pub visitor_id: VisitorId,
+    /// `None` for players that aren't signed in, who only receive `Claims`.
+    pub nick_name: Option<NickName>,
+    pub admin: bool,
+    pub moderator: bool,
+    pub ban: bool,
+}
+
+impl MockAccount {
+    /// A player that isn't signed in.
+    pub fn guest(visitor_id: VisitorId) -> Self {
+        Self {
+            visitor_id,
+            nick_name: None,
+            admin: false,
+            moderator: false,
+            ban: false,
+        }
+    }
+
+    /// A signed in player without privileges.
+    pub fn user(visitor_id: VisitorId, nick_name: NickName) -> Self {
+        Self {
+            nick_name: Some(nick_name),
+            ..Self::guest(visitor_id)
+        }
+    }
+}
+
+/// What [`MockPlasma`] does with a request matched by a [`MockFault`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum MockFaultAction {
+    /// Ignore the request, as if it was lost.
+    Drop,
+    /// Process the request, but delay the replies by this many milliseconds.
+    Delay(i64),
+    /// Reply with `Warning` instead of processing the request.
+    Warn(String),
+}
+
+/// Applies `action` to the next request that `matches`, from `server_id` if
+/// it is `Some`. Each fault applies once.
+#[derive(Clone, Debug)]
+pub struct MockFault {
+    pub server_id: Option<ServerId>,
+    pub matches: fn(&PlasmaRequestV1) -> bool,
+    pub action: MockFaultAction,
+}
+
+#[derive(Debug)]
+struct MockServer {
+    protocol: NegotiatedProtocol,
+    role: ServerRole,
+    /// From the last `Heartbeat`, with the last settings of each arena, for `Topology`.
+    realms: BTreeMap<RealmId, RealmHeartbeat>,
+    /// The last `Topology` sent and its sequence number, if `TopologyDelta` was
+    /// negotiated.
+    topology: Option<(u64, HashMap<ServerId, ServerUseTopology>)>,
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        Self {
+            protocol: Default::default(),
+            role: ServerRole::Public,
+            realms: Default::default(),
+            topology: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MockReservation {
+    team_token: TeamToken,
+    expires: NonZeroUnixMillis,
+}
+
+#[derive(Debug)]
+struct MockDelivery {
+    due: NonZeroUnixMillis,
+    server_id: ServerId,
+    update: PlasmaUpdateV2,
+}
+
+/// Deterministic, in-memory stand-in for Plasma, for testing game servers end to end.
+///
+/// Servers are registered by their first request. Replies are queued with the
+/// configured latency and collected with [`Self::take_updates`], in the envelope the
+/// recipient negotiated.
+#[derive(Debug)]
+pub struct MockPlasma {
+    game_id: GameId,
+    latency: i64,
+    server_latency: HashMap<ServerId, i64>,
+    servers: BTreeMap<ServerId, MockServer>,
+    accounts: HashMap<SessionToken, MockAccount>,
+    claims: HashMap<VisitorId, ClaimSet>,
+    /// Cloud servers, local servers, and realms have separate leaderboards.
+    leaderboards: BTreeMap<ServerKind, Leaderboards>,
+    team_names: HashMap<(RealmId, TeamName), MockReservation>,
+    /// Set by moderators with `ModerateChat`, and enforced on `SendChat`.
+    chat_policies: HashMap<(ServerKind, RealmId), ChatPolicy>,
+    chat_rate_limiter: ChatRateLimiter,
+    /// Escalates `ModerateAbuse` into sanctions.
+    abuse: AbuseLedger,
+    sanctions: BTreeMap<ServerKind, SanctionIndex>,
+    /// Messages of `Chat` updates, for `TranslateChat`.
+    chats: HashMap<ChatId, String>,
+    next_team_token: NonZeroU16,
+    faults: Vec<MockFault>,
+    outbox: Vec<MockDelivery>,
+    received: Vec<(ServerId, PlasmaRequestV1)>,
+}
+
+impl MockPlasma {
+    /// How long a team name is reserved if `ReserveTeamName` doesn't say.
+    pub const TEAM_NAME_EXPIRY: i64 = 60 * 60 * 1000;
+
+    pub fn new(game_id: GameId) -> Self {
+        Self {
+            game_id,
+            latency: 0,
+            server_latency: Default::default(),
+            servers: Default::default(),
+            accounts: Default::default(),
+            claims: Default::default(),
+            leaderboards: Default::default(),
+            team_names: Default::default(),
+            chat_policies: Default::default(),
+            chat_rate_limiter: Default::default(),
+            abuse: Default::default(),
+            sanctions: Default::default(),
+            chats: Default::default(),
+            next_team_token: NonZeroU16::MIN,
+            faults: Default::default(),
+            outbox: Default::default(),
+            received: Default::default(),
+        }
+    }
+
+    /// Milliseconds until replies are delivered to any server.
+    pub fn set_latency(&mut self, latency: i64) {
+        self.latency = latency;
+    }
+
+    /// Overrides the latency of deliveries to `server_id`, or clears the override.
+    pub fn set_server_latency(&mut self, server_id: ServerId, latency: Option<i64>) {
+        if let Some(latency) = latency {
+            self.server_latency.insert(server_id, latency);
+        } else {
+            self.server_latency.remove(&server_id);
+        }
+    }
+
+    /// Makes `session_token` authenticate as `account`.
+    pub fn add_account(&mut self, session_token: SessionToken, account: MockAccount) {
+        self.accounts.insert(session_token, account);
+    }
+
+    /// Changes the role of a registered server, which is sent with the next
+    /// `Heartbeat` reply.
+    pub fn set_role(&mut self, server_id: ServerId, role: ServerRole) {
+        if let Some(server) = self.servers.get_mut(&server_id) {
+            server.role = role;
+        }
+    }
+
+    /// Sanctions a visitor on servers of `kind`, telling them immediately.
+    pub fn sanction(&mut self, kind: ServerKind, sanction: Sanction, now: NonZeroUnixMillis) {
+        self.sanctions.entry(kind).or_default().insert(sanction);
+        let update = PlasmaUpdateV1::Sanctions {
+            sanctions: [sanction].into(),
+        };
+        for server_id in self.servers_of_kind(kind) {
+            self.deliver(server_id, None, update.clone(), now, 0);
+        }
+    }
+
+    pub fn script_fault(&mut self, fault: MockFault) {
+        self.faults.push(fault);
+    }
+
+    /// Processes a request from `server_id`, queueing any updates it causes.
+    pub fn receive(&mut self, server_id: ServerId, request: PlasmaRequest, now: NonZeroUnixMillis) {
+        let (request_id, request) = request.into_parts();
+        self.received.push((server_id, request.clone()));
+        self.servers.entry(server_id).or_default();
+
+        let mut delay = 0;
+        if let Some(index) = self.faults.iter().position(|fault| {
+            fault.server_id.unwrap_or(server_id) == server_id && (fault.matches)(&request)
+        }) {
+            match self.faults.remove(index).action {
+                MockFaultAction::Drop => return,
+                MockFaultAction::Delay(d) => delay = d,
+                MockFaultAction::Warn(message) => {
+                    let reply = PlasmaUpdateV1::Warning { message };
+                    self.deliver(server_id, request_id, reply, now, 0);
+                    return;
+                }
+            }
+        }
+
+        let reply = |plasma: &mut Self, update| {
+            plasma.deliver(server_id, request_id, update, now, delay);
+        };
+        let broadcast = |plasma: &mut Self, recipients: &[ServerId], update: PlasmaUpdateV1| {
+            for &recipient in recipients {
+                plasma.deliver(recipient, None, update.clone(), now, delay);
+            }
+        };
+
+        match request {
+            PlasmaRequestV1::AuthenticatePlayer {
+                arena_id,
+                arena_token,
+                player_id,
+                session_token,
+            } => {
+                let Some(account) = self.accounts.get(&session_token).cloned() else {
+                    reply(
+                        self,
+                        PlasmaUpdateV1::AuthenticationFailed {
+                            arena_id,
+                            player_id,
+                            reason: AuthenticationFailure::InvalidSession,
+                            session_token,
+                        },
+                    );
+                    return;
+                };
+                if account.nick_name.is_some() {
+                    let player = PlasmaUpdateV1::Player {
+                        active_heartbeat: true,
+                        admin: account.admin,
+                        arena_id,
+                        arena_token,
+                        ban: account.ban,
+                        moderator: account.moderator,
+                        nick_name: account.nick_name,
+                        player_id,
+                        session_token,
+                        visitor_id: account.visitor_id,
+                    };
+                    self.abuse.observe(&player);
+                    reply(self, player);
+                }
+                let mut claims = self
+                    .claims
+                    .get(&account.visitor_id)
+                    .map(|claims| claims.subset(self.game_id, arena_id.realm_id))
+                    .unwrap_or_default();
+                claims.date_synchronized = now;
+                reply(
+                    self,
+                    PlasmaUpdateV1::Claims {
+                        claims: [ClaimUpdateDto {
+                            arena_id,
+                            claims,
+                            player_id,
+                            visitor_id: account.visitor_id,
+                        }]
+                        .into(),
+                    },
+                );
+            }
+            PlasmaRequestV1::Heartbeat {
+                claims, mut realms, ..
+            } => {
+                let changed = self.merge_claims(&claims);
+                let server = self.servers.get_mut(&server_id).unwrap();
+                // Every arena is in each heartbeat, but settings only until acknowledged.
+                for (realm_id, realm) in &mut realms {
+                    for (scene_id, arena) in &mut realm.scenes {
+                        if arena.settings.is_none() {
+                            arena.settings = server
+                                .realms
+                                .get(realm_id)
+                                .and_then(|realm| realm.scenes.get(scene_id))
+                                .and_then(|arena| arena.settings.clone());
+                        }
+                    }
+                }
+                server.realms = realms;
+                let role = server.role;
+                reply(self, PlasmaUpdateV1::Heartbeat {});
+                reply(self, PlasmaUpdateV1::Role { role });
+                if let Some(topology) = self.topology_update(server_id, false) {
+                    reply(self, topology);
+                }
+                if !changed.is_empty() {
+                    reply(
+                        self,
+                        PlasmaUpdateV1::Claims {
+                            claims: changed.into(),
+                        },
+                    );
+                }
+            }
+            PlasmaRequestV1::RegisterServer { handshake, .. } => {
+                if let Some(protocol) = handshake
+                    .as_ref()
+                    .and_then(|h| PlasmaHandshake::default().negotiate(h))
+                {
+                    self.servers.get_mut(&server_id).unwrap().protocol = protocol.clone();
+                    reply(self, PlasmaUpdateV1::Handshake { protocol });
+                }
+                reply(
+                    self,
+                    PlasmaUpdateV1::Snippets {
+                        snippets: Default::default(),
+                    },
+                );
+                let leaderboards: Vec<_> = self
+                    .leaderboards
+                    .get(&server_id.kind)
+                    .into_iter()
+                    .flat_map(Leaderboards::iter)
+                    .map(Leaderboard::update)
+                    .collect();
+                for leaderboard in leaderboards {
+                    reply(self, leaderboard);
+                }
+                let mut chat_policies: Vec<_> = self
+                    .chat_policies
+                    .iter()
+                    .filter(|((kind, _), _)| *kind == server_id.kind)
+                    .map(|(&(_, realm_id), &policy)| PlasmaUpdateV1::ChatPolicy {
+                        realm_id,
+                        policy,
+                    })
+                    .collect();
+                chat_policies.sort_by_key(|update| match update {
+                    PlasmaUpdateV1::ChatPolicy { realm_id, .. } => Some(*realm_id),
+                    _ => None,
+                });
+                for chat_policy in chat_policies {
+                    reply(self, chat_policy);
+                }
+                let sanctions = self
+                    .sanctions
+                    .get(&server_id.kind)
+                    .map(|s| s.active(now))
+                    .unwrap_or_default();
+                if !sanctions.is_empty() {
+                    reply(
+                        self,
+                        PlasmaUpdateV1::Sanctions {
+                            sanctions: sanctions.into(),
+                        },
+                    );
+                }
+                if let Some(topology) = self.topology_update(server_id, true) {
+                    reply(self, topology);
+                }
+            }
+            PlasmaRequestV1::ReleaseTeamName {
+                arena_id,
+                team_name,
+                team_token,
+                ..
+            } => {
+                let key = (arena_id.realm_id, team_name);
+                if self
+                    .team_names
+                    .get(&key)
+                    .is_some_and(|r| r.team_token == team_token)
+                {
+                    self.team_names.remove(&key);
+                }
+            }
+            PlasmaRequestV1::RequestTopology {} => {
+                if let Some(topology) = self.topology_update(server_id, true) {
+                    reply(self, topology);
+                }
+            }
+            PlasmaRequestV1::ReserveTeamName {
+                arena_id,
+                expires,
+                player_id,
+                team_name,
+                team_token,
+            } => {
+                let key = (arena_id.realm_id, team_name);
+                let expires = expires.unwrap_or(NonZeroUnixMillis::from_i64(
+                    now.to_i64() + Self::TEAM_NAME_EXPIRY,
+                ));
+                let available = !self.team_names.get(&key).is_some_and(|reservation| {
+                    reservation.expires > now && Some(reservation.team_token) != team_token
+                });
+                if !available {
+                    reply(
+                        self,
+                        PlasmaUpdateV1::TeamNameUnavailable {
+                            arena_id,
+                            player_id,
+                            team_name,
+                        },
+                    );
+                } else if expires <= now {
+                    self.team_names.remove(&key);
+                } else {
+                    let team_token = team_token.unwrap_or_else(|| {
+                        let team_token = TeamToken(self.next_team_token);
+                        self.next_team_token = self
+                            .next_team_token
+                            .checked_add(1)
+                            .unwrap_or(NonZeroU16::MIN);
+                        team_token
+                    });
+                    self.team_names.insert(
+                        key,
+                        MockReservation {
+                            team_token,
+                            expires,
+                        },
+                    );
+                    reply(
+                        self,
+                        PlasmaUpdateV1::TeamName {
+                            arena_id,
+                            player_id,
+                            team_name,
+                            team_token,
+                        },
+                    );
+                }
+            }
+            PlasmaRequestV1::SendChat {
+                admin,
+                alias,
+                arena_id,
+                authentic,
+                ip_address,
+                message,
+                player_id,
+                team_name,
+                timestamp,
+                visitor_id,
+                recipient,
+            } => {
+                if self
+                    .sanctions
+                    .get(&server_id.kind)
+                    .and_then(|s| s.check_chat(visitor_id, arena_id.realm_id, now))
+                    .is_some()
+                {
+                    return;
+                }
+                let policy = self.chat_policy(server_id.kind, arena_id.realm_id);
+                let Ok(message) = policy.enforce(
+                    &mut self.chat_rate_limiter,
+                    visitor_id,
+                    ip_address,
+                    &message,
+                    now,
+                ) else {
+                    return;
+                };
+                let recipients: Vec<_> = match recipient {
+                    ChatRecipient::Arena | ChatRecipient::Player(_) | ChatRecipient::TeamOf(_) => {
+                        vec![server_id]
+                    }
+                    ChatRecipient::Broadcast => self.realm_servers(server_id, arena_id.realm_id),
+                    ChatRecipient::None => Vec::new(),
+                };
+                let chat_id = ChatId {
+                    arena_id,
+                    message_id: timestamp,
+                    server_id,
+                };
+                self.chats.insert(chat_id, message.clone());
+                let update = PlasmaUpdateV1::Chat {
+                    admin,
+                    alias,
+                    authentic,
+                    chat_id,
+                    ip_address,
+                    message: ChatMessage::Raw {
+                        message,
+                        detected_language_id: Default::default(),
+                        english_translation: None,
+                    },
+                    player_id,
+                    recipient,
+                    team_name,
+                    visitor_id,
+                };
+                self.abuse.observe(&update);
+                broadcast(self, &recipients, update);
+            }
+            PlasmaRequestV1::SendServerMessage {
+                message,
+                recipients,
+            } => {
+                let mut recipients: Vec<_> = recipients
+                    .into_iter()
+                    .filter(|r| r.kind == server_id.kind && self.servers.contains_key(r))
+                    .collect();
+                recipients.sort();
+                let update = PlasmaUpdateV1::Parley {
+                    message,
+                    sender: server_id,
+                };
+                broadcast(self, &recipients, update);
+            }
+            PlasmaRequestV1::TranslateChat {
+                chat_id,
+                language_id,
+            } => {
+                let translation = self
+                    .chats
+                    .get(&chat_id)
+                    .map(|message| Self::translate(message, language_id));
+                reply(
+                    self,
+                    PlasmaUpdateV1::ChatTranslation {
+                        chat_id,
+                        language_id,
+                        translation,
+                    },
+                );
+            }
+            PlasmaRequestV1::UnregisterServer => {
+                self.servers.remove(&server_id);
+                self.outbox.retain(|d| d.server_id != server_id);
+            }
+            PlasmaRequestV1::UpdateLeaderboards { realm_id, scores } => {
+                let changed: Vec<_> = self
+                    .leaderboards
+                    .entry(server_id.kind)
+                    .or_default()
+                    .merge(realm_id, &scores, now)
+                    .into_iter()
+                    .map(|(leaderboard, diff)| {
+                        (leaderboard.update(), leaderboard.diff_update(diff))
+                    })
+                    .collect();
+                let (diff_recipients, recipients): (Vec<_>, Vec<_>) = self
+                    .servers_of_kind(server_id.kind)
+                    .into_iter()
+                    .partition(|recipient| {
+                        self.servers[recipient]
+                            .protocol
+                            .has(PlasmaCapability::LeaderboardDiff)
+                    });
+                for (update, diff_update) in changed {
+                    broadcast(self, &recipients, update);
+                    broadcast(self, &diff_recipients, diff_update);
+                }
+            }
+            PlasmaRequestV1::ModerateChat {
+                arena_id,
+                safe_mode,
+                slow_mode,
+                visitor_id,
+                ..
+            } => {
+                let moderator = self
+                    .accounts
+                    .values()
+                    .any(|a| a.visitor_id == visitor_id && (a.moderator || a.admin));
+                if !moderator {
+                    return;
+                }
+                let realm_id = arena_id.realm_id;
+                let policy = self
+                    .chat_policies
+                    .entry((server_id.kind, realm_id))
+                    .or_default();
+                policy.moderate(safe_mode, slow_mode, now);
+                let update = PlasmaUpdateV1::ChatPolicy {
+                    realm_id,
+                    policy: *policy,
+                };
+                let recipients = self.realm_servers(server_id, realm_id);
+                broadcast(self, &recipients, update);
+            }
+            PlasmaRequestV1::ModerateAbuse {
+                chat_id,
+                visitor_id,
+                ..
+            } => {
+                if let Ok(Some(escalation)) = self.abuse.report(chat_id, visitor_id, now) {
+                    self.sanction(chat_id.server_id.kind, escalation.into(), now);
+                }
+            }
+            PlasmaRequestV1::SaveFile { .. }
+            | PlasmaRequestV1::UpdateMetrics { .. }
+            | PlasmaRequestV1::UpdateQuestSamples { .. }
+            | PlasmaRequestV1::UpdateServerLog { .. } => {}
+        }
+    }
+
+    /// Removes updates for `server_id` that are due by `now`, in the order they were
+    /// queued, or returns `None` if there are none.
+    pub fn take_updates(
+        &mut self,
+        server_id: ServerId,
+        now: NonZeroUnixMillis,
+    ) -> Option<PlasmaUpdate> {
+        let mut due = Vec::new();
+        let mut i = 0;
+        while i < self.outbox.len() {
+            let delivery = &self.outbox[i];
+            if delivery.server_id == server_id && delivery.due <= now {
+                due.push(self.outbox.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        if due.is_empty() {
+            return None;
+        }
+        // Stable, so same-due updates stay in order.
+        due.sort_by_key(|d| d.due);
+        let version = self
+            .servers
+            .get(&server_id)
+            .map(|s| s.protocol.version)
+            .unwrap_or_default();
+        Some(PlasmaUpdate::new(
+            version,
+            due.into_iter().map(|d| d.update).collect(),
+        ))
+    }
+
+    /// When the next queued update becomes due, if any.
+    pub fn next_due(&self) -> Option<NonZeroUnixMillis> {
+        self.outbox.iter().map(|d| d.due).min()
+    }
+
+    /// Every request received so far, in order, for assertions.
+    pub fn received(&self) -> &[(ServerId, PlasmaRequestV1)] {
+        &self.received
+    }
+
+    pub fn is_registered(&self, server_id: ServerId) -> bool {
+        self.servers.contains_key(&server_id)
+    }
+
+    pub fn claims(&self, visitor_id: VisitorId) -> Option<&ClaimSet> {
+        self.claims.get(&visitor_id)
+    }
+
+    pub fn leaderboard(
+        &self,
+        kind: ServerKind,
+        realm_id: RealmId,
+        period_id: PeriodId,
+    ) -> &[LeaderboardScoreDto] {
+        self.leaderboards
+            .get(&kind)
+            .and_then(|l| l.get(realm_id, period_id))
+            .map(Leaderboard::scores)
+            .unwrap_or_default()
+    }
+
+    /// The fake, deterministic translation that `TranslateChat` replies with.
+    pub fn translate(message: &str, language_id: LanguageId) -> String {
+        format!("[{}] {message}", language_id.as_str())
+    }
+
+    /// The chat policy that servers of `kind` were sent for `realm_id`.
+    pub fn chat_policy(&self, kind: ServerKind, realm_id: RealmId) -> ChatPolicy {
+        self.chat_policies
+            .get(&(kind, realm_id))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Returns the token of the team that reserved `team_name` in `arena_id`'s realm.
+    pub fn team_name(
+        &self,
+        arena_id: ArenaId,
+        team_name: TeamName,
+        now: NonZeroUnixMillis,
+    ) -> Option<TeamToken> {
+        self.team_names
+            .get(&(arena_id.realm_id, team_name))
+            .filter(|r| r.expires > now)
+            .map(|r| r.team_token)
+    }
+
+    fn deliver(
+        &mut self,
+        server_id: ServerId,
+        correlation_id: Option<RequestId>,
+        update: PlasmaUpdateV1,
+        now: NonZeroUnixMillis,
+        delay: i64,
+    ) {
+        if !self.servers.contains_key(&server_id) {
+            return;
+        }
+        let latency = self
+            .server_latency
+            .get(&server_id)
+            .copied()
+            .unwrap_or(self.latency);
+        self.outbox.push(MockDelivery {
+            due: NonZeroUnixMillis::from_i64(now.to_i64() + latency + delay),
+            server_id,
+            update: PlasmaUpdateV2 {
+                correlation_id,
+                update,
+            },
+        });
+    }
+
+    /// Merges claims from a heartbeat, returning what changed.
+    fn merge_claims(&mut self, claims: &[ClaimUpdateDto]) -> Vec<ClaimUpdateDto> {
+        claims
+            .iter()
+            .filter_map(|dto| {
+                let (changed, _) = self.claims.entry(dto.visitor_id).or_default().merge(
+                    &dto.claims,
+                    self.game_id,
+                    dto.arena_id.realm_id,
+                );
+                changed
+                    .filter(|c| !c.is_empty())
+                    .map(|claims| ClaimUpdateDto { claims, ..*dto })
+            })
+            .collect()
+    }
+
+    /// Servers of the same kind as `sender` that host `realm_id`, and `sender` itself.
+    fn realm_servers(&self, sender: ServerId, realm_id: RealmId) -> Vec<ServerId> {
+        self.servers
+            .iter()
+            .filter(|&(&id, server)| {
+                id.kind == sender.kind
+                    && (id == sender
+                        || realm_id.is_public_default()
+                        || server.realms.contains_key(&realm_id))
+            })
+            .map(|(&id, _)| id)
+            .collect()
+    }
+
+    fn servers_of_kind(&self, kind: ServerKind) -> Vec<ServerId> {
+        self.servers
+            .keys()
+            .filter(|id| id.kind == kind)
+            .copied()
+            .collect()
+    }
+
+    /// A full `Topology` for `recipient`, or the changes since the last one if it
+    /// negotiated `TopologyDelta` and `full` is false. `None` if nothing changed.
+    fn topology_update(&mut self, recipient: ServerId, full: bool) -> Option<PlasmaUpdateV1> {
+        let servers = self.topology(recipient);
+        let server = self.servers.get_mut(&recipient).unwrap();
+        if !server.protocol.has(PlasmaCapability::TopologyDelta) {
+            return Some(PlasmaUpdateV1::Topology {
+                servers,
+                sequence: 0,
+            });
+        }
+        let sequence = server.topology.as_ref().map_or(0, |(s, _)| *s) + 1;
+        let update = match &server.topology {
+            Some((_, old)) if !full => {
+                let delta = TopologyDelta::between(old, &servers, sequence);
+                if delta.is_empty() {
+                    return None;
+                }
+                PlasmaUpdateV1::TopologyDelta { delta }
+            }
+            _ => PlasmaUpdateV1::Topology {
+                servers: servers.clone(),
+                sequence,
+            },
+        };
+        server.topology = Some((sequence, servers));
+        Some(update)
+    }
+
+    /// Public servers of the same kind as `recipient`, and `recipient` itself.
+    fn topology(&self, recipient: ServerId) -> HashMap<ServerId, ServerUseTopology> {
+        let realm = |heartbeat: &RealmHeartbeat| RealmUseTopology {
+            acl: Default::default(),
+            scenes: heartbeat
+                .scenes
+                .iter()
+                .map(|(&scene_id, arena)| {
+                    (
+                        scene_id,
+                        SceneUseTopology {
+                            player_count: arena.player_count,
+                            settings: arena.settings.clone(),
+                        },
+                    )
+                })
+                .collect(),
+        };
+        self.servers
+            .iter()
+            .filter(|&(&id, server)| {
+                id.kind == recipient.kind
+                    && (id == recipient || id.kind.is_local() || server.role.is_public())
+            })
+            .map(|(&id, server)| {
+                (
+                    id,
+                    ServerUseTopology {
+                        datacenter: String::from("mock"),
+                        default_realm: server.realms.get(&RealmId::PublicDefault).map(realm),
+                        other_realms: server
+                            .realms
+                            .iter()
+                            .filter(|(realm_id, _)| !realm_id.is_public_default())
+                            .map(|(&realm_id, heartbeat)| (realm_id, realm(heartbeat)))
+                            .collect(),
+                        region_id: Default::default(),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plasma::at;
+    use crate::{
+        ArenaId, ArenaToken, AuthenticationFailure, ChatId, ChatMessage, ChatRecipient, GameId,
+        HeartbeatBuilder, LanguageId, LeaderboardScoreDto, MockAccount, MockFault, MockFaultAction,
+        MockPlasma, NickName, NonZeroUnixMillis, PeriodId, PlasmaHandshake, PlasmaRequest,
+        PlasmaRequestV1, PlasmaSession, PlasmaSessionEvent, PlasmaUpdate, PlasmaUpdateV1,
+        PlayerAlias, PlayerId, ProtocolVersion, RealmId, SanctionKind, ServerId, ServerKind,
+        SessionToken, TeamName, UnixTime, VisitorId,
+    };
+    use std::num::{NonZeroU16, NonZeroU32, NonZeroU64};
+    use std::str::FromStr;
+
+    fn server(s: &str) -> ServerId {
+        ServerId::from_str(s).unwrap()
+    }
+
+    fn player(n: u16) -> PlayerId {
+        PlayerId(NonZeroU16::new(n).unwrap())
+    }
+
+    fn send(plasma: &mut MockPlasma, server_id: ServerId, request: PlasmaRequestV1) {
+        plasma.receive(server_id, PlasmaRequest::V1(request), at(0));
+    }
+
+    fn updates(plasma: &mut MockPlasma, server_id: ServerId) -> Vec<PlasmaUpdateV1> {
+        plasma
+            .take_updates(server_id, NonZeroUnixMillis::MAX)
+            .map(|u| u.into_v1().into_vec())
+            .unwrap_or_default()
+    }
+
+    fn chat(recipient: ChatRecipient) -> PlasmaRequestV1 {
+        PlasmaRequestV1::SendChat {
+            admin: false,
+            alias: PlayerAlias::new_unsanitized("Guest"),
+            arena_id: ArenaId::default(),
+            authentic: false,
+            ip_address: [127, 0, 0, 1].into(),
+            message: String::from("hello"),
+            player_id: Some(player(1)),
+            team_name: None,
+            timestamp: at(0),
+            visitor_id: None,
+            recipient,
+        }
+    }
+
+    /// Exchanges messages until neither side has anything more to say.
+    fn pump(
+        plasma: &mut MockPlasma,
+        session: &mut PlasmaSession,
+        server_id: ServerId,
+        now: NonZeroUnixMillis,
+    ) -> Vec<PlasmaSessionEvent> {
+        let mut events = Vec::new();
+        loop {
+            let requests = session.take_versioned_requests(now);
+            for request in &requests {
+                plasma.receive(server_id, request.clone(), now);
+            }
+            let update = plasma.take_updates(server_id, now);
+            if requests.is_empty() && update.is_none() {
+                break;
+            }
+            if let Some(update) = update {
+                events.extend(session.receive_versioned(update, now));
+            }
+        }
+        events.extend(session.poll(now));
+        events
+    }
+
+    #[test]
+    fn session_end_to_end() {
+        let server_id = server("Cloud/1");
+        let mut plasma = MockPlasma::new(GameId::from_str("Mk48").unwrap());
+        let user = SessionToken(NonZeroU64::new(1).unwrap());
+        let guest = SessionToken(NonZeroU64::new(2).unwrap());
+        let stranger = SessionToken(NonZeroU64::new(3).unwrap());
+        let visitor_id = VisitorId(NonZeroU64::new(10).unwrap());
+        plasma.add_account(user, MockAccount::user(visitor_id, NickName::new("Nick")));
+        plasma.add_account(guest, MockAccount::guest(visitor_id));
+
+        let mut session = PlasmaSession::new(at(0), 0);
+        session.connected(at(0));
+        session.heartbeat(at(0)).unwrap();
+        pump(&mut plasma, &mut session, server_id, at(0));
+        assert_eq!(session.protocol().version, ProtocolVersion::V2);
+        assert!(session.role().is_public());
+        assert!(session.topology().contains_key(&server_id));
+
+        let arena_token = ArenaToken(NonZeroU32::new(5).unwrap());
+        for (n, session_token) in [user, guest, stranger].into_iter().enumerate() {
+            let player_id = player(n as u16 + 1);
+            session.authenticate_player(
+                ArenaId::default(),
+                arena_token,
+                player_id,
+                session_token,
+                at(1),
+            );
+        }
+        let events = pump(&mut plasma, &mut session, server_id, at(1));
+        let outcome = |player_id| {
+            events.iter().find_map(|event| match event {
+                PlasmaSessionEvent::Authenticated {
+                    player_id: p,
+                    update,
+                    ..
+                } if *p == player_id => Some(Ok(update.clone())),
+                PlasmaSessionEvent::AuthenticationFailed {
+                    player_id: p,
+                    reason,
+                    ..
+                } if *p == player_id => Some(Err(*reason)),
+                _ => None,
+            })
+        };
+        assert!(matches!(
+            outcome(player(1)),
+            Some(Ok(PlasmaUpdateV1::Player {
+                nick_name: Some(_),
+                ..
+            }))
+        ));
+        assert!(matches!(
+            outcome(player(2)),
+            Some(Ok(PlasmaUpdateV1::Claims { .. }))
+        ));
+        assert!(matches!(
+            outcome(player(3)),
+            Some(Err(AuthenticationFailure::InvalidSession))
+        ));
+        assert!(session.pending_authentications().is_empty());
+    }
+
+    #[test]
+    fn chat_fan_out_and_parley() {
+        let (cloud_1, cloud_2, local) = (server("Cloud/1"), server("Cloud/2"), server("Local/1"));
+        let mut plasma = MockPlasma::new(GameId::from_str("Mk48").unwrap());
+        for server_id in [cloud_1, cloud_2, local] {
+            send(&mut plasma, server_id, PlasmaRequestV1::RequestTopology {});
+            updates(&mut plasma, server_id);
+        }
+
+        let chats = |plasma: &mut MockPlasma| {
+            [cloud_1, cloud_2, local].map(|server_id| {
+                updates(plasma, server_id)
+                    .into_iter()
+                    .filter(|u| matches!(u, PlasmaUpdateV1::Chat { .. }))
+                    .count()
+            })
+        };
+        send(&mut plasma, cloud_1, chat(ChatRecipient::Broadcast));
+        assert_eq!(chats(&mut plasma), [1, 1, 0]);
+        for recipient in [
+            ChatRecipient::Arena,
+            ChatRecipient::Player(player(3)),
+            ChatRecipient::TeamOf(player(3)),
+        ] {
+            send(&mut plasma, cloud_1, chat(recipient));
+            assert_eq!(chats(&mut plasma), [1, 0, 0], "{recipient:?}");
+        }
+        send(&mut plasma, cloud_1, chat(ChatRecipient::None));
+        assert_eq!(chats(&mut plasma), [0, 0, 0]);
+
+        send(
+            &mut plasma,
+            cloud_1,
+            PlasmaRequestV1::SendServerMessage {
+                message: serde_json::json!("ping"),
+                recipients: [cloud_2, local].into(),
+            },
+        );
+        assert!(matches!(
+            updates(&mut plasma, cloud_2).as_slice(),
+            [PlasmaUpdateV1::Parley { sender, .. }] if *sender == cloud_1
+        ));
+        assert!(updates(&mut plasma, local).is_empty());
+    }
+
+    #[test]
+    fn chat_policy() {
+        let (cloud_1, cloud_2, local) = (server("Cloud/1"), server("Cloud/2"), server("Local/1"));
+        let mut plasma = MockPlasma::new(GameId::from_str("Mk48").unwrap());
+        for server_id in [cloud_1, cloud_2, local] {
+            send(&mut plasma, server_id, PlasmaRequestV1::RequestTopology {});
+            updates(&mut plasma, server_id);
+        }
+        let moderator = VisitorId(NonZeroU64::new(7).unwrap());
+        let moderate = |visitor_id| PlasmaRequestV1::ModerateChat {
+            alias: PlayerAlias::new_unsanitized("Mod"),
+            arena_id: ArenaId::default(),
+            safe_mode: None,
+            slow_mode: Some(5),
+            visitor_id,
+        };
+
+        // Only moderators can moderate.
+        send(&mut plasma, cloud_1, moderate(moderator));
+        assert!(updates(&mut plasma, cloud_1).is_empty());
+
+        plasma.add_account(
+            SessionToken(NonZeroU64::new(7).unwrap()),
+            MockAccount {
+                moderator: true,
+                ..MockAccount::user(moderator, NickName::new("Mod"))
+            },
+        );
+        send(&mut plasma, cloud_1, moderate(moderator));
+        let policy = plasma.chat_policy(ServerKind::Cloud, RealmId::PublicDefault);
+        assert!(policy.is_slow_mode(at(0)));
+        for server_id in [cloud_1, cloud_2] {
+            assert!(matches!(
+                updates(&mut plasma, server_id).as_slice(),
+                [PlasmaUpdateV1::ChatPolicy { policy: p, .. }] if *p == policy
+            ));
+        }
+        assert!(updates(&mut plasma, local).is_empty());
+
+        // Slow mode drops a second chat from the same sender.
+        send(&mut plasma, cloud_1, chat(ChatRecipient::Arena));
+        send(&mut plasma, cloud_1, chat(ChatRecipient::Arena));
+        assert_eq!(updates(&mut plasma, cloud_1).len(), 1);
+
+        // Servers that register later are told.
+        send(
+            &mut plasma,
+            server("Cloud/3"),
+            PlasmaRequestV1::RegisterServer {
+                date_started: None,
+                handshake: None,
+            },
+        );
+        assert!(updates(&mut plasma, server("Cloud/3"))
+            .iter()
+            .any(|u| matches!(u, PlasmaUpdateV1::ChatPolicy { .. })));
+    }
+
+    #[test]
+    fn translate_chat() {
+        let server_id = server("Cloud/1");
+        let mut plasma = MockPlasma::new(GameId::from_str("Mk48").unwrap());
+        send(&mut plasma, server_id, chat(ChatRecipient::Arena));
+        let [PlasmaUpdateV1::Chat {
+            chat_id,
+            mut message,
+            ..
+        }] = <[_; 1]>::try_from(updates(&mut plasma, server_id)).unwrap()
+        else {
+            panic!("expected chat");
+        };
+
+        let (en, es) = (LanguageId::default(), LanguageId::new("es"));
+        let rendered = message.render(en).unwrap();
+        assert_eq!(rendered.text, "hello");
+        assert!(!rendered.translated && !rendered.untranslated);
+        let rendered = message.render(es).unwrap();
+        assert_eq!(rendered.text, "hello");
+        assert!(rendered.untranslated);
+
+        // English translations keep the message one that older clients understand.
+        message.insert_translation(en, String::from("hi"));
+        assert!(matches!(
+            &message,
+            ChatMessage::Raw { english_translation: Some(t), .. } if t == "hi"
+        ));
+        assert_eq!(message.translation(en), Some("hi"));
+
+        let translate = |chat_id| PlasmaRequestV1::TranslateChat {
+            chat_id,
+            language_id: es,
+        };
+        send(&mut plasma, server_id, translate(chat_id));
+        let [PlasmaUpdateV1::ChatTranslation {
+            language_id,
+            translation: Some(translation),
+            ..
+        }] = <[_; 1]>::try_from(updates(&mut plasma, server_id)).unwrap()
+        else {
+            panic!("expected translation");
+        };
+        assert_eq!(translation, MockPlasma::translate("hello", es));
+        message.insert_translation(language_id, translation.clone());
+        let rendered = message.render(es).unwrap();
+        assert_eq!(rendered.text, translation);
+        assert!(rendered.translated && rendered.language_id == es);
+        assert!(matches!(message, ChatMessage::Translated { .. }));
+        assert_eq!(message.translation(en), Some("hi"));
+
+        let unknown = ChatId {
+            message_id: at(1),
+            ..chat_id
+        };
+        send(&mut plasma, server_id, translate(unknown));
+        assert!(matches!(
+            updates(&mut plasma, server_id).as_slice(),
+            [PlasmaUpdateV1::ChatTranslation {
+                translation: None,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn abuse_reports_and_sanctions() {
+        let (cloud_1, cloud_2) = (server("Cloud/1"), server("Cloud/2"));
+        let mut plasma = MockPlasma::new(GameId::from_str("Mk48").unwrap());
+        for server_id in [cloud_1, cloud_2] {
+            send(&mut plasma, server_id, PlasmaRequestV1::RequestTopology {});
+            updates(&mut plasma, server_id);
+        }
+        let visitor = |n| VisitorId(NonZeroU64::new(n).unwrap());
+        let offensive = || {
+            let mut request = chat(ChatRecipient::Arena);
+            if let PlasmaRequestV1::SendChat { visitor_id, .. } = &mut request {
+                *visitor_id = Some(visitor(1));
+            }
+            request
+        };
+        send(&mut plasma, cloud_1, offensive());
+        let chat_id = match updates(&mut plasma, cloud_1).as_slice() {
+            [PlasmaUpdateV1::Chat { chat_id, .. }] => *chat_id,
+            updates => panic!("{updates:?}"),
+        };
+
+        for reporter in 2..5 {
+            send(
+                &mut plasma,
+                cloud_1,
+                PlasmaRequestV1::ModerateAbuse {
+                    alias: PlayerAlias::default(),
+                    chat_id,
+                    visitor_id: visitor(reporter),
+                },
+            );
+        }
+        let sanctions = updates(&mut plasma, cloud_2);
+        let [PlasmaUpdateV1::Sanctions { sanctions: muted }] = sanctions.as_slice() else {
+            panic!("{sanctions:?}");
+        };
+        assert_eq!(muted[0].kind, SanctionKind::Mute);
+        assert_eq!(muted[0].visitor_id, visitor(1));
+
+        let mut session = PlasmaSession::new(at(0), Default::default());
+        session.receive(sanctions[0].clone(), at(0));
+        assert!(session
+            .sanctions()
+            .check_chat(Some(visitor(1)), RealmId::PublicDefault, at(0))
+            .is_some());
+
+        // Chats from the muted visitor are dropped.
+        updates(&mut plasma, cloud_1);
+        send(&mut plasma, cloud_1, offensive());
+        assert!(updates(&mut plasma, cloud_1).is_empty());
+    }
+
+    #[test]
+    fn leaderboards_and_team_names() {
+        let (cloud_1, cloud_2, local) = (server("Cloud/1"), server("Cloud/2"), server("Local/1"));
+        let mut plasma = MockPlasma::new(GameId::from_str("Mk48").unwrap());
+        for server_id in [cloud_1, cloud_2, local] {
+            send(&mut plasma, server_id, PlasmaRequestV1::RequestTopology {});
+            updates(&mut plasma, server_id);
+        }
+        // Only `cloud_2` can apply diffs.
+        send(
+            &mut plasma,
+            cloud_2,
+            PlasmaRequestV1::RegisterServer {
+                date_started: None,
+                handshake: Some(PlasmaHandshake::default()),
+            },
+        );
+        updates(&mut plasma, cloud_2);
+
+        let score = |alias: &str, score| LeaderboardScoreDto {
+            alias: PlayerAlias::new_unsanitized(alias),
+            score,
+        };
+        let update = |scores: Vec<LeaderboardScoreDto>| PlasmaRequestV1::UpdateLeaderboards {
+            realm_id: RealmId::PublicDefault,
+            scores: scores.into(),
+        };
+        send(
+            &mut plasma,
+            cloud_1,
+            update(vec![score("a", 5), score("b", 9)]),
+        );
+        send(
+            &mut plasma,
+            cloud_2,
+            update(vec![score("a", 7), score("b", 1)]),
+        );
+        let periods: Vec<_> = updates(&mut plasma, cloud_1)
+            .into_iter()
+            .filter_map(|u| match u {
+                PlasmaUpdateV1::Leaderboard {
+                    period_id, scores, ..
+                } => Some((period_id, scores)),
+                _ => None,
+            })
+            .collect();
+        // One per period for each change.
+        assert_eq!(periods.len(), 2 * 3);
+        assert_eq!(periods[3].0, PeriodId::AllTime);
+        assert_eq!(*periods[3].1, [score("b", 9), score("a", 7)]);
+        assert!(updates(&mut plasma, local).is_empty());
+        assert_eq!(
+            plasma.leaderboard(ServerKind::Cloud, RealmId::PublicDefault, PeriodId::Daily),
+            [score("b", 9), score("a", 7)]
+        );
+
+        let diffs = updates(&mut plasma, cloud_2);
+        assert_eq!(diffs.len(), 2 * 3);
+        let mut session = PlasmaSession::new(at(0), Default::default());
+        for diff in diffs {
+            assert!(matches!(diff, PlasmaUpdateV1::LeaderboardDiff { .. }));
+            session.receive(diff, at(0));
+        }
+        assert_eq!(
+            session.leaderboard(RealmId::PublicDefault, PeriodId::AllTime),
+            [score("b", 9), score("a", 7)]
+        );
+
+        // Unchanged leaderboards aren't sent.
+        send(&mut plasma, cloud_1, update(vec![score("a", 6)]));
+        assert!(updates(&mut plasma, cloud_2).is_empty());
+
+        let team_name = TeamName::new_unsanitized("ABC");
+        let reserve = |player_id, team_token| PlasmaRequestV1::ReserveTeamName {
+            arena_id: ArenaId::default(),
+            expires: None,
+            player_id,
+            team_name,
+            team_token,
+        };
+        send(&mut plasma, cloud_1, reserve(player(1), None));
+        let [PlasmaUpdateV1::TeamName { team_token, .. }] = updates(&mut plasma, cloud_1)[..]
+        else {
+            panic!();
+        };
+        send(&mut plasma, cloud_2, reserve(player(3), None));
+        assert!(matches!(
+            updates(&mut plasma, cloud_2)[..],
+            [PlasmaUpdateV1::TeamNameUnavailable { .. }]
+        ));
+        // Renewal with the token works.
+        send(&mut plasma, cloud_1, reserve(player(1), Some(team_token)));
+        assert!(matches!(
+            updates(&mut plasma, cloud_1)[..],
+            [PlasmaUpdateV1::TeamName { .. }]
+        ));
+        send(
+            &mut plasma,
+            cloud_1,
+            PlasmaRequestV1::ReleaseTeamName {
+                arena_id: ArenaId::default(),
+                player_id: player(1),
+                team_name,
+                team_token,
+            },
+        );
+        assert!(plasma
+            .team_name(ArenaId::default(), team_name, at(0))
+            .is_none());
+    }
+
+    #[test]
+    fn faults_and_latency() {
+        let server_id = server("Cloud/1");
+        let mut plasma = MockPlasma::new(GameId::from_str("Mk48").unwrap());
+        plasma.set_latency(100);
+        plasma.script_fault(MockFault {
+            server_id: None,
+            matches: |r| matches!(r, PlasmaRequestV1::RequestTopology {}),
+            action: MockFaultAction::Drop,
+        });
+        plasma.script_fault(MockFault {
+            server_id: Some(server_id),
+            matches: |r| matches!(r, PlasmaRequestV1::RequestTopology {}),
+            action: MockFaultAction::Delay(1000),
+        });
+
+        let request = PlasmaRequest::V1(PlasmaRequestV1::RequestTopology {});
+        plasma.receive(server_id, request.clone(), at(0));
+        assert!(plasma.next_due().is_none());
+
+        plasma.receive(server_id, request.clone(), at(0));
+        assert_eq!(plasma.next_due().unwrap().to_i64(), at(0).to_i64() + 1100);
+        assert!(plasma.take_updates(server_id, at(1)).is_none());
+        assert!(matches!(
+            plasma.take_updates(server_id, at(2)),
+            Some(PlasmaUpdate::V1(updates)) if matches!(updates[..], [PlasmaUpdateV1::Topology { .. }])
+        ));
+
+        // Faults are used up.
+        plasma.receive(server_id, request, at(2));
+        assert_eq!(plasma.next_due().unwrap().to_i64(), at(2).to_i64() + 100);
+        assert_eq!(plasma.received().len(), 3);
+    }
+
+    #[test]
+    fn topology_deltas_keep_settings() {
+        let server_id = server("Cloud/1");
+        let arena_id = ArenaId::default();
+        let mut plasma = MockPlasma::new(GameId::from_str("Mk48").unwrap());
+        let topology = |updates: Vec<PlasmaUpdateV1>| {
+            updates.into_iter().find(|update| {
+                matches!(
+                    update,
+                    PlasmaUpdateV1::Topology { .. } | PlasmaUpdateV1::TopologyDelta { .. }
+                )
+            })
+        };
+        send(
+            &mut plasma,
+            server_id,
+            PlasmaRequestV1::RegisterServer {
+                date_started: None,
+                handshake: Some(PlasmaHandshake::default()),
+            },
+        );
+        assert!(matches!(
+            topology(updates(&mut plasma, server_id)),
+            Some(PlasmaUpdateV1::Topology { sequence: 1, .. })
+        ));
+
+        let settings = serde_json::json!({"max_players": 10});
+        let mut builder = HeartbeatBuilder::new(0);
+        builder.player_joined(arena_id, player(1));
+        builder.settings_changed(arena_id, settings.clone());
+        send(&mut plasma, server_id, builder.build().unwrap());
+        builder.acknowledged();
+        let Some(PlasmaUpdateV1::TopologyDelta { delta }) =
+            topology(updates(&mut plasma, server_id))
+        else {
+            panic!("no delta");
+        };
+        assert_eq!(delta.sequence, 2);
+
+        // Settings were acknowledged, so they're left out, but nothing changed.
+        send(&mut plasma, server_id, builder.build().unwrap());
+        assert!(topology(updates(&mut plasma, server_id)).is_none());
+
+        send(&mut plasma, server_id, PlasmaRequestV1::RequestTopology {});
+        let Some(PlasmaUpdateV1::Topology { servers, sequence }) =
+            topology(updates(&mut plasma, server_id))
+        else {
+            panic!("no topology");
+        };
+        assert_eq!(sequence, 3);
+        assert_eq!(
+            servers[&server_id].arena(arena_id).unwrap().settings,
+            Some(settings)
+        );
+    }
+}
@@ -1,18 +1,67 @@
 // SPDX-FileCopyrightText: 2024 Softbear, Inc.
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
+mod abuse;
+#[cfg(feature = "plasma_bitcode")]
+mod binary;
+mod chat;
 mod dto;
 mod heartbeat;
+mod leaderboard;
+mod liveness;
+#[cfg(feature = "plasma_mock")]
+mod mock;
+mod pending;
+mod policy;
 mod request;
+mod role;
+mod router;
+mod sanction;
+mod session;
 mod topology;
+mod translations;
 mod update;
-
+mod version;
+pub use abuse::{
+    AbuseEscalation, AbuseLedger, AbuseReport, AbuseSummary, AbuseThresholds, InvalidAbuseReport,
+};
+#[cfg(feature = "plasma_bitcode")]
+pub use binary::{FrameEncoding, InvalidFrame};
+pub use chat::{ChatMember, ChatRouter};
 pub use dto::{
-    ChatRecipient, ClaimUpdateDto, DomainDto, LogLevel, RealmAcl, ServerFailureDiagnostic,
-    ServerLogDto, ServerRole, Snippet, SnippetCriteria, TranslationsDto, TranslationsFile,
-    WebsocketConnectQuery,
+    AuthenticationFailure, ChatRecipient, ClaimUpdateDto, DomainDto, LogLevel, RealmAcl,
+    ServerFailureDiagnostic, ServerLogDto, ServerRole, Snippet, SnippetCriteria, TranslationsDto,
+    TranslationsFile, WebsocketConnectQuery,
+};
+pub use heartbeat::{
+    ActiveHeartbeat, ArenaHeartbeat, HeartbeatBuilder, InvalidHeartbeat, RealmHeartbeat,
+};
+pub use leaderboard::{
+    Leaderboard, LeaderboardDiff, LeaderboardRank, Leaderboards, ScoreHistogram,
 };
-pub use heartbeat::{ActiveHeartbeat, ArenaHeartbeat, RealmHeartbeat};
-pub use request::{PlasmaDeveloper, PlasmaDeveloperV1, PlasmaRequest, PlasmaRequestV1};
-pub use topology::{RealmUseTopology, SceneUseTopology, ServerUseTopology};
-pub use update::{PlasmaUpdate, PlasmaUpdateV1};
+pub use liveness::{HeartbeatTracker, Liveness};
+#[cfg(feature = "plasma_mock")]
+pub use mock::{MockAccount, MockFault, MockFaultAction, MockPlasma};
+pub use pending::{PendingRequest, PendingRequests};
+pub use policy::{ChatPolicy, ChatRateLimiter, ChatRejection};
+pub use request::{
+    PlasmaDeveloper, PlasmaDeveloperV1, PlasmaRequest, PlasmaRequestV1, PlasmaRequestV2,
+};
+pub use role::{InvalidRoleTransition, RoleSideEffect, RoleTransition, RoleTransitionContext};
+pub use router::{PlayerRouter, Route, RouteCandidate, RouteError, RouteExplanation, RouteVerdict};
+pub use sanction::{Sanction, SanctionIndex, SanctionKind, SanctionReason};
+pub use session::{PendingAuthentication, PendingTeamName, PlasmaSession, PlasmaSessionEvent};
+pub use topology::{
+    RealmUseTopology, RealmUseTopologyDelta, SceneUseTopology, SceneUseTopologyDelta,
+    ServerUseTopology, ServerUseTopologyDelta, TopologyDelta, TopologyGap,
+};
+pub use translations::{PluralCategory, TranslationCoverage, Translator};
+pub use update::{PlasmaUpdate, PlasmaUpdateV1, PlasmaUpdateV2};
+pub use version::{NegotiatedProtocol, PlasmaCapability, PlasmaHandshake, ProtocolVersion};
+
+/// `seconds` after a fixed time in late 2023, for tests.
+#[cfg(test)]
+pub(crate) fn at(seconds: i64) -> crate::NonZeroUnixMillis {
+    use crate::UnixTime;
+    crate::NonZeroUnixMillis::from_i64(1_700_000_000_000 + seconds * 1000)
+}
@@ -0,0 +1,229 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{PlasmaRequestV1, PlasmaUpdateV1};
+use crate::{NonZeroUnixMillis, RequestId, UnixTime};
+use std::collections::HashMap;
+
+/// A request awaiting a correlated update.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingRequest<T> {
+    pub context: T,
+    pub date_sent: NonZeroUnixMillis,
+    /// Milliseconds after `date_sent` to give up.
+    pub timeout: i64,
+}
+
+impl<T> PendingRequest<T> {
+    pub fn is_expired(&self, now: NonZeroUnixMillis) -> bool {
+        now.to_i64() - self.date_sent.to_i64() >= self.timeout
+    }
+}
+
+/// Requests awaiting a reply, keyed by [`RequestId`], so that replies can be matched
+/// via `correlation_id` and missing replies noticed.
+#[derive(Clone, Debug)]
+pub struct PendingRequests<T> {
+    /// With the order they were inserted in, since [`RequestId`]s wrap around.
+    pending: HashMap<RequestId, (u64, PendingRequest<T>)>,
+    next_order: u64,
+}
+
+impl<T> Default for PendingRequests<T> {
+    fn default() -> Self {
+        Self {
+            pending: Default::default(),
+            next_order: 0,
+        }
+    }
+}
+
+impl<T> PendingRequests<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tracks a sent request, replacing any with the same ID.
+    pub fn insert(
+        &mut self,
+        request_id: RequestId,
+        context: T,
+        now: NonZeroUnixMillis,
+        timeout: i64,
+    ) {
+        let order = self.next_order;
+        self.next_order += 1;
+        self.pending.insert(
+            request_id,
+            (
+                order,
+                PendingRequest {
+                    context,
+                    date_sent: now,
+                    timeout,
+                },
+            ),
+        );
+    }
+
+    /// Stops tracking the request answered by an update with `correlation_id`.
+    pub fn resolve(&mut self, correlation_id: RequestId) -> Option<PendingRequest<T>> {
+        self.pending
+            .remove(&correlation_id)
+            .map(|(_, pending)| pending)
+    }
+
+    /// Removes and returns requests that timed out, oldest first.
+    pub fn expire(&mut self, now: NonZeroUnixMillis) -> Vec<(RequestId, PendingRequest<T>)> {
+        let expired: Vec<_> = self
+            .pending
+            .iter()
+            .filter(|(_, (_, pending))| pending.is_expired(now))
+            .map(|(request_id, _)| *request_id)
+            .collect();
+        let mut expired: Vec<_> = expired
+            .into_iter()
+            .filter_map(|request_id| Some((request_id, self.pending.remove(&request_id)?)))
+            .collect();
+        expired.sort_by_key(|(_, (order, _))| *order);
+        expired
+            .into_iter()
+            .map(|(request_id, (_, pending))| (request_id, pending))
+            .collect()
+    }
+
+    /// Stops tracking the oldest request that `answers` an update without a
+    /// `correlation_id`, e.g. under [`ProtocolVersion::V1`](super::ProtocolVersion::V1).
+    pub fn resolve_oldest(
+        &mut self,
+        answers: impl Fn(&T) -> bool,
+    ) -> Option<(RequestId, PendingRequest<T>)> {
+        let (request_id, _) = self
+            .pending
+            .iter()
+            .filter(|(_, (_, pending))| answers(&pending.context))
+            .map(|(request_id, (order, _))| (*request_id, *order))
+            .min_by_key(|(_, order)| *order)?;
+        let (_, pending) = self.pending.remove(&request_id)?;
+        Some((request_id, pending))
+    }
+
+    pub fn get(&self, request_id: RequestId) -> Option<&PendingRequest<T>> {
+        self.pending.get(&request_id).map(|(_, pending)| pending)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (RequestId, &PendingRequest<T>)> {
+        self.pending.iter().map(|(k, (_, v))| (*k, v))
+    }
+
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+impl PlasmaRequestV1 {
+    /// Milliseconds to wait for a reply, or `None` if Plasma doesn't reply.
+    pub fn reply_timeout(&self) -> Option<i64> {
+        match self {
+            Self::AuthenticatePlayer { .. } => Some(30 * 1000),
+            Self::RegisterServer { .. } => Some(30 * 1000),
+            Self::RequestTopology {} => Some(30 * 1000),
+            Self::ReserveTeamName { .. } => Some(10 * 1000),
+            Self::TranslateChat { .. } => Some(10 * 1000),
+            _ => None,
+        }
+    }
+
+    /// Whether `update` is the reply to this request, for updates without a
+    /// `correlation_id`.
+    pub fn is_answered_by(&self, update: &PlasmaUpdateV1) -> bool {
+        match (self, update) {
+            (
+                Self::RegisterServer { .. },
+                PlasmaUpdateV1::Handshake { .. } | PlasmaUpdateV1::Snippets { .. },
+            ) => true,
+            (Self::RequestTopology {}, PlasmaUpdateV1::Topology { .. }) => true,
+            (
+                Self::ReserveTeamName {
+                    arena_id,
+                    player_id,
+                    team_name,
+                    ..
+                },
+                PlasmaUpdateV1::TeamName {
+                    arena_id: a,
+                    player_id: p,
+                    team_name: t,
+                    ..
+                }
+                | PlasmaUpdateV1::TeamNameUnavailable {
+                    arena_id: a,
+                    player_id: p,
+                    team_name: t,
+                },
+            ) => (arena_id, player_id, team_name) == (a, p, t),
+            (
+                Self::TranslateChat {
+                    chat_id,
+                    language_id,
+                },
+                PlasmaUpdateV1::ChatTranslation {
+                    chat_id: c,
+                    language_id: l,
+                    ..
+                },
+            ) => (chat_id, language_id) == (c, l),
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plasma::at;
+    use crate::{PendingRequests, RequestId};
+    use std::num::NonZeroU32;
+
+    #[test]
+    fn resolve_and_expire() {
+        let first = RequestId::default();
+        let second = first.next();
+        let third = second.next();
+        let mut pending = PendingRequests::new();
+        pending.insert(third, "c", at(0), 5_000);
+        pending.insert(first, "a", at(0), 10_000);
+        pending.insert(second, "b", at(0), 5_000);
+
+        assert_eq!(pending.resolve(first).unwrap().context, "a");
+        assert!(pending.resolve(first).is_none());
+        assert!(pending.expire(at(4)).is_empty());
+
+        let expired: Vec<_> = pending
+            .expire(at(5))
+            .into_iter()
+            .map(|(request_id, pending)| (request_id, pending.context))
+            .collect();
+        assert_eq!(expired, [(third, "c"), (second, "b")]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn oldest_after_wrapping() {
+        // `last` has the greatest ID, but was sent first.
+        let last = RequestId(NonZeroU32::MAX);
+        let wrapped = last.next();
+        let mut pending = PendingRequests::new();
+        pending.insert(last, "a", at(0), 5_000);
+        pending.insert(wrapped, "b", at(0), 5_000);
+        let (oldest, _) = pending.resolve_oldest(|_| true).unwrap();
+        assert_eq!(oldest, last);
+    }
+}
@@ -0,0 +1,248 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use crate::{NonZeroUnixMillis, UnixTime, VisitorId};
+use rustrict::{Censor, Type};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+use std::net::IpAddr;
+
+/// Chat moderation of a realm, set by `ModerateChat` and sent in `ChatPolicy`.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ChatPolicy {
+    /// Messages are censored at [`ChatPolicy::safe_mode_threshold`] until then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub safe_mode_until: Option<NonZeroUnixMillis>,
+    /// Senders are limited by a [`ChatRateLimiter`] until then.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub slow_mode_until: Option<NonZeroUnixMillis>,
+}
+
+/// Why a chat wasn't sent.
+#[derive(Debug, Clone)]
+pub enum ChatRejection {
+    /// The sender may chat again at `until`.
+    SlowMode { until: NonZeroUnixMillis },
+}
+
+impl Display for ChatRejection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for ChatRejection {}
+
+impl ChatPolicy {
+    /// Stricter than the default censor threshold, catching mild profanity too.
+    pub fn safe_mode_threshold() -> Type {
+        Type::INAPPROPRIATE
+            | ((Type::PROFANE | Type::OFFENSIVE | Type::SEXUAL | Type::MEAN) & Type::MILD_OR_HIGHER)
+    }
+
+    /// Applies `ModerateChat`, where each mode lasts for some minutes from `now` and
+    /// `None` has no effect. Zero minutes ends a mode.
+    pub fn moderate(
+        &mut self,
+        safe_mode: Option<u32>,
+        slow_mode: Option<u32>,
+        now: NonZeroUnixMillis,
+    ) {
+        let until = |minutes: u32| {
+            (minutes > 0)
+                .then(|| NonZeroUnixMillis::from_i64(now.to_i64() + minutes as i64 * 60 * 1000))
+        };
+        if let Some(minutes) = safe_mode {
+            self.safe_mode_until = until(minutes);
+        }
+        if let Some(minutes) = slow_mode {
+            self.slow_mode_until = until(minutes);
+        }
+    }
+
+    pub fn is_safe_mode(&self, now: NonZeroUnixMillis) -> bool {
+        self.safe_mode_until.is_some_and(|until| now < until)
+    }
+
+    pub fn is_slow_mode(&self, now: NonZeroUnixMillis) -> bool {
+        self.slow_mode_until.is_some_and(|until| now < until)
+    }
+
+    /// Forgets modes that ended, returning whether any did.
+    pub fn expire(&mut self, now: NonZeroUnixMillis) -> bool {
+        let before = *self;
+        if !self.is_safe_mode(now) {
+            self.safe_mode_until = None;
+        }
+        if !self.is_slow_mode(now) {
+            self.slow_mode_until = None;
+        }
+        *self != before
+    }
+
+    /// Censors a message more strictly in safe mode, and otherwise leaves it as is.
+    pub fn censor(&self, message: &str, now: NonZeroUnixMillis) -> String {
+        if self.is_safe_mode(now) {
+            Censor::from_str(message)
+                .with_censor_threshold(Self::safe_mode_threshold())
+                .censor()
+        } else {
+            message.to_owned()
+        }
+    }
+
+    /// Rate limits the sender in slow mode, then censors the message.
+    pub fn enforce(
+        &self,
+        rate_limiter: &mut ChatRateLimiter,
+        visitor_id: Option<VisitorId>,
+        ip_address: IpAddr,
+        message: &str,
+        now: NonZeroUnixMillis,
+    ) -> Result<String, ChatRejection> {
+        if self.is_slow_mode(now) {
+            rate_limiter.check(visitor_id, ip_address, now)?;
+        }
+        Ok(self.censor(message, now))
+    }
+}
+
+/// Allows each visitor and IP address one chat per interval, for slow mode.
+#[derive(Clone, Debug)]
+pub struct ChatRateLimiter {
+    interval: i64,
+    visitors: HashMap<VisitorId, NonZeroUnixMillis>,
+    ip_addresses: HashMap<IpAddr, NonZeroUnixMillis>,
+}
+
+impl ChatRateLimiter {
+    /// Milliseconds between chats by default.
+    pub const INTERVAL: i64 = 10 * 1000;
+
+    pub fn new(interval: i64) -> Self {
+        Self {
+            interval,
+            visitors: Default::default(),
+            ip_addresses: Default::default(),
+        }
+    }
+
+    /// Records a chat, unless the visitor or IP address chatted within the interval.
+    pub fn check(
+        &mut self,
+        visitor_id: Option<VisitorId>,
+        ip_address: IpAddr,
+        now: NonZeroUnixMillis,
+    ) -> Result<(), ChatRejection> {
+        let last = visitor_id
+            .and_then(|v| self.visitors.get(&v))
+            .into_iter()
+            .chain(self.ip_addresses.get(&ip_address))
+            .max();
+        if let Some(last) = last {
+            let until = NonZeroUnixMillis::from_i64(last.to_i64() + self.interval);
+            if now < until {
+                return Err(ChatRejection::SlowMode { until });
+            }
+        }
+        if let Some(visitor_id) = visitor_id {
+            self.visitors.insert(visitor_id, now);
+        }
+        self.ip_addresses.insert(ip_address, now);
+        Ok(())
+    }
+
+    /// Forgets senders that may chat again.
+    pub fn prune(&mut self, now: NonZeroUnixMillis) {
+        let interval = self.interval;
+        let recent = |last: &mut NonZeroUnixMillis| now.to_i64() < last.to_i64() + interval;
+        self.visitors.retain(|_, last| recent(last));
+        self.ip_addresses.retain(|_, last| recent(last));
+    }
+}
+
+impl Default for ChatRateLimiter {
+    fn default() -> Self {
+        Self::new(Self::INTERVAL)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plasma::at;
+    use crate::{ChatPolicy, ChatRateLimiter, ChatRejection, VisitorId};
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::num::NonZeroU64;
+
+    fn visitor(n: u64) -> Option<VisitorId> {
+        Some(VisitorId(NonZeroU64::new(n).unwrap()))
+    }
+
+    fn ip(n: u8) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, n))
+    }
+
+    #[test]
+    fn moderate_and_expire() {
+        let mut policy = ChatPolicy::default();
+        policy.moderate(Some(5), None, at(0));
+        assert!(policy.is_safe_mode(at(299)));
+        assert!(!policy.is_safe_mode(at(300)));
+        assert!(!policy.is_slow_mode(at(0)));
+
+        // `None` leaves a mode as is, zero ends it.
+        policy.moderate(None, Some(1), at(10));
+        assert!(policy.is_safe_mode(at(10)) && policy.is_slow_mode(at(10)));
+        policy.moderate(Some(0), None, at(20));
+        assert_eq!(policy.safe_mode_until, None);
+
+        assert!(!policy.expire(at(69)));
+        assert!(policy.expire(at(70)));
+        assert_eq!(policy, ChatPolicy::default());
+    }
+
+    #[test]
+    fn slow_mode() {
+        let mut limiter = ChatRateLimiter::new(10 * 1000);
+        assert!(limiter.check(visitor(1), ip(1), at(0)).is_ok());
+        assert!(matches!(
+            limiter.check(visitor(1), ip(2), at(5)),
+            Err(ChatRejection::SlowMode { until }) if until == at(10)
+        ));
+        // Same IP address with a different (or no) visitor ID.
+        assert!(limiter.check(visitor(2), ip(1), at(5)).is_err());
+        assert!(limiter.check(None, ip(1), at(5)).is_err());
+        assert!(limiter.check(visitor(2), ip(2), at(5)).is_ok());
+        assert!(limiter.check(visitor(1), ip(1), at(10)).is_ok());
+
+        limiter.prune(at(15));
+        assert!(limiter.check(visitor(2), ip(2), at(15)).is_ok());
+
+        let mut policy = ChatPolicy::default();
+        let mut limiter = ChatRateLimiter::default();
+        for _ in 0..3 {
+            assert!(policy
+                .enforce(&mut limiter, visitor(1), ip(1), "hi", at(0))
+                .is_ok());
+        }
+        policy.moderate(None, Some(1), at(0));
+        assert!(policy
+            .enforce(&mut limiter, visitor(1), ip(1), "hi", at(0))
+            .is_ok());
+        assert!(policy
+            .enforce(&mut limiter, visitor(1), ip(1), "hi", at(1))
+            .is_err());
+    }
+
+    #[test]
+    fn safe_mode() {
+        let mut policy = ChatPolicy::default();
+        let profane = "oh shit";
+        assert_eq!(policy.censor(profane, at(0)), profane);
+        policy.moderate(Some(1), None, at(0));
+        assert_ne!(policy.censor(profane, at(0)), profane);
+        assert_eq!(policy.censor("hello", at(0)), "hello");
+        assert_eq!(policy.censor(profane, at(60)), profane);
+    }
+}
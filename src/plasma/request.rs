@@ -1,10 +1,12 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use super::{ChatRecipient, ClaimUpdateDto, RealmHeartbeat, ServerLogDto};
+use super::{
+    ChatRecipient, ClaimUpdateDto, PlasmaHandshake, ProtocolVersion, RealmHeartbeat, ServerLogDto,
+};
 use crate::{
-    is_default, ArenaId, ArenaToken, ChatId, ClientHash, EngineMetrics, GameId,
+    is_default, ArenaId, ArenaToken, ChatId, ClientHash, EngineMetrics, GameId, LanguageId,
     LeaderboardScoreDto, MetricFilter, NonZeroUnixMillis, PlayerAlias, PlayerId, QuestSampleDto,
-    RealmId, ServerId, SessionToken, TeamName, TeamToken, VisitorId,
+    RealmId, RequestId, ServerId, SessionToken, TeamName, TeamToken, VisitorId,
 };
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashSet};
@@ -38,6 +40,52 @@ pub enum PlasmaDeveloperV1 {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum PlasmaRequest {
     V1(PlasmaRequestV1),
+    /// Only sent if negotiated.
+    V2(PlasmaRequestV2),
+}
+
+impl PlasmaRequest {
+    /// Wraps a request in the envelope of `version`, dropping what it can't carry.
+    pub fn new(version: ProtocolVersion, request_id: RequestId, request: PlasmaRequestV1) -> Self {
+        match version {
+            ProtocolVersion::V1 => Self::V1(request),
+            ProtocolVersion::V2 => Self::V2(PlasmaRequestV2 {
+                request_id,
+                request,
+            }),
+        }
+    }
+
+    pub fn version(&self) -> ProtocolVersion {
+        match self {
+            Self::V1(_) => ProtocolVersion::V1,
+            Self::V2(_) => ProtocolVersion::V2,
+        }
+    }
+
+    /// Unwraps a request of any version. V1 requests have no request ID.
+    pub fn into_parts(self) -> (Option<RequestId>, PlasmaRequestV1) {
+        match self {
+            Self::V1(request) => (None, request),
+            Self::V2(PlasmaRequestV2 {
+                request_id,
+                request,
+            }) => (Some(request_id), request),
+        }
+    }
+}
+
+/// A request that updates may refer to by `request_id`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlasmaRequestV2 {
+    pub request_id: RequestId,
+    pub request: PlasmaRequestV1,
+}
+
+impl From<PlasmaRequestV2> for PlasmaRequestV1 {
+    fn from(request: PlasmaRequestV2) -> Self {
+        request.request
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -118,13 +166,17 @@ pub enum PlasmaRequestV1 {
     /// A server has started running.  Registers the [`game_id`] and [`server_id`]
     /// provided when opening web socket.
     ///
-    /// Plasma sends [`Referrers`] and [`Snippets`] in response.
+    /// Plasma sends [`Referrers`] and [`Snippets`] in response, preceded by [`Handshake`]
+    /// if `handshake` is [`Some`].
     //
     // {} is for backward compatibility
     RegisterServer {
         // TODO: this is an Option for backward compatibility but eventually won't be an Option.
         #[serde(default, skip_serializing_if = "Option::is_none")]
         date_started: Option<NonZeroUnixMillis>,
+        /// Supported versions and capabilities. `None` is sent by old servers.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        handshake: Option<PlasmaHandshake>,
     },
     /// Releases team name.  No response is sent.
     ReleaseTeamName {
@@ -138,7 +190,14 @@ pub enum PlasmaRequestV1 {
         /// Proof that team was reserved.
         team_token: TeamToken,
     },
-    /// Plasma sends [`TeamName`] in response if the team name is available.
+    /// Plasma sends a full [`Topology`] in response, e.g. after a [`TopologyDelta`]
+    /// arrived out of sequence.
+    //
+    // {} is for backward compatibility
+    RequestTopology {},
+    /// Plasma sends [`TeamName`] in response if the team name is available, and
+    /// [`TeamNameUnavailable`] otherwise. If neither arrives within 10 seconds, see
+    /// [`PlasmaRequestV1::reply_timeout`], the reservation times out.
     ReserveTeamName {
         /// Arena ID of requestor (scene id may change as player moves
         /// between arenas, but realm id remains constant).
@@ -204,6 +263,14 @@ pub enum PlasmaRequestV1 {
         /// Server IDs of recipient servers (these must be of the same kind, local/cloud, as sender).
         recipients: HashSet<ServerId>,
     },
+    /// Ask for a chat message to be translated, e.g. for a recipient whose language
+    /// has no cached translation. Plasma replies with [`ChatTranslation`].
+    TranslateChat {
+        /// The chat message, which must have been sent recently.
+        chat_id: ChatId,
+        /// Language to translate into.
+        language_id: LanguageId,
+    },
     /// A server has stopped. The server, its arenas, and their players are cleared.
     UnregisterServer,
     /// Update the leaderboards with recent scores, always in batches
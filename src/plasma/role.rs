@@ -0,0 +1,397 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::ServerRole;
+use crate::{NonZeroUnixMillis, ServerNumber, UnixTime};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+/// What a server knows when its role changes.
+#[derive(Clone, Debug)]
+pub struct RoleTransitionContext<'a> {
+    pub now: NonZeroUnixMillis,
+    /// Players currently connected to the server.
+    pub player_count: usize,
+    /// The server's own number, if it is a cloud server.
+    pub server_number: Option<ServerNumber>,
+    /// Known roles of other servers, to detect redirect cycles.
+    pub roles: &'a HashMap<ServerNumber, ServerRole>,
+}
+
+/// Something a server must do when its role changes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RoleSideEffect {
+    StartAcceptingConnections,
+    StopAcceptingConnections,
+    /// Redirect new connections to the server (replacing any previous redirect).
+    StartRedirecting(ServerNumber),
+    StopRedirecting,
+    ShowInSelector,
+    HideFromSelector,
+    /// Shut down; the server is being deleted.
+    Delete,
+}
+
+/// A validated role change, see [`ServerRole::transition`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RoleTransition {
+    pub from: ServerRole,
+    pub to: ServerRole,
+    /// In the order they should be performed.
+    pub side_effects: Vec<RoleSideEffect>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum InvalidRoleTransition {
+    /// `Deleting` is terminal.
+    Deleting,
+    /// `Failed` servers stay failed for [`ServerRole::FAILED_MINIMUM`].
+    FailedTooRecently {
+        remaining: i64,
+    },
+    /// Only servers without players may be deleted or re-allocated.
+    PlayersRemaining {
+        player_count: usize,
+    },
+    /// The documented lifecycle doesn't allow it.
+    NotAllowed,
+    RedirectToSelf,
+    /// Following redirects from this server revisits a server.
+    RedirectCycle {
+        servers: Vec<ServerNumber>,
+    },
+}
+
+impl Display for InvalidRoleTransition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for InvalidRoleTransition {}
+
+impl ServerRole {
+    /// Milliseconds a server stays `Failed` to allow diagnostics.
+    pub const FAILED_MINIMUM: i64 = 24 * 60 * 60 * 1000;
+
+    /// Accepts new connections (instead of refusing or redirecting them).
+    pub fn is_accepting_connections(self) -> bool {
+        matches!(self, Self::Public | Self::Realms | Self::Unlisted)
+    }
+
+    /// Displayed in the server selector.
+    pub fn is_listed(self) -> bool {
+        self.is_public()
+    }
+
+    /// Validates a role change against the documented lifecycle:
+    /// - Any role except `Deleting` may fall back to `Unlisted` (e.g. when Plasma is unreachable),
+    ///   subject to the `Terminating` and `Failed` rules below.
+    /// - `Unlisted`, `Public`, and `Realms` may become any role except `Deleting`.
+    /// - `Standby` may be conscripted as `Public`, `Realms`, or `Unlisted`, or close further.
+    /// - `Terminating` may only become `Deleting`, `Public`, `Realms`, or `Unlisted` without
+    ///   players.
+    /// - `Failed` stays failed for [`Self::FAILED_MINIMUM`], then may become `Unlisted`, or
+    ///   `Deleting` once without players.
+    /// - `Deleting` is terminal.
+    pub fn transition(
+        from: Self,
+        to: Self,
+        context: &RoleTransitionContext<'_>,
+    ) -> Result<RoleTransition, InvalidRoleTransition> {
+        use ServerRole::*;
+
+        let require_empty = || {
+            if context.player_count == 0 {
+                Ok(())
+            } else {
+                Err(InvalidRoleTransition::PlayersRemaining {
+                    player_count: context.player_count,
+                })
+            }
+        };
+        let not_allowed = Err(InvalidRoleTransition::NotAllowed);
+
+        match (from, to) {
+            (Deleting, Deleting) => {}
+            (Deleting, _) => return Err(InvalidRoleTransition::Deleting),
+            (Failed { date_failed, .. }, Deleting | Unlisted) => {
+                let elapsed = context.now.to_i64() - date_failed.to_i64();
+                if elapsed < Self::FAILED_MINIMUM {
+                    return Err(InvalidRoleTransition::FailedTooRecently {
+                        remaining: Self::FAILED_MINIMUM - elapsed,
+                    });
+                }
+                if to == Deleting {
+                    require_empty()?;
+                }
+            }
+            (Failed { .. }, Failed { .. }) => {}
+            (Failed { .. }, _) => return not_allowed,
+            (Terminating { .. }, Deleting | Public | Realms | Unlisted) => require_empty()?,
+            (Terminating { .. }, Terminating { .. } | Failed { .. }) => {}
+            (Terminating { .. }, Standby { .. }) => return not_allowed,
+            (_, Unlisted) => {}
+            (Unlisted | Public | Realms, Deleting) => return not_allowed,
+            (Unlisted | Public | Realms, _) => {}
+            (Standby { .. }, Deleting) => return not_allowed,
+            (Standby { .. }, _) => {}
+        }
+
+        if let Some(redirect) = to.redirect() {
+            if Some(redirect) == context.server_number {
+                return Err(InvalidRoleTransition::RedirectToSelf);
+            }
+            if let Some(servers) = Self::redirect_cycle(context.server_number, to, context.roles) {
+                return Err(InvalidRoleTransition::RedirectCycle { servers });
+            }
+        }
+
+        Ok(RoleTransition {
+            from,
+            to,
+            side_effects: Self::side_effects(from, to),
+        })
+    }
+
+    /// Follows redirects from a server with `role`, returning the visited servers if they
+    /// form a cycle.
+    pub fn redirect_cycle(
+        server_number: Option<ServerNumber>,
+        role: Self,
+        roles: &HashMap<ServerNumber, ServerRole>,
+    ) -> Option<Vec<ServerNumber>> {
+        let mut visited: Vec<ServerNumber> = server_number.into_iter().collect();
+        let mut next = role.redirect();
+        while let Some(current) = next {
+            let cycle = visited.contains(&current);
+            visited.push(current);
+            if cycle {
+                return Some(visited);
+            }
+            next = roles.get(&current).and_then(|r| r.redirect());
+        }
+        None
+    }
+
+    fn side_effects(from: Self, to: Self) -> Vec<RoleSideEffect> {
+        let mut side_effects = Vec::new();
+        if from.is_accepting_connections() && !to.is_accepting_connections() {
+            side_effects.push(RoleSideEffect::StopAcceptingConnections);
+        }
+        if from.is_listed() && !to.is_listed() {
+            side_effects.push(RoleSideEffect::HideFromSelector);
+        }
+        match (from.redirect(), to.redirect()) {
+            (old, Some(new)) if old != Some(new) => {
+                side_effects.push(RoleSideEffect::StartRedirecting(new))
+            }
+            (Some(_), None) => side_effects.push(RoleSideEffect::StopRedirecting),
+            _ => {}
+        }
+        if !from.is_accepting_connections() && to.is_accepting_connections() {
+            side_effects.push(RoleSideEffect::StartAcceptingConnections);
+        }
+        if !from.is_listed() && to.is_listed() {
+            side_effects.push(RoleSideEffect::ShowInSelector);
+        }
+        if !matches!(from, Self::Deleting) && matches!(to, Self::Deleting) {
+            side_effects.push(RoleSideEffect::Delete);
+        }
+        side_effects
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plasma::at;
+    use crate::{
+        InvalidRoleTransition, NonZeroUnixMillis, RoleSideEffect, RoleTransitionContext,
+        ServerNumber, ServerRole,
+    };
+    use std::collections::HashMap;
+    use std::num::NonZeroU8;
+
+    fn n(n: u8) -> ServerNumber {
+        ServerNumber(NonZeroU8::new(n).unwrap())
+    }
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    /// One of each kind of role, in the order of the table below.
+    fn roles() -> [ServerRole; 7] {
+        [
+            ServerRole::Deleting,
+            ServerRole::Failed {
+                date_failed: at(0),
+                diagnostic: None,
+                redirect: Some(n(2)),
+            },
+            ServerRole::Public,
+            ServerRole::Realms,
+            ServerRole::Standby {
+                redirect: Some(n(2)),
+            },
+            ServerRole::Terminating {
+                redirect: Some(n(2)),
+            },
+            ServerRole::Unlisted,
+        ]
+    }
+
+    fn allowed(now: NonZeroUnixMillis, player_count: usize) -> Vec<[bool; 7]> {
+        let other_roles = HashMap::new();
+        let context = RoleTransitionContext {
+            now,
+            player_count,
+            server_number: Some(n(1)),
+            roles: &other_roles,
+        };
+        roles()
+            .map(|from| roles().map(|to| ServerRole::transition(from, to, &context).is_ok()))
+            .to_vec()
+    }
+
+    #[test]
+    fn exhaustive() {
+        const T: bool = true;
+        const F: bool = false;
+
+        // Columns: Deleting, Failed, Public, Realms, Standby, Terminating, Unlisted.
+        let with_players = [
+            [T, F, F, F, F, F, F], // Deleting
+            [F, T, F, F, F, F, T], // Failed
+            [F, T, T, T, T, T, T], // Public
+            [F, T, T, T, T, T, T], // Realms
+            [F, T, T, T, T, T, T], // Standby
+            [F, T, F, F, F, T, F], // Terminating
+            [F, T, T, T, T, T, T], // Unlisted
+        ];
+        assert_eq!(allowed(at(DAY), 3), with_players);
+
+        let mut without_players = with_players;
+        without_players[1][0] = true; // Failed -> Deleting after 24h.
+        without_players[5][0] = true; // Terminating -> Deleting.
+        without_players[5][2] = true; // Terminating -> Public.
+        without_players[5][3] = true; // Terminating -> Realms.
+        without_players[5][6] = true; // Terminating -> Unlisted.
+        assert_eq!(allowed(at(DAY), 0), without_players);
+
+        let mut too_soon = without_players;
+        too_soon[1][0] = false;
+        too_soon[1][6] = false;
+        assert_eq!(allowed(at(DAY - 1), 0), too_soon);
+    }
+
+    #[test]
+    fn errors() {
+        let roles = HashMap::from([
+            (
+                n(2),
+                ServerRole::Standby {
+                    redirect: Some(n(3)),
+                },
+            ),
+            (
+                n(3),
+                ServerRole::Terminating {
+                    redirect: Some(n(1)),
+                },
+            ),
+        ]);
+        let context = RoleTransitionContext {
+            now: at(0),
+            player_count: 1,
+            server_number: Some(n(1)),
+            roles: &roles,
+        };
+        let standby = |redirect| ServerRole::Standby {
+            redirect: Some(n(redirect)),
+        };
+
+        assert_eq!(
+            ServerRole::transition(ServerRole::Public, standby(1), &context),
+            Err(InvalidRoleTransition::RedirectToSelf)
+        );
+        assert_eq!(
+            ServerRole::transition(ServerRole::Public, standby(2), &context),
+            Err(InvalidRoleTransition::RedirectCycle {
+                servers: vec![n(1), n(2), n(3), n(1)]
+            })
+        );
+        assert_eq!(
+            ServerRole::transition(ServerRole::Public, standby(4), &context)
+                .map(|t| t.side_effects),
+            Ok(vec![
+                RoleSideEffect::StopAcceptingConnections,
+                RoleSideEffect::HideFromSelector,
+                RoleSideEffect::StartRedirecting(n(4)),
+            ])
+        );
+        assert_eq!(
+            ServerRole::transition(ServerRole::Deleting, ServerRole::Public, &context),
+            Err(InvalidRoleTransition::Deleting)
+        );
+        assert_eq!(
+            ServerRole::transition(
+                ServerRole::Terminating { redirect: None },
+                ServerRole::Deleting,
+                &context
+            ),
+            Err(InvalidRoleTransition::PlayersRemaining { player_count: 1 })
+        );
+    }
+
+    #[test]
+    fn side_effects() {
+        let roles = HashMap::new();
+        let context = RoleTransitionContext {
+            now: at(0),
+            player_count: 0,
+            server_number: None,
+            roles: &roles,
+        };
+        let effects = |from, to| {
+            ServerRole::transition(from, to, &context)
+                .unwrap()
+                .side_effects
+        };
+
+        assert!(effects(ServerRole::Public, ServerRole::Public).is_empty());
+        assert_eq!(
+            effects(
+                ServerRole::Standby {
+                    redirect: Some(n(2))
+                },
+                ServerRole::Public
+            ),
+            vec![
+                RoleSideEffect::StopRedirecting,
+                RoleSideEffect::StartAcceptingConnections,
+                RoleSideEffect::ShowInSelector,
+            ]
+        );
+        assert_eq!(
+            effects(
+                ServerRole::Standby {
+                    redirect: Some(n(2))
+                },
+                ServerRole::Standby {
+                    redirect: Some(n(3))
+                }
+            ),
+            vec![RoleSideEffect::StartRedirecting(n(3))]
+        );
+        assert_eq!(
+            effects(
+                ServerRole::Terminating { redirect: None },
+                ServerRole::Deleting
+            ),
+            vec![RoleSideEffect::Delete]
+        );
+        assert_eq!(
+            effects(ServerRole::Realms, ServerRole::Unlisted),
+            Vec::<RoleSideEffect>::new()
+        );
+    }
+}
@@ -0,0 +1,429 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{SceneUseTopology, ServerRole, ServerUseTopology};
+use crate::{ArenaId, ArenaQuery, RealmId, RegionId, ServerId, TierNumber, UserId, VisitorId};
+use std::collections::HashMap;
+use std::fmt::{self, Display, Formatter};
+
+/// Where to send a player, see [`PlayerRouter::route`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Route {
+    pub server_id: ServerId,
+    pub arena_id: ArenaId,
+    pub explanation: RouteExplanation,
+}
+
+/// Every arena that was considered, for debugging.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RouteExplanation {
+    /// Best first.
+    pub candidates: Vec<RouteCandidate>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteCandidate {
+    pub server_id: ServerId,
+    pub arena_id: ArenaId,
+    /// [`RegionId::distance`] from the player, or 0 if the player's region is unknown.
+    pub distance: u8,
+    pub player_count: u16,
+    /// From the scene's `max_players` setting.
+    pub capacity: Option<u16>,
+    pub verdict: RouteVerdict,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RouteVerdict {
+    Chosen,
+    /// Eligible but scored worse than the chosen candidate.
+    Eligible,
+    /// Realm ACL doesn't allow the player.
+    Denied,
+    /// At or over capacity.
+    Full,
+    /// Server is closing and redirects to another server.
+    Redirected {
+        to: ServerId,
+    },
+    /// Server is closing, or being deleted, without a redirect.
+    Closing,
+    /// Server's redirects form a cycle.
+    RedirectCycle,
+}
+
+#[derive(Debug, Clone)]
+pub enum RouteError {
+    /// Queries other than `Specific` and `AnyInstance` are resolved by Plasma.
+    Unsupported,
+    /// Following `ServerRole::redirect` revisited a server.
+    RedirectCycle { servers: Vec<ServerId> },
+    /// No arena is eligible.
+    NoCandidates { explanation: RouteExplanation },
+}
+
+impl Display for RouteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// Chooses an arena for a player from the current `Topology`.
+///
+/// Nearer regions are preferred, then fuller (but not full) arenas so that players
+/// find each other.
+#[derive(Clone, Debug)]
+pub struct PlayerRouter<'a> {
+    topology: &'a HashMap<ServerId, ServerUseTopology>,
+    roles: HashMap<ServerId, ServerRole>,
+}
+
+impl<'a> PlayerRouter<'a> {
+    /// Scene setting that caps the player count.
+    pub const CAPACITY_SETTING: &'static str = "max_players";
+
+    pub fn new(topology: &'a HashMap<ServerId, ServerUseTopology>) -> Self {
+        Self {
+            topology,
+            roles: Default::default(),
+        }
+    }
+
+    /// Roles of servers, if known. Servers without a known role are assumed to be
+    /// open, as `Topology` only contains those.
+    pub fn with_role(mut self, server_id: ServerId, role: ServerRole) -> Self {
+        self.roles.insert(server_id, role);
+        self
+    }
+
+    pub fn set_role(&mut self, server_id: ServerId, role: ServerRole) {
+        self.roles.insert(server_id, role);
+    }
+
+    /// Follows redirects starting at `server_id`, returning the server that will
+    /// actually accept players. Redirects stay within the kind of `server_id`.
+    pub fn resolve_redirects(&self, server_id: ServerId) -> Result<ServerId, RouteError> {
+        let mut visited = vec![server_id];
+        let mut current = server_id;
+        while let Some(number) = self.roles.get(&current).and_then(|r| r.redirect()) {
+            current = ServerId {
+                kind: server_id.kind,
+                number,
+            };
+            if visited.contains(&current) {
+                visited.push(current);
+                return Err(RouteError::RedirectCycle { servers: visited });
+            }
+            visited.push(current);
+        }
+        Ok(current)
+    }
+
+    /// Resolves `query` for a player in `region_id` (if known).
+    pub fn route(
+        &self,
+        query: ArenaQuery,
+        region_id: Option<RegionId>,
+        visitor_id: Option<VisitorId>,
+        user_id: Option<UserId>,
+    ) -> Result<Route, RouteError> {
+        match query {
+            ArenaQuery::Specific(arena_id, _) => {
+                let route = |filter: &dyn Fn(ServerId, ArenaId) -> bool| {
+                    self.route_any_instance(
+                        arena_id.realm_id,
+                        arena_id.scene_id.tier_number,
+                        region_id,
+                        visitor_id,
+                        user_id,
+                        filter,
+                    )
+                };
+                // Prefer the exact arena, otherwise the server it redirects to, otherwise
+                // another instance of the same tier.
+                route(&|_, candidate| candidate == arena_id)
+                    .or_else(|e| {
+                        let RouteError::NoCandidates { explanation } = &e else {
+                            return Err(e);
+                        };
+                        let Some(to) =
+                            explanation.candidates.iter().find_map(|c| match c.verdict {
+                                RouteVerdict::Redirected { to } => Some(to),
+                                _ => None,
+                            })
+                        else {
+                            return Err(e);
+                        };
+                        route(&|server_id, candidate| server_id == to && candidate == arena_id)
+                            .or_else(|_| route(&|server_id, _| server_id == to))
+                    })
+                    .or_else(|_| route(&|_, _| true))
+            }
+            ArenaQuery::AnyInstance(realm_id, tier_number) => self.route_any_instance(
+                realm_id,
+                tier_number,
+                region_id,
+                visitor_id,
+                user_id,
+                |_, _| true,
+            ),
+            ArenaQuery::NewTemporary | ArenaQuery::Invitation(_) => Err(RouteError::Unsupported),
+        }
+    }
+
+    fn route_any_instance(
+        &self,
+        realm_id: RealmId,
+        tier_number: Option<TierNumber>,
+        region_id: Option<RegionId>,
+        visitor_id: Option<VisitorId>,
+        user_id: Option<UserId>,
+        filter: impl Fn(ServerId, ArenaId) -> bool,
+    ) -> Result<Route, RouteError> {
+        let mut candidates = Vec::new();
+        for (server_id, server) in self.topology {
+            let Some(realm) = server.realm(realm_id) else {
+                continue;
+            };
+            let distance = region_id.map_or(0, |r| r.distance(server.region_id));
+            let target = self.resolve_redirects(*server_id);
+            for (scene_id, scene) in &realm.scenes {
+                let arena_id = ArenaId::new(realm_id, *scene_id);
+                if scene_id.tier_number != tier_number || !filter(*server_id, arena_id) {
+                    continue;
+                }
+                let capacity = Self::capacity(scene);
+                let verdict = if target.is_err() {
+                    RouteVerdict::RedirectCycle
+                } else if let Some(to) = target.as_ref().ok().filter(|to| *to != server_id) {
+                    RouteVerdict::Redirected { to: *to }
+                } else if self
+                    .roles
+                    .get(server_id)
+                    .is_some_and(|r| r.is_closing() || *r == ServerRole::Deleting)
+                {
+                    RouteVerdict::Closing
+                } else if !realm.acl.allows(visitor_id, user_id) {
+                    RouteVerdict::Denied
+                } else if capacity.is_some_and(|c| scene.player_count >= c) {
+                    RouteVerdict::Full
+                } else {
+                    RouteVerdict::Eligible
+                };
+                candidates.push(RouteCandidate {
+                    server_id: *server_id,
+                    arena_id,
+                    distance,
+                    player_count: scene.player_count,
+                    capacity,
+                    verdict,
+                });
+            }
+        }
+
+        candidates.sort_by(|a, b| {
+            (a.verdict != RouteVerdict::Eligible)
+                .cmp(&(b.verdict != RouteVerdict::Eligible))
+                .then(a.distance.cmp(&b.distance))
+                .then(b.player_count.cmp(&a.player_count))
+                .then(a.server_id.cmp(&b.server_id))
+                .then(a.arena_id.scene_id.cmp(&b.arena_id.scene_id))
+        });
+
+        match candidates.first_mut() {
+            Some(best) if best.verdict == RouteVerdict::Eligible => {
+                best.verdict = RouteVerdict::Chosen;
+                Ok(Route {
+                    server_id: best.server_id,
+                    arena_id: best.arena_id,
+                    explanation: RouteExplanation { candidates },
+                })
+            }
+            _ => Err(RouteError::NoCandidates {
+                explanation: RouteExplanation { candidates },
+            }),
+        }
+    }
+
+    fn capacity(scene: &SceneUseTopology) -> Option<u16> {
+        scene
+            .settings
+            .as_ref()?
+            .get(Self::CAPACITY_SETTING)?
+            .as_u64()
+            .map(|c| c.min(u16::MAX as u64) as u16)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ArenaId, ArenaQuery, InstanceNumber, PlayerRouter, RealmAcl, RealmId, RealmUseTopology,
+        RegionId, RouteError, RouteVerdict, SceneId, SceneUseTopology, ServerId, ServerRole,
+        ServerUseTopology, VisitorId,
+    };
+    use std::collections::HashMap;
+    use std::num::NonZeroU64;
+    use std::str::FromStr;
+
+    fn server(region_id: RegionId, scenes: &[(u8, u16, Option<u16>)]) -> ServerUseTopology {
+        ServerUseTopology {
+            datacenter: String::new(),
+            default_realm: Some(RealmUseTopology {
+                acl: RealmAcl::default(),
+                scenes: scenes
+                    .iter()
+                    .map(|&(instance, player_count, max_players)| {
+                        (
+                            SceneId::new(None, InstanceNumber(instance)),
+                            SceneUseTopology {
+                                player_count,
+                                settings: max_players
+                                    .map(|m| serde_json::json!({ "max_players": m })),
+                            },
+                        )
+                    })
+                    .collect(),
+            }),
+            other_realms: Default::default(),
+            region_id,
+        }
+    }
+
+    fn id(s: &str) -> ServerId {
+        ServerId::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn nearest_then_fullest() {
+        let topology = HashMap::from([
+            (id("Cloud/1"), server(RegionId::Europe, &[(0, 5, None)])),
+            (
+                id("Cloud/2"),
+                server(RegionId::NorthAmerica, &[(1, 3, None), (2, 9, Some(9))]),
+            ),
+            (
+                id("Cloud/3"),
+                server(RegionId::NorthAmerica, &[(3, 7, None)]),
+            ),
+        ]);
+        let router = PlayerRouter::new(&topology);
+        let query = ArenaQuery::AnyInstance(RealmId::PublicDefault, None);
+
+        let route = router
+            .route(query, Some(RegionId::NorthAmerica), None, None)
+            .unwrap();
+        assert_eq!(route.server_id, id("Cloud/3"));
+        let full = route
+            .explanation
+            .candidates
+            .iter()
+            .find(|c| c.verdict == RouteVerdict::Full)
+            .unwrap();
+        assert_eq!(full.capacity, Some(9));
+
+        let route = router
+            .route(query, Some(RegionId::Europe), None, None)
+            .unwrap();
+        assert_eq!(route.server_id, id("Cloud/1"));
+
+        // Specific arena that exists is honored, even if not best.
+        let arena = |instance: u8| {
+            ArenaId::new(
+                RealmId::PublicDefault,
+                SceneId::new(None, InstanceNumber(instance)),
+            )
+        };
+        let specific = ArenaQuery::Specific(arena(1), None);
+        let route = router
+            .route(specific, Some(RegionId::NorthAmerica), None, None)
+            .unwrap();
+        assert_eq!((route.server_id, route.arena_id), (id("Cloud/2"), arena(1)));
+
+        // Unless its server redirects, in which case the player follows the redirect.
+        let router = PlayerRouter::new(&topology).with_role(
+            id("Cloud/2"),
+            ServerRole::Terminating {
+                redirect: Some(id("Cloud/3").number),
+            },
+        );
+        let route = router
+            .route(specific, Some(RegionId::Europe), None, None)
+            .unwrap();
+        assert_eq!((route.server_id, route.arena_id), (id("Cloud/3"), arena(3)));
+
+        // Servers being deleted aren't candidates.
+        let router = PlayerRouter::new(&topology).with_role(id("Cloud/3"), ServerRole::Deleting);
+        let route = router
+            .route(query, Some(RegionId::NorthAmerica), None, None)
+            .unwrap();
+        assert_eq!(route.server_id, id("Cloud/2"));
+        assert!(route
+            .explanation
+            .candidates
+            .iter()
+            .any(|c| c.server_id == id("Cloud/3") && c.verdict == RouteVerdict::Closing));
+    }
+
+    #[test]
+    fn acl_and_redirects() {
+        let visitor_id = VisitorId(NonZeroU64::new(1).unwrap());
+        let mut whitelisted = server(RegionId::Europe, &[(0, 0, None)]);
+        whitelisted.default_realm.as_mut().unwrap().acl =
+            RealmAcl::VisitorWhitelist([visitor_id].into_iter().collect());
+        let topology = HashMap::from([
+            (id("Cloud/1"), whitelisted),
+            (id("Cloud/2"), server(RegionId::Europe, &[(1, 0, None)])),
+        ]);
+        let query = ArenaQuery::AnyInstance(RealmId::PublicDefault, None);
+
+        let router = PlayerRouter::new(&topology);
+        assert_eq!(
+            router.route(query, None, None, None).unwrap().server_id,
+            id("Cloud/2")
+        );
+        assert_eq!(
+            router
+                .route(query, None, Some(visitor_id), None)
+                .unwrap()
+                .server_id,
+            id("Cloud/1")
+        );
+
+        let redirect = |n: &str| ServerRole::Terminating {
+            redirect: Some(id(n).number),
+        };
+        let router = PlayerRouter::new(&topology).with_role(id("Cloud/2"), redirect("Cloud/3"));
+        assert!(matches!(
+            router.route(query, None, None, None),
+            Err(RouteError::NoCandidates { .. })
+        ));
+        assert_eq!(
+            router.resolve_redirects(id("Cloud/2")).unwrap(),
+            id("Cloud/3")
+        );
+        let local = PlayerRouter::new(&topology).with_role(id("Local/2"), redirect("Local/3"));
+        assert_eq!(
+            local.resolve_redirects(id("Local/2")).unwrap(),
+            id("Local/3")
+        );
+
+        let router = router
+            .with_role(id("Cloud/3"), redirect("Cloud/4"))
+            .with_role(id("Cloud/4"), redirect("Cloud/2"));
+        assert!(matches!(
+            router.resolve_redirects(id("Cloud/2")),
+            Err(RouteError::RedirectCycle { servers }) if servers.len() == 4
+        ));
+        let Err(RouteError::NoCandidates { explanation }) = router.route(query, None, None, None)
+        else {
+            panic!();
+        };
+        assert!(explanation
+            .candidates
+            .iter()
+            .any(|c| c.verdict == RouteVerdict::RedirectCycle));
+    }
+}
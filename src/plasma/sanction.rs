@@ -0,0 +1,277 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{AbuseEscalation, PlasmaUpdateV1};
+use crate::{is_default, NonZeroUnixMillis, RealmId, VisitorId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// What a [`Sanction`] restricts, from least to most severe.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+pub enum SanctionKind {
+    /// Chats are silently dropped, without telling the sender.
+    Mute,
+    /// Chats are refused, telling the sender.
+    ChatBan,
+    /// May not join the realm (or any realm if unscoped) or chat there.
+    RealmBan,
+    /// May not join any realm or chat.
+    GlobalBan,
+}
+
+impl SanctionKind {
+    /// Every kind blocks chat, only bans block joining.
+    pub fn blocks_join(self) -> bool {
+        matches!(self, Self::RealmBan | Self::GlobalBan)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash, Serialize, Deserialize)]
+pub enum SanctionReason {
+    #[default]
+    Unspecified,
+    /// Abuse reports crossed a threshold, see [`AbuseLedger`](super::AbuseLedger).
+    Reported,
+    Spam,
+    Harassment,
+    Inappropriate,
+    Cheating,
+}
+
+/// A time-bounded restriction of a visitor, sent in `Sanctions`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct Sanction {
+    pub kind: SanctionKind,
+    pub visitor_id: VisitorId,
+    /// Realm to which the sanction applies, or `None` for every realm. Ignored by
+    /// `GlobalBan`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub realm_id: Option<RealmId>,
+    /// When the sanction is lifted, or `None` if never.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<NonZeroUnixMillis>,
+    #[serde(default, skip_serializing_if = "is_default")]
+    pub reason: SanctionReason,
+}
+
+impl Sanction {
+    pub fn is_active(&self, now: NonZeroUnixMillis) -> bool {
+        !self.expires.is_some_and(|expires| now >= expires)
+    }
+
+    /// Whether the sanction applies in `realm_id`.
+    pub fn applies_to(&self, realm_id: RealmId) -> bool {
+        self.kind == SanctionKind::GlobalBan || !self.realm_id.is_some_and(|r| r != realm_id)
+    }
+
+    /// Sanctions with the same key replace each other.
+    fn key(&self) -> (SanctionKind, Option<RealmId>) {
+        (self.kind, self.realm_id)
+    }
+}
+
+impl From<AbuseEscalation> for Sanction {
+    fn from(escalation: AbuseEscalation) -> Self {
+        let (kind, realm_id, visitor_id, until) = match escalation {
+            AbuseEscalation::Mute {
+                realm_id,
+                visitor_id,
+                until,
+            } => (SanctionKind::Mute, realm_id, visitor_id, until),
+            AbuseEscalation::Ban {
+                realm_id,
+                visitor_id,
+                until,
+            } => (SanctionKind::RealmBan, realm_id, visitor_id, until),
+        };
+        Self {
+            kind,
+            visitor_id,
+            realm_id: Some(realm_id),
+            expires: Some(until),
+            reason: SanctionReason::Reported,
+        }
+    }
+}
+
+/// Active sanctions by visitor, kept in sync by `Sanctions` updates, for checking
+/// each `SendChat` and join.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SanctionIndex {
+    sanctions: HashMap<VisitorId, Vec<Sanction>>,
+}
+
+impl SanctionIndex {
+    /// Adds a sanction, replacing any of the same visitor, kind and realm. An expired
+    /// sanction lifts the one it replaces.
+    pub fn insert(&mut self, sanction: Sanction) {
+        let sanctions = self.sanctions.entry(sanction.visitor_id).or_default();
+        sanctions.retain(|s| s.key() != sanction.key());
+        sanctions.push(sanction);
+    }
+
+    /// Applies a `Sanctions` update, ignoring other updates.
+    pub fn apply(&mut self, update: &PlasmaUpdateV1) {
+        if let PlasmaUpdateV1::Sanctions { sanctions } = update {
+            for &sanction in sanctions.iter() {
+                self.insert(sanction);
+            }
+        }
+    }
+
+    /// Active sanctions of a visitor in any realm.
+    pub fn get(
+        &self,
+        visitor_id: VisitorId,
+        now: NonZeroUnixMillis,
+    ) -> impl Iterator<Item = &Sanction> + '_ {
+        self.sanctions
+            .get(&visitor_id)
+            .into_iter()
+            .flatten()
+            .filter(move |s| s.is_active(now))
+    }
+
+    /// The most severe active sanction of `visitor_id` in `realm_id` that matches.
+    fn most_severe(
+        &self,
+        visitor_id: Option<VisitorId>,
+        realm_id: RealmId,
+        now: NonZeroUnixMillis,
+        matches: impl Fn(SanctionKind) -> bool,
+    ) -> Option<&Sanction> {
+        let visitor_id = visitor_id?;
+        self.get(visitor_id, now)
+            .filter(|s| matches(s.kind) && s.applies_to(realm_id))
+            .max_by_key(|s| s.kind)
+    }
+
+    /// The sanction that keeps a visitor from chatting in a realm, if any. A `Mute`
+    /// means the chat should be dropped without telling the sender.
+    pub fn check_chat(
+        &self,
+        visitor_id: Option<VisitorId>,
+        realm_id: RealmId,
+        now: NonZeroUnixMillis,
+    ) -> Option<&Sanction> {
+        self.most_severe(visitor_id, realm_id, now, |_| true)
+    }
+
+    /// The sanction that keeps a visitor from joining a realm, if any.
+    pub fn check_join(
+        &self,
+        visitor_id: Option<VisitorId>,
+        realm_id: RealmId,
+        now: NonZeroUnixMillis,
+    ) -> Option<&Sanction> {
+        self.most_severe(visitor_id, realm_id, now, SanctionKind::blocks_join)
+    }
+
+    /// Every active sanction, e.g. to send after `RegisterServer`, in a stable order.
+    pub fn active(&self, now: NonZeroUnixMillis) -> Vec<Sanction> {
+        let mut active: Vec<_> = self
+            .sanctions
+            .values()
+            .flatten()
+            .filter(|s| s.is_active(now))
+            .copied()
+            .collect();
+        active.sort_by_key(|s| (s.visitor_id, s.kind, s.realm_id));
+        active
+    }
+
+    /// Forgets sanctions that were lifted.
+    pub fn prune(&mut self, now: NonZeroUnixMillis) {
+        self.sanctions.retain(|_, sanctions| {
+            sanctions.retain(|s| s.is_active(now));
+            !sanctions.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plasma::at;
+    use crate::{
+        PlasmaUpdateV1, RealmId, RealmName, Sanction, SanctionIndex, SanctionKind, SanctionReason,
+        VisitorId,
+    };
+    use std::num::NonZeroU64;
+
+    fn visitor(n: u64) -> VisitorId {
+        VisitorId(NonZeroU64::new(n).unwrap())
+    }
+
+    fn sanction(kind: SanctionKind, realm_id: Option<RealmId>, expires: i64) -> Sanction {
+        Sanction {
+            kind,
+            visitor_id: visitor(1),
+            realm_id,
+            expires: Some(at(expires)),
+            reason: SanctionReason::Spam,
+        }
+    }
+
+    #[test]
+    fn chat_and_join() {
+        let public = RealmId::PublicDefault;
+        let named = RealmId::Named(RealmName::new("party"));
+        let mut index = SanctionIndex::default();
+        index.apply(&PlasmaUpdateV1::Sanctions {
+            sanctions: [
+                sanction(SanctionKind::Mute, None, 100),
+                sanction(SanctionKind::RealmBan, Some(named), 50),
+            ]
+            .into(),
+        });
+
+        let kind = |s: Option<&Sanction>| s.map(|s| s.kind);
+        assert_eq!(
+            kind(index.check_chat(Some(visitor(1)), public, at(0))),
+            Some(SanctionKind::Mute)
+        );
+        assert_eq!(
+            kind(index.check_chat(Some(visitor(1)), named, at(0))),
+            Some(SanctionKind::RealmBan)
+        );
+        assert_eq!(
+            kind(index.check_join(Some(visitor(1)), public, at(0))),
+            None
+        );
+        assert_eq!(
+            kind(index.check_join(Some(visitor(1)), named, at(0))),
+            Some(SanctionKind::RealmBan)
+        );
+        assert_eq!(
+            kind(index.check_join(Some(visitor(1)), named, at(50))),
+            None
+        );
+        assert_eq!(kind(index.check_chat(None, public, at(0))), None);
+        assert_eq!(
+            kind(index.check_chat(Some(visitor(2)), public, at(0))),
+            None
+        );
+
+        // A global ban ignores its realm.
+        index.insert(sanction(SanctionKind::GlobalBan, Some(named), 200));
+        assert_eq!(
+            kind(index.check_join(Some(visitor(1)), public, at(0))),
+            Some(SanctionKind::GlobalBan)
+        );
+    }
+
+    #[test]
+    fn replace_and_prune() {
+        let mut index = SanctionIndex::default();
+        index.insert(sanction(SanctionKind::ChatBan, None, 100));
+        assert_eq!(index.active(at(0)).len(), 1);
+
+        // Lifted early by an expired sanction with the same key.
+        index.insert(sanction(SanctionKind::ChatBan, None, 0));
+        assert!(index.active(at(0)).is_empty());
+
+        index.insert(sanction(SanctionKind::Mute, None, 10));
+        index.prune(at(10));
+        assert_eq!(index, SanctionIndex::default());
+    }
+}
@@ -0,0 +1,1125 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::{
+    AuthenticationFailure, ChatPolicy, ClaimUpdateDto, HeartbeatBuilder, HeartbeatTracker,
+    InvalidHeartbeat, LeaderboardRank, Leaderboards, Liveness, NegotiatedProtocol, PendingRequests,
+    PlasmaHandshake, PlasmaRequest, PlasmaRequestV1, PlasmaUpdate, PlasmaUpdateV1,
+    RoleTransitionContext, SanctionIndex, ServerRole, ServerUseTopology, Snippet,
+};
+use crate::{
+    ArenaId, ArenaToken, ClientHash, LeaderboardScoreDto, NonZeroUnixMillis, PeriodId, PlayerAlias,
+    PlayerId, RealmId, RequestId, ServerId, SessionToken, TeamName, TeamToken, UnixTime,
+};
+use std::collections::HashMap;
+use std::mem;
+
+/// An `AuthenticatePlayer` that hasn't been answered by `Player` or `Claims` yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingAuthentication {
+    pub arena_token: ArenaToken,
+    pub session_token: SessionToken,
+    pub date_requested: NonZeroUnixMillis,
+}
+
+/// A `ReserveTeamName` that hasn't been answered by `TeamName` or `TeamNameUnavailable` yet.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PendingTeamName {
+    pub team_name: TeamName,
+    pub team_token: Option<TeamToken>,
+    pub expires: Option<NonZeroUnixMillis>,
+    pub date_requested: NonZeroUnixMillis,
+}
+
+/// Something the game server should react to, returned by [`PlasmaSession::receive`].
+#[derive(Clone, Debug)]
+pub enum PlasmaSessionEvent {
+    /// A pending `AuthenticatePlayer` was answered with `Player` or `Claims`.
+    Authenticated {
+        arena_id: ArenaId,
+        player_id: PlayerId,
+        pending: PendingAuthentication,
+        update: PlasmaUpdateV1,
+    },
+    /// A pending `AuthenticatePlayer` was answered with `AuthenticationFailed`.
+    AuthenticationFailed {
+        arena_id: ArenaId,
+        player_id: PlayerId,
+        pending: PendingAuthentication,
+        reason: AuthenticationFailure,
+    },
+    /// A pending `AuthenticatePlayer` wasn't answered in time.
+    AuthenticationTimeout {
+        arena_id: ArenaId,
+        player_id: PlayerId,
+        pending: PendingAuthentication,
+    },
+    /// A pending `ReserveTeamName` was answered with `TeamName`.
+    TeamNameReserved {
+        arena_id: ArenaId,
+        player_id: PlayerId,
+        pending: PendingTeamName,
+        team_token: TeamToken,
+    },
+    /// A pending `ReserveTeamName` was answered with `TeamNameUnavailable`.
+    TeamNameUnavailable {
+        arena_id: ArenaId,
+        player_id: PlayerId,
+        pending: PendingTeamName,
+    },
+    /// A pending `ReserveTeamName` wasn't answered in time.
+    TeamNameTimeout {
+        arena_id: ArenaId,
+        player_id: PlayerId,
+        pending: PendingTeamName,
+    },
+    /// Claims for a player that wasn't pending authentication (e.g. after `Heartbeat`).
+    Claims(ClaimUpdateDto),
+    /// The `ServerRole` changed, either by Plasma or by falling back to `Unlisted`
+    /// after not hearing from Plasma, if [`ServerRole::transition`] allows it.
+    Role { old: ServerRole, new: ServerRole },
+    /// The set of servers (or their arenas) changed.
+    Topology,
+    /// The leaderboard for the realm and period changed.
+    Leaderboard {
+        realm_id: RealmId,
+        period_id: PeriodId,
+    },
+    /// A request that expects a reply wasn't answered in time. Only reported for
+    /// requests from [`PlasmaSession::take_versioned_requests`], and never for
+    /// `AuthenticatePlayer` or `ReserveTeamName`.
+    RequestTimeout {
+        request_id: RequestId,
+        request: PlasmaRequestV1,
+    },
+    /// Not tracked by the session, the game server should handle it.
+    Other(PlasmaUpdateV1),
+}
+
+/// Transport-agnostic (sans-IO) state machine for a game server's connection to Plasma.
+///
+/// Feed it every `PlasmaUpdateV1` via [`Self::receive`], call [`Self::poll`] periodically,
+/// and send whatever [`Self::take_requests`] returns.
+#[derive(Debug)]
+pub struct PlasmaSession {
+    date_started: NonZeroUnixMillis,
+    heartbeat: HeartbeatTracker,
+    heartbeat_builder: HeartbeatBuilder,
+    /// Offered in `RegisterServer`.
+    handshake: PlasmaHandshake,
+    /// Answered in `Handshake`, V1 until then.
+    protocol: NegotiatedProtocol,
+    next_request_id: RequestId,
+    /// Requests sent with a `RequestId`, awaiting a correlated update (or, under V1, an
+    /// update that answers them).
+    pending_requests: PendingRequests<PlasmaRequestV1>,
+    role: ServerRole,
+    topology: HashMap<ServerId, ServerUseTopology>,
+    /// Sequence number of `topology`, or 0 if `TopologyDelta`s can't be applied.
+    topology_sequence: u64,
+    /// A `RequestTopology` was sent and not yet answered.
+    topology_requested: bool,
+    leaderboards: HashMap<(RealmId, PeriodId), Box<[LeaderboardScoreDto]>>,
+    leaderboard_percentiles: HashMap<(RealmId, PeriodId), Box<[u32]>>,
+    pending_authentications: HashMap<(ArenaId, PlayerId), PendingAuthentication>,
+    pending_team_names: HashMap<(ArenaId, PlayerId), PendingTeamName>,
+    snippets: Box<[Snippet]>,
+    chat_policies: HashMap<RealmId, ChatPolicy>,
+    sanctions: SanctionIndex,
+    requests: Vec<PlasmaRequestV1>,
+    unregistered: bool,
+}
+
+impl PlasmaSession {
+    /// Milliseconds before an unanswered `AuthenticatePlayer` is forgotten.
+    pub const AUTHENTICATION_TIMEOUT: i64 = 30 * 1000;
+    /// Milliseconds before an unanswered `ReserveTeamName` is forgotten.
+    pub const TEAM_NAME_TIMEOUT: i64 = 10 * 1000;
+
+    pub fn new(date_started: NonZeroUnixMillis, client_hash: ClientHash) -> Self {
+        Self {
+            date_started,
+            heartbeat: HeartbeatTracker::new(),
+            heartbeat_builder: HeartbeatBuilder::new(client_hash),
+            handshake: PlasmaHandshake::default(),
+            protocol: NegotiatedProtocol::default(),
+            next_request_id: RequestId::default(),
+            pending_requests: PendingRequests::new(),
+            role: ServerRole::default(),
+            topology: Default::default(),
+            topology_sequence: 0,
+            topology_requested: false,
+            leaderboards: Default::default(),
+            leaderboard_percentiles: Default::default(),
+            pending_authentications: Default::default(),
+            pending_team_names: Default::default(),
+            snippets: Default::default(),
+            chat_policies: Default::default(),
+            sanctions: Default::default(),
+            requests: Default::default(),
+            unregistered: false,
+        }
+    }
+
+    /// Call when the (web socket) connection to Plasma is (re)opened. Queues `RegisterServer`
+    /// and re-sends any pending `AuthenticatePlayer` and `ReserveTeamName`.
+    pub fn connected(&mut self, now: NonZeroUnixMillis) {
+        if self.unregistered {
+            return;
+        }
+        // Stale heartbeats are dropped and authentications are re-sent below.
+        let queued = mem::take(&mut self.requests);
+        self.requests.push(PlasmaRequestV1::RegisterServer {
+            date_started: Some(self.date_started),
+            handshake: Some(self.handshake.clone()),
+        });
+        // Plasma may have been replaced by an older version.
+        self.protocol = NegotiatedProtocol::default();
+        self.pending_requests.clear();
+        for ((arena_id, player_id), pending) in &mut self.pending_authentications {
+            pending.date_requested = now;
+            self.requests.push(PlasmaRequestV1::AuthenticatePlayer {
+                arena_id: *arena_id,
+                arena_token: pending.arena_token,
+                player_id: *player_id,
+                session_token: pending.session_token,
+            });
+        }
+        for ((arena_id, player_id), pending) in &mut self.pending_team_names {
+            pending.date_requested = now;
+            self.requests.push(PlasmaRequestV1::ReserveTeamName {
+                arena_id: *arena_id,
+                expires: pending.expires,
+                player_id: *player_id,
+                team_name: pending.team_name,
+                team_token: pending.team_token,
+            });
+        }
+        let pending_team_names = &self.pending_team_names;
+        self.requests.extend(queued.into_iter().filter(|request| {
+            !matches!(
+                request,
+                PlasmaRequestV1::AuthenticatePlayer { .. }
+                    | PlasmaRequestV1::Heartbeat { .. }
+                    | PlasmaRequestV1::RegisterServer { .. }
+            ) && !matches!(
+                request,
+                PlasmaRequestV1::ReserveTeamName { arena_id, player_id, .. }
+                    if pending_team_names.contains_key(&(*arena_id, *player_id))
+            )
+        }));
+        // Heartbeat immediately after registering.
+        self.heartbeat.reset();
+        self.heartbeat_builder.connected();
+    }
+
+    /// Returns true if it is time to send a `Heartbeat` (via [`Self::heartbeat`]).
+    pub fn is_heartbeat_due(&self, now: NonZeroUnixMillis) -> bool {
+        !self.unregistered && self.heartbeat.is_due(now)
+    }
+
+    /// Queues a `Heartbeat` built from the live arena state registered with
+    /// [`Self::heartbeat_builder_mut`].
+    pub fn heartbeat(&mut self, now: NonZeroUnixMillis) -> Result<(), InvalidHeartbeat> {
+        if self.unregistered {
+            return Ok(());
+        }
+        let heartbeat = self.heartbeat_builder.build()?;
+        self.heartbeat.sent(now);
+        self.requests.push(heartbeat);
+        Ok(())
+    }
+
+    /// Queues `AuthenticatePlayer` and tracks it until Plasma answers.
+    pub fn authenticate_player(
+        &mut self,
+        arena_id: ArenaId,
+        arena_token: ArenaToken,
+        player_id: PlayerId,
+        session_token: SessionToken,
+        now: NonZeroUnixMillis,
+    ) {
+        if self.unregistered {
+            return;
+        }
+        self.pending_authentications.insert(
+            (arena_id, player_id),
+            PendingAuthentication {
+                arena_token,
+                session_token,
+                date_requested: now,
+            },
+        );
+        self.requests.push(PlasmaRequestV1::AuthenticatePlayer {
+            arena_id,
+            arena_token,
+            player_id,
+            session_token,
+        });
+    }
+
+    /// Queues `ReserveTeamName` and tracks it until Plasma answers, unless it releases the
+    /// team name (`expires` in the past), which Plasma doesn't answer.
+    pub fn reserve_team_name(
+        &mut self,
+        arena_id: ArenaId,
+        player_id: PlayerId,
+        team_name: TeamName,
+        team_token: Option<TeamToken>,
+        expires: Option<NonZeroUnixMillis>,
+        now: NonZeroUnixMillis,
+    ) {
+        if self.unregistered {
+            return;
+        }
+        if expires.is_some_and(|expires| expires <= now) {
+            self.pending_team_names.remove(&(arena_id, player_id));
+        } else {
+            self.pending_team_names.insert(
+                (arena_id, player_id),
+                PendingTeamName {
+                    team_name,
+                    team_token,
+                    expires,
+                    date_requested: now,
+                },
+            );
+        }
+        self.requests.push(PlasmaRequestV1::ReserveTeamName {
+            arena_id,
+            expires,
+            player_id,
+            team_name,
+            team_token,
+        });
+    }
+
+    /// Queues an arbitrary request, e.g. `SendChat`.
+    pub fn send(&mut self, request: PlasmaRequestV1) {
+        if !self.unregistered {
+            self.requests.push(request);
+        }
+    }
+
+    /// Queues `UnregisterServer`, after which no further requests are queued.
+    pub fn unregister(&mut self) {
+        if !self.unregistered {
+            self.requests.push(PlasmaRequestV1::UnregisterServer);
+            self.unregistered = true;
+            self.pending_authentications.clear();
+            self.pending_team_names.clear();
+            self.pending_requests.clear();
+        }
+    }
+
+    /// Expires requests that Plasma never answered and falls back to
+    /// `ServerRole::Unlisted` if Plasma has likely considered the server dead.
+    pub fn poll(&mut self, now: NonZeroUnixMillis) -> Vec<PlasmaSessionEvent> {
+        let mut events = Vec::new();
+        self.pending_authentications
+            .retain(|&(arena_id, player_id), pending| {
+                let retain =
+                    now.to_i64() - pending.date_requested.to_i64() < Self::AUTHENTICATION_TIMEOUT;
+                if !retain {
+                    events.push(PlasmaSessionEvent::AuthenticationTimeout {
+                        arena_id,
+                        player_id,
+                        pending: pending.clone(),
+                    });
+                }
+                retain
+            });
+        self.pending_team_names
+            .retain(|&(arena_id, player_id), pending| {
+                let retain =
+                    now.to_i64() - pending.date_requested.to_i64() < Self::TEAM_NAME_TIMEOUT;
+                if !retain {
+                    events.push(PlasmaSessionEvent::TeamNameTimeout {
+                        arena_id,
+                        player_id,
+                        pending: pending.clone(),
+                    });
+                }
+                retain
+            });
+        events.extend(self.pending_requests.expire(now).into_iter().map(
+            |(request_id, pending)| PlasmaSessionEvent::RequestTimeout {
+                request_id,
+                request: pending.context,
+            },
+        ));
+        if self.heartbeat.should_reset_role(now) && !self.role.is_unlisted() {
+            // Unless e.g. `Deleting`, or `Terminating` with players.
+            let roles = HashMap::new();
+            let context = RoleTransitionContext {
+                now,
+                player_count: self.heartbeat_builder.player_count(),
+                server_number: None,
+                roles: &roles,
+            };
+            if ServerRole::transition(self.role, ServerRole::Unlisted, &context).is_ok() {
+                let old = mem::replace(&mut self.role, ServerRole::Unlisted);
+                events.push(PlasmaSessionEvent::Role {
+                    old,
+                    new: self.role,
+                });
+            }
+        }
+        events
+    }
+
+    /// Requests that should be sent to Plasma, in order.
+    pub fn take_requests(&mut self) -> Vec<PlasmaRequestV1> {
+        mem::take(&mut self.requests)
+    }
+
+    /// Like [`Self::take_requests`], but wrapped in the negotiated envelope and given
+    /// request IDs if it carries them. Replies are awaited either way.
+    pub fn take_versioned_requests(&mut self, now: NonZeroUnixMillis) -> Vec<PlasmaRequest> {
+        let version = self.protocol.version;
+        mem::take(&mut self.requests)
+            .into_iter()
+            .map(|request| {
+                let request_id = self.next_request_id;
+                self.next_request_id = request_id.next();
+                // Authentications and team names are tracked by `pending_authentications`
+                // and `pending_team_names`.
+                let tracked = !matches!(
+                    request,
+                    PlasmaRequestV1::AuthenticatePlayer { .. }
+                        | PlasmaRequestV1::ReserveTeamName { .. }
+                );
+                if let Some(timeout) = request.reply_timeout().filter(|_| tracked) {
+                    self.pending_requests
+                        .insert(request_id, request.clone(), now, timeout);
+                }
+                PlasmaRequest::new(version, request_id, request)
+            })
+            .collect()
+    }
+
+    /// Like [`Self::receive`], for updates of any version.
+    pub fn receive_versioned(
+        &mut self,
+        update: PlasmaUpdate,
+        now: NonZeroUnixMillis,
+    ) -> Vec<PlasmaSessionEvent> {
+        update
+            .into_v2()
+            .into_vec()
+            .into_iter()
+            .flat_map(|update| {
+                if let Some(correlation_id) = update.correlation_id {
+                    self.pending_requests.resolve(correlation_id);
+                } else {
+                    self.pending_requests
+                        .resolve_oldest(|request| request.is_answered_by(&update.update));
+                }
+                self.receive(update.update, now)
+            })
+            .collect()
+    }
+
+    /// Processes an update from Plasma.
+    pub fn receive(
+        &mut self,
+        update: PlasmaUpdateV1,
+        now: NonZeroUnixMillis,
+    ) -> Vec<PlasmaSessionEvent> {
+        let mut events = Vec::new();
+        match update {
+            PlasmaUpdateV1::Heartbeat {} => {
+                if self.heartbeat.acknowledged(now).is_some() {
+                    self.heartbeat_builder.acknowledged();
+                }
+            }
+            PlasmaUpdateV1::Claims { claims } => {
+                let mut others = Vec::new();
+                for claim in claims.into_vec() {
+                    if let Some(pending) = self
+                        .pending_authentications
+                        .remove(&(claim.arena_id, claim.player_id))
+                    {
+                        events.push(PlasmaSessionEvent::Authenticated {
+                            arena_id: claim.arena_id,
+                            player_id: claim.player_id,
+                            pending,
+                            update: PlasmaUpdateV1::Claims {
+                                claims: vec![claim].into_boxed_slice(),
+                            },
+                        });
+                    } else {
+                        others.push(PlasmaSessionEvent::Claims(claim));
+                    }
+                }
+                events.extend(others);
+            }
+            PlasmaUpdateV1::Leaderboard {
+                period_id,
+                realm_id,
+                scores,
+                percentiles,
+            } => {
+                self.leaderboards.insert((realm_id, period_id), scores);
+                self.leaderboard_percentiles
+                    .insert((realm_id, period_id), percentiles.unwrap_or_default());
+                events.push(PlasmaSessionEvent::Leaderboard {
+                    realm_id,
+                    period_id,
+                });
+            }
+            PlasmaUpdateV1::LeaderboardDiff {
+                period_id,
+                realm_id,
+                diff,
+                percentiles,
+            } => {
+                let scores = self.leaderboards.entry((realm_id, period_id)).or_default();
+                let mut applied = mem::take(scores).into_vec();
+                diff.apply(&mut applied);
+                *scores = applied.into_boxed_slice();
+                self.leaderboard_percentiles
+                    .insert((realm_id, period_id), percentiles.unwrap_or_default());
+                events.push(PlasmaSessionEvent::Leaderboard {
+                    realm_id,
+                    period_id,
+                });
+            }
+            PlasmaUpdateV1::Player {
+                arena_id,
+                player_id,
+                session_token,
+                ..
+            } if self
+                .pending_authentications
+                .get(&(arena_id, player_id))
+                .map(|p| p.session_token == session_token)
+                .unwrap_or(false) =>
+            {
+                let pending = self
+                    .pending_authentications
+                    .remove(&(arena_id, player_id))
+                    .unwrap();
+                events.push(PlasmaSessionEvent::Authenticated {
+                    arena_id,
+                    player_id,
+                    pending,
+                    update,
+                });
+            }
+            PlasmaUpdateV1::AuthenticationFailed {
+                arena_id,
+                player_id,
+                reason,
+                session_token,
+            } if self
+                .pending_authentications
+                .get(&(arena_id, player_id))
+                .map(|p| p.session_token == session_token)
+                .unwrap_or(false) =>
+            {
+                let pending = self
+                    .pending_authentications
+                    .remove(&(arena_id, player_id))
+                    .unwrap();
+                events.push(PlasmaSessionEvent::AuthenticationFailed {
+                    arena_id,
+                    player_id,
+                    pending,
+                    reason,
+                });
+            }
+            PlasmaUpdateV1::TeamName {
+                arena_id,
+                player_id,
+                team_name,
+                team_token,
+            } if self
+                .pending_team_names
+                .get(&(arena_id, player_id))
+                .is_some_and(|p| p.team_name == team_name) =>
+            {
+                let pending = self
+                    .pending_team_names
+                    .remove(&(arena_id, player_id))
+                    .unwrap();
+                events.push(PlasmaSessionEvent::TeamNameReserved {
+                    arena_id,
+                    player_id,
+                    pending,
+                    team_token,
+                });
+            }
+            PlasmaUpdateV1::TeamNameUnavailable {
+                arena_id,
+                player_id,
+                team_name,
+            } if self
+                .pending_team_names
+                .get(&(arena_id, player_id))
+                .is_some_and(|p| p.team_name == team_name) =>
+            {
+                let pending = self
+                    .pending_team_names
+                    .remove(&(arena_id, player_id))
+                    .unwrap();
+                events.push(PlasmaSessionEvent::TeamNameUnavailable {
+                    arena_id,
+                    player_id,
+                    pending,
+                });
+            }
+            PlasmaUpdateV1::Handshake { protocol } => {
+                self.protocol = protocol;
+            }
+            PlasmaUpdateV1::Role { role } => {
+                let old = mem::replace(&mut self.role, role);
+                if old != role {
+                    events.push(PlasmaSessionEvent::Role { old, new: role });
+                }
+            }
+            PlasmaUpdateV1::ChatPolicy { realm_id, policy } => {
+                self.chat_policies.insert(realm_id, policy);
+                events.push(PlasmaSessionEvent::Other(PlasmaUpdateV1::ChatPolicy {
+                    realm_id,
+                    policy,
+                }));
+            }
+            PlasmaUpdateV1::Sanctions { sanctions } => {
+                let update = PlasmaUpdateV1::Sanctions { sanctions };
+                self.sanctions.apply(&update);
+                events.push(PlasmaSessionEvent::Other(update));
+            }
+            PlasmaUpdateV1::Snippets { snippets } => {
+                self.snippets = snippets.clone();
+                events.push(PlasmaSessionEvent::Other(PlasmaUpdateV1::Snippets {
+                    snippets,
+                }));
+            }
+            PlasmaUpdateV1::Topology { servers, sequence } => {
+                self.topology_sequence = sequence;
+                self.topology_requested = false;
+                if self.topology != servers {
+                    self.topology = servers;
+                    events.push(PlasmaSessionEvent::Topology);
+                }
+            }
+            PlasmaUpdateV1::TopologyDelta { delta } => {
+                match delta.apply(&mut self.topology, &mut self.topology_sequence) {
+                    Ok(()) => {
+                        if !delta.is_empty() {
+                            events.push(PlasmaSessionEvent::Topology);
+                        }
+                    }
+                    Err(_) => {
+                        // Deltas are useless until resynchronized.
+                        self.topology_sequence = 0;
+                        if !mem::replace(&mut self.topology_requested, true) {
+                            self.requests.push(PlasmaRequestV1::RequestTopology {});
+                        }
+                    }
+                }
+            }
+            update => events.push(PlasmaSessionEvent::Other(update)),
+        }
+        events
+    }
+
+    pub fn date_started(&self) -> NonZeroUnixMillis {
+        self.date_started
+    }
+
+    pub fn heartbeat_builder(&self) -> &HeartbeatBuilder {
+        &self.heartbeat_builder
+    }
+
+    /// Register arena state (players, settings, claims, etc.) for the next heartbeat.
+    pub fn heartbeat_builder_mut(&mut self) -> &mut HeartbeatBuilder {
+        &mut self.heartbeat_builder
+    }
+
+    pub fn liveness(&self, now: NonZeroUnixMillis) -> Liveness {
+        self.heartbeat.liveness(now)
+    }
+
+    /// Estimated milliseconds since Plasma last heard from the server.
+    pub fn staleness(&self, now: NonZeroUnixMillis) -> Option<i64> {
+        self.heartbeat.staleness(now)
+    }
+
+    pub fn is_unregistered(&self) -> bool {
+        self.unregistered
+    }
+
+    pub fn role(&self) -> ServerRole {
+        self.role
+    }
+
+    /// Protocol negotiated with Plasma since the last [`Self::connected`].
+    pub fn protocol(&self) -> &NegotiatedProtocol {
+        &self.protocol
+    }
+
+    pub fn topology(&self) -> &HashMap<ServerId, ServerUseTopology> {
+        &self.topology
+    }
+
+    /// Sequence number of [`Self::topology`], or 0 if unknown.
+    pub fn topology_sequence(&self) -> u64 {
+        self.topology_sequence
+    }
+
+    pub fn leaderboard(&self, realm_id: RealmId, period_id: PeriodId) -> &[LeaderboardScoreDto] {
+        self.leaderboards
+            .get(&(realm_id, period_id))
+            .map(|scores| &**scores)
+            .unwrap_or_default()
+    }
+
+    pub fn leaderboards(
+        &self,
+    ) -> impl Iterator<Item = ((RealmId, PeriodId), &[LeaderboardScoreDto])> {
+        self.leaderboards.iter().map(|(k, v)| (*k, &**v))
+    }
+
+    /// Rank of `alias` with its best `score` of the period, see
+    /// [`LeaderboardRank::estimate`]. Assumes Plasma's leaderboards are
+    /// [`Leaderboards::CAPACITY`] long.
+    pub fn leaderboard_rank(
+        &self,
+        realm_id: RealmId,
+        period_id: PeriodId,
+        alias: PlayerAlias,
+        score: u32,
+    ) -> Option<LeaderboardRank> {
+        let percentiles = self
+            .leaderboard_percentiles
+            .get(&(realm_id, period_id))
+            .map(|percentiles| &**percentiles)
+            .unwrap_or_default();
+        LeaderboardRank::estimate(
+            self.leaderboard(realm_id, period_id),
+            percentiles,
+            Leaderboards::CAPACITY,
+            alias,
+            score,
+        )
+    }
+
+    /// Up to `radius` entries just above and below `alias`, including its own.
+    pub fn leaderboard_near(
+        &self,
+        realm_id: RealmId,
+        period_id: PeriodId,
+        alias: PlayerAlias,
+        score: u32,
+        radius: usize,
+    ) -> &[LeaderboardScoreDto] {
+        super::leaderboard::near(self.leaderboard(realm_id, period_id), alias, score, radius)
+    }
+
+    pub fn pending_authentications(&self) -> &HashMap<(ArenaId, PlayerId), PendingAuthentication> {
+        &self.pending_authentications
+    }
+
+    pub fn pending_team_names(&self) -> &HashMap<(ArenaId, PlayerId), PendingTeamName> {
+        &self.pending_team_names
+    }
+
+    pub fn snippets(&self) -> &[Snippet] {
+        &self.snippets
+    }
+
+    /// Sanctions from Plasma, to check on every `SendChat` and join.
+    pub fn sanctions(&self) -> &SanctionIndex {
+        &self.sanctions
+    }
+
+    /// The latest `ChatPolicy` of a realm, which may have expired since.
+    pub fn chat_policy(&self, realm_id: RealmId) -> ChatPolicy {
+        self.chat_policies
+            .get(&realm_id)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plasma::at;
+    use crate::{
+        ArenaId, ArenaToken, AuthenticationFailure, ClaimSubset, ClaimUpdateDto, PlasmaHandshake,
+        PlasmaRequest, PlasmaRequestV1, PlasmaSession, PlasmaSessionEvent, PlasmaUpdate,
+        PlasmaUpdateV1, PlasmaUpdateV2, PlayerId, ProtocolVersion, RequestId, ServerRole,
+        SessionToken, TeamName, TeamToken, TopologyDelta, VisitorId,
+    };
+    use std::num::{NonZeroU16, NonZeroU32, NonZeroU64};
+
+    #[test]
+    fn lifecycle() {
+        let mut session = PlasmaSession::new(at(0), 0);
+        session.connected(at(0));
+        assert!(matches!(
+            &session.take_requests()[..],
+            [PlasmaRequestV1::RegisterServer { .. }]
+        ));
+
+        assert!(session.is_heartbeat_due(at(0)));
+        session.heartbeat(at(0)).unwrap();
+        assert!(!session.is_heartbeat_due(at(59)));
+        assert!(session.is_heartbeat_due(at(60)));
+
+        let events = session.receive(
+            PlasmaUpdateV1::Role {
+                role: ServerRole::Public,
+            },
+            at(1),
+        );
+        assert!(matches!(events[..], [PlasmaSessionEvent::Role { .. }]));
+        assert_eq!(session.role(), ServerRole::Public);
+
+        // Plasma stops acknowledging heartbeats.
+        session.heartbeat(at(60)).unwrap();
+        session.heartbeat(at(120)).unwrap();
+        assert!(session.poll(at(179)).is_empty());
+        assert!(matches!(
+            session.poll(at(180))[..],
+            [PlasmaSessionEvent::Role {
+                new: ServerRole::Unlisted,
+                ..
+            }]
+        ));
+
+        session.unregister();
+        let requests = session.take_requests();
+        assert!(matches!(
+            requests.last(),
+            Some(PlasmaRequestV1::UnregisterServer)
+        ));
+        assert!(!session.is_heartbeat_due(at(120)));
+    }
+
+    /// The role after Plasma stops acknowledging heartbeats, starting with `role` and
+    /// `player_count` players.
+    fn fallback(role: ServerRole, player_count: u16) -> ServerRole {
+        let mut session = PlasmaSession::new(at(0), 0);
+        session.connected(at(0));
+        for n in 1..=player_count {
+            let player_id = PlayerId(NonZeroU16::new(n).unwrap());
+            session
+                .heartbeat_builder_mut()
+                .player_joined(ArenaId::default(), player_id);
+        }
+        session.heartbeat(at(0)).unwrap();
+        session.receive(PlasmaUpdateV1::Role { role }, at(1));
+        session.heartbeat(at(60)).unwrap();
+        session.heartbeat(at(120)).unwrap();
+        session.poll(at(180));
+        session.role()
+    }
+
+    #[test]
+    fn role_fallback() {
+        let redirect = None;
+        assert_eq!(fallback(ServerRole::Deleting, 0), ServerRole::Deleting);
+
+        let terminating = ServerRole::Terminating { redirect };
+        assert_eq!(fallback(terminating, 1), terminating);
+        assert_eq!(fallback(terminating, 0), ServerRole::Unlisted);
+
+        let failed = |seconds| ServerRole::Failed {
+            date_failed: at(seconds),
+            diagnostic: None,
+            redirect,
+        };
+        assert_eq!(fallback(failed(0), 0), failed(0));
+        let day = ServerRole::FAILED_MINIMUM / 1000;
+        assert_eq!(fallback(failed(-day), 0), ServerRole::Unlisted);
+    }
+
+    #[test]
+    fn settings_after_reconnect() {
+        let arena_id = ArenaId::default();
+        let mut session = PlasmaSession::new(at(0), 0);
+        session.connected(at(0));
+        session
+            .heartbeat_builder_mut()
+            .settings_changed(arena_id, serde_json::json!({"max_players": 10}));
+        let settings = |session: &mut PlasmaSession, seconds| {
+            session.heartbeat(at(seconds)).unwrap();
+            let requests = session.take_requests();
+            let Some(PlasmaRequestV1::Heartbeat { realms, .. }) = requests.last() else {
+                panic!("no heartbeat");
+            };
+            realms[&arena_id.realm_id].scenes[&arena_id.scene_id]
+                .settings
+                .is_some()
+        };
+        assert!(settings(&mut session, 0));
+        session.receive(PlasmaUpdateV1::Heartbeat {}, at(1));
+        assert!(!settings(&mut session, 60));
+        session.receive(PlasmaUpdateV1::Heartbeat {}, at(61));
+
+        // Plasma may have been replaced, so the acknowledged settings are sent again.
+        session.connected(at(62));
+        assert!(settings(&mut session, 62));
+    }
+
+    #[test]
+    fn authentication() {
+        let arena_id = ArenaId::default();
+        let player_id = PlayerId(NonZeroU16::new(1).unwrap());
+        let mut session = PlasmaSession::new(at(0), 0);
+        session.connected(at(0));
+        session.authenticate_player(
+            arena_id,
+            ArenaToken(NonZeroU32::new(1).unwrap()),
+            player_id,
+            SessionToken(NonZeroU64::new(1).unwrap()),
+            at(0),
+        );
+        assert_eq!(session.pending_authentications().len(), 1);
+
+        let events = session.receive(
+            PlasmaUpdateV1::Claims {
+                claims: vec![ClaimUpdateDto {
+                    arena_id,
+                    claims: ClaimSubset::default(),
+                    player_id,
+                    visitor_id: VisitorId(NonZeroU64::new(1).unwrap()),
+                }]
+                .into_boxed_slice(),
+            },
+            at(1),
+        );
+        assert!(matches!(
+            events[..],
+            [PlasmaSessionEvent::Authenticated { .. }]
+        ));
+        assert!(session.pending_authentications().is_empty());
+
+        session.authenticate_player(
+            arena_id,
+            ArenaToken(NonZeroU32::new(1).unwrap()),
+            player_id,
+            SessionToken(NonZeroU64::new(2).unwrap()),
+            at(2),
+        );
+        assert!(session.poll(at(10)).is_empty());
+        assert!(matches!(
+            session.poll(at(40))[..],
+            [PlasmaSessionEvent::AuthenticationTimeout { .. }]
+        ));
+
+        let session_token = SessionToken(NonZeroU64::new(3).unwrap());
+        session.authenticate_player(
+            arena_id,
+            ArenaToken(NonZeroU32::new(1).unwrap()),
+            player_id,
+            session_token,
+            at(50),
+        );
+        let events = session.receive(
+            PlasmaUpdateV1::AuthenticationFailed {
+                arena_id,
+                player_id,
+                reason: AuthenticationFailure::Banned,
+                session_token,
+            },
+            at(51),
+        );
+        assert!(matches!(
+            events[..],
+            [PlasmaSessionEvent::AuthenticationFailed {
+                reason: AuthenticationFailure::Banned,
+                ..
+            }]
+        ));
+        assert!(session.pending_authentications().is_empty());
+    }
+
+    #[test]
+    fn team_names() {
+        let arena_id = ArenaId::default();
+        let player = |n| PlayerId(NonZeroU16::new(n).unwrap());
+        let team_name = TeamName::new_unsanitized("ABC");
+        let team_token = TeamToken(NonZeroU16::new(7).unwrap());
+        let mut session = PlasmaSession::new(at(0), 0);
+        session.connected(at(0));
+        for n in 1..=3 {
+            session.reserve_team_name(arena_id, player(n), team_name, None, None, at(0));
+        }
+        // Releasing isn't answered.
+        session.reserve_team_name(arena_id, player(4), team_name, None, Some(at(0)), at(0));
+        assert_eq!(session.pending_team_names().len(), 3);
+        assert_eq!(session.take_requests().len(), 5);
+
+        // Replies are correlated without request IDs.
+        let events = session.receive(
+            PlasmaUpdateV1::TeamName {
+                arena_id,
+                player_id: player(1),
+                team_name,
+                team_token,
+            },
+            at(1),
+        );
+        assert!(matches!(
+            &events[..],
+            [PlasmaSessionEvent::TeamNameReserved { player_id, team_token: t, .. }]
+                if *player_id == player(1) && *t == team_token
+        ));
+        let events = session.receive(
+            PlasmaUpdateV1::TeamNameUnavailable {
+                arena_id,
+                player_id: player(2),
+                team_name,
+            },
+            at(1),
+        );
+        assert!(matches!(
+            &events[..],
+            [PlasmaSessionEvent::TeamNameUnavailable { player_id, pending, .. }]
+                if *player_id == player(2) && pending.team_name == team_name
+        ));
+
+        // Reconnecting re-sends what is still pending, once.
+        session.connected(at(2));
+        let requests = session.take_requests();
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|r| matches!(r, PlasmaRequestV1::ReserveTeamName { .. }))
+                .count(),
+            1
+        );
+        assert!(session.poll(at(11)).is_empty());
+        assert!(matches!(
+            &session.poll(at(12))[..],
+            [PlasmaSessionEvent::TeamNameTimeout { player_id, .. }] if *player_id == player(3)
+        ));
+        assert!(session.pending_team_names().is_empty());
+    }
+
+    #[test]
+    fn topology_delta() {
+        let mut session = PlasmaSession::new(at(0), 0);
+        session.connected(at(0));
+        session.take_requests();
+        let delta = |sequence| PlasmaUpdateV1::TopologyDelta {
+            delta: TopologyDelta {
+                sequence,
+                ..Default::default()
+            },
+        };
+
+        // No base topology yet, so resynchronize once.
+        assert!(session.receive(delta(1), at(1)).is_empty());
+        assert!(session.receive(delta(2), at(1)).is_empty());
+        assert!(matches!(
+            &session.take_requests()[..],
+            [PlasmaRequestV1::RequestTopology {}]
+        ));
+
+        session.receive(
+            PlasmaUpdateV1::Topology {
+                servers: Default::default(),
+                sequence: 5,
+            },
+            at(2),
+        );
+        session.receive(delta(6), at(3));
+        assert_eq!(session.topology_sequence(), 6);
+        assert!(session.take_requests().is_empty());
+
+        session.receive(delta(8), at(4));
+        assert_eq!(session.topology_sequence(), 0);
+        assert!(matches!(
+            &session.take_requests()[..],
+            [PlasmaRequestV1::RequestTopology {}]
+        ));
+    }
+
+    #[test]
+    fn negotiation() {
+        let mut session = PlasmaSession::new(at(0), 0);
+        session.connected(at(0));
+        assert!(matches!(
+            &session.take_versioned_requests(at(0))[..],
+            [PlasmaRequest::V1(PlasmaRequestV1::RegisterServer {
+                handshake: Some(_),
+                ..
+            })]
+        ));
+
+        let protocol = PlasmaHandshake::default()
+            .negotiate(&PlasmaHandshake::default())
+            .unwrap();
+        session.receive_versioned(
+            PlasmaUpdate::V1(vec![PlasmaUpdateV1::Handshake { protocol }].into_boxed_slice()),
+            at(1),
+        );
+        assert_eq!(session.protocol().version, ProtocolVersion::V2);
+
+        session.send(PlasmaRequestV1::RequestTopology {});
+        session.send(PlasmaRequestV1::RequestTopology {});
+        let requests = session.take_versioned_requests(at(0));
+        let ids: Vec<_> = requests
+            .into_iter()
+            .map(|r| r.into_parts().0.unwrap())
+            .collect();
+        // The first ID went to `RegisterServer`.
+        let first = RequestId::default().next();
+        assert_eq!(ids, [first, first.next()]);
+
+        // The first `RequestTopology` is answered, the second isn't.
+        session.receive_versioned(
+            PlasmaUpdate::V2(
+                vec![PlasmaUpdateV2 {
+                    correlation_id: Some(first),
+                    update: PlasmaUpdateV1::Topology {
+                        servers: Default::default(),
+                        sequence: 1,
+                    },
+                }]
+                .into_boxed_slice(),
+            ),
+            at(1),
+        );
+        assert!(session.poll(at(29)).is_empty());
+        assert!(matches!(
+            &session.poll(at(30))[..],
+            [PlasmaSessionEvent::RequestTimeout {
+                request_id,
+                request: PlasmaRequestV1::RequestTopology {}
+            }] if *request_id == first.next()
+        ));
+
+        // Reconnecting falls back to V1 until Plasma answers again.
+        session.connected(at(2));
+        assert_eq!(session.protocol().version, ProtocolVersion::V1);
+
+        // Requests sent as V1 are answered by the kind of update.
+        session.send(PlasmaRequestV1::RequestTopology {});
+        session.take_versioned_requests(at(2));
+        session.receive_versioned(
+            PlasmaUpdate::V1(
+                vec![PlasmaUpdateV1::Topology {
+                    servers: Default::default(),
+                    sequence: 2,
+                }]
+                .into_boxed_slice(),
+            ),
+            at(3),
+        );
+        // Which answered `RequestTopology`, leaving `RegisterServer`, which is answered
+        // by `Handshake` or `Snippets`.
+        assert!(matches!(
+            &session.poll(at(32))[..],
+            [PlasmaSessionEvent::RequestTimeout {
+                request: PlasmaRequestV1::RegisterServer { .. },
+                ..
+            }]
+        ));
+    }
+}
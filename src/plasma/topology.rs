@@ -2,12 +2,12 @@
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
 use super::RealmAcl;
-use crate::{is_default, ArenaId, RealmId, RegionId, SceneId};
+use crate::{is_default, ArenaId, RealmId, RegionId, SceneId, ServerId};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fmt::Debug;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug, Display, Formatter};
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
 /// A collection of arenas that share chat and, ideally, liveboard.
 pub struct RealmUseTopology {
     #[serde(default, skip_serializing_if = "is_default")]
@@ -83,3 +83,355 @@ impl ServerUseTopology {
         })
     }
 }
+
+/// Changes to the `servers` of a `Topology` update, sent instead of the full map.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TopologyDelta {
+    /// Sequence number of the resulting topology. Applies on top of `sequence - 1`.
+    pub sequence: u64,
+    /// Servers that were added, or whose datacenter or region changed, in full.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub added: HashMap<ServerId, ServerUseTopology>,
+    /// Servers that are no longer relevant.
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub removed: HashSet<ServerId>,
+    /// Changes to the realms of existing servers.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub changed: HashMap<ServerId, ServerUseTopologyDelta>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ServerUseTopologyDelta {
+    /// Realms that were added or changed.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub realms: HashMap<RealmId, RealmUseTopologyDelta>,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub removed_realms: HashSet<RealmId>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RealmUseTopologyDelta {
+    /// New ACL, if it changed or the realm was added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub acl: Option<RealmAcl>,
+    /// Scenes that were added or changed.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub scenes: HashMap<SceneId, SceneUseTopologyDelta>,
+    #[serde(default, skip_serializing_if = "HashSet::is_empty")]
+    pub removed_scenes: HashSet<SceneId>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SceneUseTopologyDelta {
+    /// New player count, if it changed or the scene was added.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub player_count: Option<u16>,
+    /// New settings, if they changed. `Some(Value::Null)` means they were cleared.
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_util::some_value",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub settings: Option<serde_json::Value>,
+}
+
+/// A `TopologyDelta` didn't follow the receiver's topology, so it should request
+/// a full `Topology`.
+#[derive(Debug, Clone)]
+pub struct TopologyGap {
+    pub expected: u64,
+    pub received: u64,
+    /// A server that the delta changed but the receiver doesn't have, even though the
+    /// sequence matched.
+    pub unknown: Option<ServerId>,
+}
+
+impl Display for TopologyGap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{self:?}")
+    }
+}
+
+impl std::error::Error for TopologyGap {}
+
+impl TopologyDelta {
+    /// Computes the changes from `old` to `new`, where `new` has sequence number `sequence`.
+    pub fn between(
+        old: &HashMap<ServerId, ServerUseTopology>,
+        new: &HashMap<ServerId, ServerUseTopology>,
+        sequence: u64,
+    ) -> Self {
+        let mut delta = Self {
+            sequence,
+            ..Default::default()
+        };
+        delta.removed = old
+            .keys()
+            .filter(|k| !new.contains_key(k))
+            .copied()
+            .collect();
+        for (server_id, new_server) in new {
+            let Some(old_server) = old.get(server_id) else {
+                delta.added.insert(*server_id, new_server.clone());
+                continue;
+            };
+            if old_server.datacenter != new_server.datacenter
+                || old_server.region_id != new_server.region_id
+            {
+                delta.added.insert(*server_id, new_server.clone());
+                continue;
+            }
+            let server_delta = ServerUseTopologyDelta::between(old_server, new_server);
+            if !server_delta.is_empty() {
+                delta.changed.insert(*server_id, server_delta);
+            }
+        }
+        delta
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+
+    /// Applies the changes to `servers`, which has sequence number `sequence`. On success,
+    /// `sequence` is advanced. On a gap, including a change to a server that `servers`
+    /// doesn't have, nothing is changed.
+    pub fn apply(
+        &self,
+        servers: &mut HashMap<ServerId, ServerUseTopology>,
+        sequence: &mut u64,
+    ) -> Result<(), TopologyGap> {
+        let expected = sequence.wrapping_add(1);
+        if *sequence == 0 || self.sequence != expected {
+            return Err(TopologyGap {
+                expected,
+                received: self.sequence,
+                unknown: None,
+            });
+        }
+        if let Some(unknown) = self.changed.keys().find(|server_id| {
+            !self.added.contains_key(server_id)
+                && (self.removed.contains(server_id) || !servers.contains_key(server_id))
+        }) {
+            return Err(TopologyGap {
+                expected,
+                received: self.sequence,
+                unknown: Some(*unknown),
+            });
+        }
+        for server_id in &self.removed {
+            servers.remove(server_id);
+        }
+        for (server_id, server) in &self.added {
+            servers.insert(*server_id, server.clone());
+        }
+        for (server_id, server_delta) in &self.changed {
+            server_delta.apply(servers.get_mut(server_id).unwrap());
+        }
+        *sequence = self.sequence;
+        Ok(())
+    }
+}
+
+impl ServerUseTopologyDelta {
+    fn between(old: &ServerUseTopology, new: &ServerUseTopology) -> Self {
+        let mut delta = Self::default();
+        for (realm_id, _) in old.realms() {
+            if new.realm(realm_id).is_none() {
+                delta.removed_realms.insert(realm_id);
+            }
+        }
+        for (realm_id, new_realm) in new.realms() {
+            let realm_delta = RealmUseTopologyDelta::between(old.realm(realm_id), new_realm);
+            if !realm_delta.is_empty() {
+                delta.realms.insert(realm_id, realm_delta);
+            }
+        }
+        delta
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.realms.is_empty() && self.removed_realms.is_empty()
+    }
+
+    fn apply(&self, server: &mut ServerUseTopology) {
+        for realm_id in &self.removed_realms {
+            if realm_id.is_public_default() {
+                server.default_realm = None;
+            } else {
+                server.other_realms.remove(realm_id);
+            }
+        }
+        for (realm_id, realm_delta) in &self.realms {
+            let realm = if realm_id.is_public_default() {
+                server
+                    .default_realm
+                    .get_or_insert_with(RealmUseTopology::default)
+            } else {
+                server.other_realms.entry(*realm_id).or_default()
+            };
+            realm_delta.apply(realm);
+        }
+    }
+}
+
+impl RealmUseTopologyDelta {
+    fn between(old: Option<&RealmUseTopology>, new: &RealmUseTopology) -> Self {
+        let mut delta = Self {
+            acl: (old.map(|old| &old.acl) != Some(&new.acl)).then(|| new.acl.clone()),
+            ..Default::default()
+        };
+        if let Some(old) = old {
+            delta.removed_scenes = old
+                .scenes
+                .keys()
+                .filter(|k| !new.scenes.contains_key(k))
+                .copied()
+                .collect();
+        }
+        for (scene_id, new_scene) in &new.scenes {
+            let old_scene = old.and_then(|old| old.scenes.get(scene_id));
+            let scene_delta = SceneUseTopologyDelta {
+                player_count: (old_scene.map(|s| s.player_count) != Some(new_scene.player_count))
+                    .then_some(new_scene.player_count),
+                settings: (old_scene.and_then(|s| s.settings.as_ref())
+                    != new_scene.settings.as_ref())
+                .then(|| new_scene.settings.clone().unwrap_or_default()),
+            };
+            if !scene_delta.is_empty() {
+                delta.scenes.insert(*scene_id, scene_delta);
+            }
+        }
+        delta
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.acl.is_none() && self.scenes.is_empty() && self.removed_scenes.is_empty()
+    }
+
+    fn apply(&self, realm: &mut RealmUseTopology) {
+        if let Some(acl) = &self.acl {
+            realm.acl = acl.clone();
+        }
+        for scene_id in &self.removed_scenes {
+            realm.scenes.remove(scene_id);
+        }
+        for (scene_id, scene_delta) in &self.scenes {
+            let scene = realm
+                .scenes
+                .entry(*scene_id)
+                .or_insert_with(|| SceneUseTopology {
+                    player_count: 0,
+                    settings: None,
+                });
+            if let Some(player_count) = scene_delta.player_count {
+                scene.player_count = player_count;
+            }
+            if let Some(settings) = &scene_delta.settings {
+                scene.settings = (!settings.is_null()).then(|| settings.clone());
+            }
+        }
+    }
+}
+
+impl SceneUseTopologyDelta {
+    pub fn is_empty(&self) -> bool {
+        self.player_count.is_none() && self.settings.is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        RealmAcl, RealmId, RealmUseTopology, RegionId, SceneId, SceneUseTopology, ServerId,
+        ServerUseTopology, TopologyDelta,
+    };
+    use std::collections::HashMap;
+    use std::str::FromStr;
+
+    fn server(player_count: u16, settings: Option<serde_json::Value>) -> ServerUseTopology {
+        ServerUseTopology {
+            datacenter: String::new(),
+            default_realm: Some(RealmUseTopology {
+                acl: RealmAcl::default(),
+                scenes: [(
+                    SceneId::default(),
+                    SceneUseTopology {
+                        player_count,
+                        settings,
+                    },
+                )]
+                .into_iter()
+                .collect(),
+            }),
+            other_realms: Default::default(),
+            region_id: RegionId::Europe,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let a = ServerId::from_str("Cloud/1").unwrap();
+        let b = ServerId::from_str("Cloud/2").unwrap();
+        let c = ServerId::from_str("Cloud/3").unwrap();
+        let old = HashMap::from([(a, server(5, None)), (b, server(3, None))]);
+        let mut new = HashMap::from([
+            (a, server(6, Some(serde_json::json!({"max_players": 10})))),
+            (c, server(0, None)),
+        ]);
+        new.get_mut(&a)
+            .unwrap()
+            .other_realms
+            .insert(RealmId::from_str("named/foo").unwrap(), Default::default());
+
+        let delta = TopologyDelta::between(&old, &new, 2);
+        assert_eq!(delta.removed.len(), 1);
+        assert_eq!(delta.added.len(), 1);
+        assert_eq!(delta.changed.len(), 1);
+
+        // Delta survives JSON.
+        let delta: TopologyDelta =
+            serde_json::from_str(&serde_json::to_string(&delta).unwrap()).unwrap();
+
+        let mut servers = old.clone();
+        let mut sequence = 1;
+        delta.apply(&mut servers, &mut sequence).unwrap();
+        assert_eq!(servers, new);
+        assert_eq!(sequence, 2);
+
+        // Clearing settings, which must also survive JSON.
+        let delta = TopologyDelta::between(&new, &old, 3);
+        let delta: TopologyDelta =
+            serde_json::from_str(&serde_json::to_string(&delta).unwrap()).unwrap();
+        delta.apply(&mut servers, &mut sequence).unwrap();
+        assert_eq!(servers, old);
+        assert!(TopologyDelta::between(&old, &old, 4).is_empty());
+    }
+
+    #[test]
+    fn gap() {
+        let mut servers = HashMap::new();
+        let mut sequence = 5;
+        let delta = TopologyDelta {
+            sequence: 7,
+            ..Default::default()
+        };
+        let gap = delta.apply(&mut servers, &mut sequence).unwrap_err();
+        assert_eq!((gap.expected, gap.received), (6, 7));
+        assert_eq!(sequence, 5);
+
+        // The sequence matches, but the changed server is unknown.
+        let a = ServerId::from_str("Cloud/1").unwrap();
+        let b = ServerId::from_str("Cloud/2").unwrap();
+        servers.insert(a, server(1, None));
+        let delta = TopologyDelta::between(
+            &HashMap::from([(b, server(1, None))]),
+            &HashMap::from([(b, server(2, None))]),
+            6,
+        );
+        let gap = delta.apply(&mut servers, &mut sequence).unwrap_err();
+        assert_eq!(gap.unknown, Some(b));
+        assert_eq!(sequence, 5);
+        assert_eq!(servers, HashMap::from([(a, server(1, None))]));
+    }
+}
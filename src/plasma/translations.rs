@@ -0,0 +1,459 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use super::TranslationsFile;
+use crate::{ChatMessage, LanguageDto, LanguageId};
+use std::collections::{BTreeMap, HashMap};
+
+/// Plural category of a count, after the CLDR rules of common languages. Plural
+/// phrases are stored under `{key}_{category}`, e.g. `kills_one` and `kills_other`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum PluralCategory {
+    Zero,
+    One,
+    Two,
+    Few,
+    Many,
+    Other,
+}
+
+impl PluralCategory {
+    pub fn new(language_id: LanguageId, count: u64) -> Self {
+        let (ones, tens) = (count % 10, count % 100);
+        match base_language(language_id).as_str() {
+            "ja" | "ko" | "zh" | "vi" | "th" | "id" | "ms" => Self::Other,
+            "fr" | "pt" | "hi" | "bn" if count <= 1 => Self::One,
+            "ru" | "uk" | "be" | "sr" | "hr" | "bs" => {
+                if ones == 1 && tens != 11 {
+                    Self::One
+                } else if (2..=4).contains(&ones) && !(12..=14).contains(&tens) {
+                    Self::Few
+                } else {
+                    Self::Many
+                }
+            }
+            "pl" if count == 1 => Self::One,
+            "pl" if (2..=4).contains(&ones) && !(12..=14).contains(&tens) => Self::Few,
+            "pl" => Self::Many,
+            "cs" | "sk" if (2..=4).contains(&count) => Self::Few,
+            "ar" => match count {
+                0 => Self::Zero,
+                1 => Self::One,
+                2 => Self::Two,
+                _ if (3..=10).contains(&tens) => Self::Few,
+                _ if tens >= 11 => Self::Many,
+                _ => Self::Other,
+            },
+            _ if count == 1 => Self::One,
+            _ => Self::Other,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Zero => "zero",
+            Self::One => "one",
+            Self::Two => "two",
+            Self::Few => "few",
+            Self::Many => "many",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Phrases that fall back to English in each language, see [`Translator::coverage`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TranslationCoverage {
+    /// Number of indexed phrases.
+    pub phrases: usize,
+    /// Sorted keys of untranslated phrases, for each language of the file.
+    pub missing: BTreeMap<LanguageId, Vec<String>>,
+}
+
+impl TranslationCoverage {
+    /// Fraction of phrases translated into `language_id`, from 0 to 1.
+    pub fn ratio(&self, language_id: LanguageId) -> f32 {
+        if self.phrases == 0 {
+            return 1.0;
+        }
+        let missing = self.missing.get(&language_id).map_or(0, Vec::len);
+        self.phrases.saturating_sub(missing) as f32 / self.phrases as f32
+    }
+}
+
+/// Indexes a [`TranslationsFile`] by `translation_id`, or by English text for
+/// phrases without one, and looks up phrases through a chain of fallback languages
+/// (e.g. `pt-BR`, then `pt`, then English).
+///
+/// `bulktext` phrases without a `translation_id`, and other phrases without either
+/// that or English text, can't be looked up and are skipped.
+#[derive(Clone, Debug, Default)]
+pub struct Translator {
+    languages: Box<[LanguageDto]>,
+    phrases: HashMap<String, HashMap<LanguageId, String>>,
+    fallbacks: HashMap<LanguageId, LanguageId>,
+}
+
+impl Translator {
+    pub fn new(file: &TranslationsFile) -> Self {
+        let english = LanguageId::default();
+        let phrases = file
+            .translations
+            .iter()
+            .filter_map(|translation| {
+                let key = translation.translation_id.clone().or_else(|| {
+                    let english = translation.translated_text.get(&english);
+                    english.filter(|_| !translation.bulktext).cloned()
+                })?;
+                Some((key, translation.translated_text.clone()))
+            })
+            .collect();
+        Self {
+            languages: file.languages.clone(),
+            phrases,
+            fallbacks: Default::default(),
+        }
+    }
+
+    /// Languages in the order menus should use.
+    pub fn languages(&self) -> &[LanguageDto] {
+        &self.languages
+    }
+
+    /// Makes `language_id` fall back to `fallback` before its base language, or
+    /// restores the default chain if `None`.
+    pub fn set_fallback(&mut self, language_id: LanguageId, fallback: Option<LanguageId>) {
+        if let Some(fallback) = fallback {
+            self.fallbacks.insert(language_id, fallback);
+        } else {
+            self.fallbacks.remove(&language_id);
+        }
+    }
+
+    /// Languages to try, in order, ending with English.
+    pub fn fallbacks(&self, language_id: LanguageId) -> Vec<LanguageId> {
+        let mut chain = vec![language_id];
+        let mut next = self.fallbacks.get(&language_id);
+        while let Some(&fallback) = next.filter(|f| !chain.contains(f)) {
+            chain.push(fallback);
+            next = self.fallbacks.get(&fallback);
+        }
+        for language_id in chain.clone() {
+            let base = base_language(language_id);
+            if !chain.contains(&base) {
+                chain.push(base);
+            }
+        }
+        if !chain.contains(&LanguageId::default()) {
+            chain.push(LanguageId::default());
+        }
+        chain
+    }
+
+    /// The phrase in the first language of [`Self::fallbacks`] that has it.
+    pub fn get(&self, key: &str, language_id: LanguageId) -> Option<&str> {
+        let translated_text = self.phrases.get(key)?;
+        self.fallbacks(language_id)
+            .into_iter()
+            .find_map(|l| translated_text.get(&l))
+            .map(String::as_str)
+    }
+
+    /// Like [`Self::get`], but falls back to the key itself, which is the English
+    /// text of phrases without a `translation_id`.
+    pub fn translate<'a>(&'a self, key: &'a str, language_id: LanguageId) -> &'a str {
+        self.get(key, language_id).unwrap_or(key)
+    }
+
+    /// Translates a phrase and fills in its `{placeholders}`.
+    pub fn format(
+        &self,
+        key: &str,
+        language_id: LanguageId,
+        parameters: &[(&str, String)],
+    ) -> String {
+        interpolate(self.translate(key, language_id), parameters)
+    }
+
+    /// Translates the plural form of a phrase for `count`, filling in `{count}` and
+    /// other placeholders. Each fallback language picks its own plural category,
+    /// then `other`, then the phrase under `key` itself.
+    pub fn format_plural(
+        &self,
+        key: &str,
+        count: u64,
+        language_id: LanguageId,
+        parameters: &[(&str, String)],
+    ) -> String {
+        let template = self
+            .fallbacks(language_id)
+            .into_iter()
+            .find_map(|l| {
+                let category = PluralCategory::new(l, count);
+                [category, PluralCategory::Other].into_iter().find_map(|c| {
+                    self.phrases
+                        .get(&format!("{key}_{}", c.as_str()))
+                        .and_then(|t| t.get(&l))
+                })
+            })
+            .map(String::as_str)
+            .unwrap_or_else(|| self.translate(key, language_id));
+        let mut parameters = parameters.to_vec();
+        parameters.push(("count", count.to_string()));
+        interpolate(template, &parameters)
+    }
+
+    /// Localized text of any [`ChatMessage`]. System messages use their
+    /// [`ChatPhrase`](crate::ChatPhrase), falling back to its English text, and raw
+    /// messages use [`ChatMessage::render`].
+    pub fn render_chat(&self, message: &ChatMessage, language_id: LanguageId) -> String {
+        let Some(phrase) = message.phrase() else {
+            return message
+                .render(language_id)
+                .map(|rendered| rendered.text.to_owned())
+                .unwrap_or_default();
+        };
+        let template = self
+            .get(phrase.translation_id, language_id)
+            .unwrap_or(phrase.english);
+        let mut parameters = message.parameters();
+        for (name, value) in &mut parameters {
+            if *name == "achievement" {
+                *value = self.translate(value, language_id).to_owned();
+            }
+        }
+        interpolate(template, &parameters)
+    }
+
+    /// Phrases that each language of the file would show in English. Falling back to
+    /// a language other than English, e.g. `pt-BR` to `pt`, counts as translated.
+    pub fn coverage(&self) -> TranslationCoverage {
+        let english = LanguageId::default();
+        let missing = self
+            .languages
+            .iter()
+            .map(|l| l.language_id)
+            .filter(|&l| l != english)
+            .map(|language_id| {
+                let chain = self.fallbacks(language_id);
+                let mut missing: Vec<_> = self
+                    .phrases
+                    .iter()
+                    .filter(|(_, t)| !chain.iter().any(|l| *l != english && t.contains_key(l)))
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                missing.sort_unstable();
+                (language_id, missing)
+            })
+            .collect();
+        TranslationCoverage {
+            phrases: self.phrases.len(),
+            missing,
+        }
+    }
+}
+
+/// The language of a regional variant, e.g. `pt` of `pt-BR`.
+fn base_language(language_id: LanguageId) -> LanguageId {
+    let s = language_id.as_str();
+    LanguageId::new(s.split(['-', '_']).next().unwrap_or(s))
+}
+
+/// Replaces each `{name}` in `template` with its value. Unknown names and
+/// unbalanced braces are kept as is.
+fn interpolate(template: &str, parameters: &[(&str, String)]) -> String {
+    let mut ret = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('}') else {
+            break;
+        };
+        ret.push_str(&rest[..start]);
+        let name = &after[..end];
+        if let Some((_, value)) = parameters.iter().find(|(n, _)| *n == name) {
+            ret.push_str(value);
+        } else {
+            ret.push_str(&rest[start..start + end + 2]);
+        }
+        rest = &after[end + 1..];
+    }
+    ret.push_str(rest);
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        ChatMessage, LanguageDto, LanguageId, PlayerAlias, PluralCategory, ServerNumber,
+        TranslationsDto, TranslationsFile, Translator,
+    };
+
+    fn dto(translation_id: Option<&str>, translated_text: &[(&str, &str)]) -> TranslationsDto {
+        TranslationsDto {
+            bulktext: false,
+            translation_id: translation_id.map(str::to_owned),
+            translated_text: translated_text
+                .iter()
+                .map(|(l, t)| (LanguageId::new(l), (*t).to_owned()))
+                .collect(),
+        }
+    }
+
+    fn translator() -> Translator {
+        let language = |language_id: &str| LanguageDto {
+            language_id: LanguageId::new(language_id),
+            language_name: language_id.to_owned(),
+        };
+        Translator::new(&TranslationsFile {
+            languages: [language("en"), language("es"), language("pt-BR")].into(),
+            translations: [
+                dto(None, &[("en", "Play"), ("es", "Jugar"), ("pt", "Jogar")]),
+                dto(Some("respawn"), &[("en", "Respawn"), ("pt-BR", "Renascer")]),
+                dto(
+                    Some("kills_one"),
+                    &[("en", "{count} kill"), ("ru", "{count} убийство")],
+                ),
+                dto(
+                    Some("kills_other"),
+                    &[("en", "{count} kills"), ("es", "{count} bajas")],
+                ),
+                dto(Some("kills_few"), &[("ru", "{count} убийства")]),
+                dto(Some("kills_many"), &[("ru", "{count} убийств")]),
+                dto(
+                    Some("chat_kill_streak"),
+                    &[("es", "¡{alias} lleva una racha de {streak}!")],
+                ),
+                dto(
+                    Some("chat_achievement"),
+                    &[("es", "{alias} obtuvo {achievement}")],
+                ),
+                dto(
+                    Some("achievement_sharpshooter"),
+                    &[("en", "Sharpshooter"), ("es", "Francotirador")],
+                ),
+                TranslationsDto {
+                    bulktext: true,
+                    ..dto(None, &[("en", "Lost")])
+                },
+            ]
+            .into(),
+        })
+    }
+
+    #[test]
+    fn lookup_and_fallbacks() {
+        let mut translator = translator();
+        let [en, es, pt_br] = ["en", "es", "pt-BR"].map(LanguageId::new);
+        assert_eq!(translator.translate("Play", es), "Jugar");
+        assert_eq!(translator.translate("Play", pt_br), "Jogar");
+        assert_eq!(translator.translate("respawn", pt_br), "Renascer");
+        assert_eq!(translator.translate("respawn", es), "Respawn");
+        assert_eq!(translator.get("Lost", en), None);
+        assert_eq!(translator.translate("Unknown", es), "Unknown");
+        assert_eq!(
+            translator.fallbacks(pt_br),
+            ["pt-BR", "pt", "en"].map(LanguageId::new)
+        );
+
+        // A custom chain, which must not loop.
+        translator.set_fallback(LanguageId::new("gl"), Some(es));
+        translator.set_fallback(es, Some(LanguageId::new("gl")));
+        assert_eq!(translator.translate("Play", LanguageId::new("gl")), "Jugar");
+        assert_eq!(
+            translator.fallbacks(es),
+            ["es", "gl", "en"].map(LanguageId::new)
+        );
+    }
+
+    #[test]
+    fn plurals() {
+        let translator = translator();
+        let [en, es, ru] = ["en", "es", "ru"].map(LanguageId::new);
+        assert_eq!(PluralCategory::new(ru, 21), PluralCategory::One);
+        assert_eq!(PluralCategory::new(ru, 12), PluralCategory::Many);
+        assert_eq!(
+            PluralCategory::new(LanguageId::new("fr"), 0),
+            PluralCategory::One
+        );
+        assert_eq!(
+            PluralCategory::new(LanguageId::new("ja"), 1),
+            PluralCategory::Other
+        );
+
+        let kills = |count, language_id| translator.format_plural("kills", count, language_id, &[]);
+        assert_eq!(kills(1, en), "1 kill");
+        assert_eq!(kills(2, en), "2 kills");
+        // Spanish has no `one`, so uses `other`.
+        assert_eq!(kills(1, es), "1 bajas");
+        assert_eq!(kills(3, ru), "3 убийства");
+        assert_eq!(kills(5, ru), "5 убийств");
+        assert_eq!(kills(21, ru), "21 убийство");
+    }
+
+    #[test]
+    fn render_chat() {
+        let translator = translator();
+        let [en, es, fr] = ["en", "es", "fr"].map(LanguageId::new);
+        let alias = PlayerAlias::new_unsanitized("Guest");
+
+        let streak = ChatMessage::KillStreak { alias, streak: 5 };
+        assert_eq!(
+            translator.render_chat(&streak, es),
+            "¡Guest lleva una racha de 5!"
+        );
+        assert_eq!(
+            translator.render_chat(&streak, fr),
+            "Guest is on a 5 kill streak!"
+        );
+
+        let achievement = ChatMessage::Achievement {
+            alias,
+            achievement: String::from("achievement_sharpshooter"),
+        };
+        assert_eq!(
+            translator.render_chat(&achievement, es),
+            "Guest obtuvo Francotirador"
+        );
+        assert_eq!(
+            translator.render_chat(&achievement, en),
+            "Guest earned Sharpshooter"
+        );
+
+        let welcome = ChatMessage::Welcome {
+            server_number: ServerNumber::new(3).unwrap(),
+            arena_id: Default::default(),
+        };
+        assert_eq!(translator.render_chat(&welcome, es), "Welcome to server 3!");
+        let raw = ChatMessage::Raw {
+            message: String::from("hola"),
+            detected_language_id: es,
+            english_translation: Some(String::from("hello")),
+        };
+        assert_eq!(translator.render_chat(&raw, en), "hello");
+    }
+
+    #[test]
+    fn coverage() {
+        let coverage = translator().coverage();
+        assert_eq!(coverage.phrases, 9);
+        assert_eq!(
+            coverage.missing.keys().copied().collect::<Vec<_>>(),
+            ["es", "pt-BR"].map(LanguageId::new)
+        );
+        assert_eq!(
+            coverage.missing[&LanguageId::new("es")],
+            ["kills_few", "kills_many", "kills_one", "respawn"]
+        );
+        assert_eq!(coverage.ratio(LanguageId::new("pt-BR")), 2.0 / 9.0);
+        assert_eq!(coverage.ratio(LanguageId::default()), 1.0);
+    }
+
+    #[test]
+    fn interpolate() {
+        let parameters = [("a", String::from("1"))];
+        assert_eq!(super::interpolate("{a}+{a}", &parameters), "1+1");
+        assert_eq!(super::interpolate("{b} {a", &parameters), "{b} {a");
+        assert_eq!(super::interpolate("}{}", &parameters), "}{}");
+    }
+}
@@ -1,11 +1,15 @@
 // SPDX-FileCopyrightText: 2024 Softbear, Inc.
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use super::{ChatRecipient, ClaimUpdateDto, DomainDto, ServerRole, ServerUseTopology, Snippet};
+use super::{
+    AuthenticationFailure, ChatPolicy, ChatRecipient, ClaimUpdateDto, DomainDto, LeaderboardDiff,
+    NegotiatedProtocol, ProtocolVersion, Sanction, ServerRole, ServerUseTopology, Snippet,
+    TopologyDelta,
+};
 use crate::{
-    is_default, ArenaId, ArenaToken, ChatId, ChatMessage, LeaderboardScoreDto, NickName, PeriodId,
-    PlayerAlias, PlayerId, RealmId, Referrer, ServerId, SessionToken, TeamName, TeamToken,
-    VisitorId,
+    is_default, ArenaId, ArenaToken, ChatId, ChatMessage, LanguageId, LeaderboardScoreDto,
+    NickName, PeriodId, PlayerAlias, PlayerId, RealmId, Referrer, RequestId, ServerId,
+    SessionToken, TeamName, TeamToken, VisitorId,
 };
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -21,6 +25,62 @@ pub enum PlasmaUpdate {
         #[serde(deserialize_with = "crate::serde_util::box_slice_skip_invalid")]
         Box<[PlasmaUpdateV1]>,
     ),
+    /// Version 2 protocol, only sent if negotiated.
+    V2(
+        #[serde(deserialize_with = "crate::serde_util::box_slice_skip_invalid")]
+        Box<[PlasmaUpdateV2]>,
+    ),
+}
+
+impl PlasmaUpdate {
+    /// Wraps updates in the envelope of `version`, dropping what it can't carry.
+    pub fn new(version: ProtocolVersion, updates: Vec<PlasmaUpdateV2>) -> Self {
+        match version {
+            ProtocolVersion::V1 => Self::V1(updates.into_iter().map(|u| u.update).collect()),
+            ProtocolVersion::V2 => Self::V2(updates.into_boxed_slice()),
+        }
+    }
+
+    pub fn version(&self) -> ProtocolVersion {
+        match self {
+            Self::V1(_) => ProtocolVersion::V1,
+            Self::V2(_) => ProtocolVersion::V2,
+        }
+    }
+
+    /// Unwraps updates of any version, e.g. for code that predates correlation IDs.
+    pub fn into_v1(self) -> Box<[PlasmaUpdateV1]> {
+        match self {
+            Self::V1(updates) => updates,
+            Self::V2(updates) => updates.into_vec().into_iter().map(|u| u.update).collect(),
+        }
+    }
+
+    /// Unwraps updates of any version. V1 updates have no correlation ID.
+    pub fn into_v2(self) -> Box<[PlasmaUpdateV2]> {
+        match self {
+            Self::V1(updates) => updates.into_vec().into_iter().map(Into::into).collect(),
+            Self::V2(updates) => updates,
+        }
+    }
+}
+
+/// An update, possibly in response to a particular request.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlasmaUpdateV2 {
+    /// The request this update answers, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<RequestId>,
+    pub update: PlasmaUpdateV1,
+}
+
+impl From<PlasmaUpdateV1> for PlasmaUpdateV2 {
+    fn from(update: PlasmaUpdateV1) -> Self {
+        Self {
+            correlation_id: None,
+            update,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -33,6 +93,16 @@ pub enum PlasmaUpdateV1 {
         /// Uniquely identifies the arena, for idempotency.
         arena_token: ArenaToken,
     },
+    /// Sent in response to [`AuthenticatePlayer`] instead of [`Player`] or [`Claims`]
+    /// if the player can't be authenticated.
+    AuthenticationFailed {
+        #[serde(default, skip_serializing_if = "is_default")]
+        arena_id: ArenaId,
+        player_id: PlayerId,
+        reason: AuthenticationFailure,
+        /// The `session_token` that was used by `AuthenticatePlayer`.
+        session_token: SessionToken,
+    },
     /// Sent after [`SendChat`] on on same or another server,
     /// providing the profanity filter passes.
     Chat {
@@ -62,6 +132,20 @@ pub enum PlasmaUpdateV1 {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         visitor_id: Option<VisitorId>,
     },
+    /// Sent after [`ModerateChat`] to servers hosting the realm.
+    ChatPolicy {
+        #[serde(default, skip_serializing_if = "is_default")]
+        realm_id: RealmId,
+        policy: ChatPolicy,
+    },
+    /// Sent after [`TranslateChat`], to be cached in the [`ChatMessage`].
+    ChatTranslation {
+        chat_id: ChatId,
+        language_id: LanguageId,
+        /// `None` if the chat is unknown or couldn't be translated.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        translation: Option<String>,
+    },
     /// Sent for non-signed in players after [`AuthenticatePlayer`].
     /// May also be sent for any player after [`Heartbeat`].
     Claims {
@@ -75,6 +159,11 @@ pub enum PlasmaUpdateV1 {
     //
     // {} is for backward compatibility
     Heartbeat {},
+    /// Sent in response to [`RegisterServer`] with a handshake. Servers that don't
+    /// receive it must assume [`ProtocolVersion::V1`] without capabilities.
+    Handshake {
+        protocol: NegotiatedProtocol,
+    },
     /// The leaderboard for the specified period.  This is sent
     /// in response to [`RegisterServer`], and also when a leaderboard
     /// has changed due to [`UpdateLeaderboards`] from any game server.
@@ -86,6 +175,23 @@ pub enum PlasmaUpdateV1 {
         realm_id: RealmId,
         /// The scores that made the leaderboard.
         scores: Box<[LeaderboardScoreDto]>,
+        /// Best scores of each alias at the 1st through 99th percentiles of the period,
+        /// lowest first, for estimating the rank of players that didn't make `scores`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        percentiles: Option<Box<[u32]>>,
+    },
+    /// Sent instead of [`Leaderboard`] when a leaderboard changed, to servers that
+    /// negotiated [`PlasmaCapability::LeaderboardDiff`](super::PlasmaCapability). Applies
+    /// to the scores of the last `Leaderboard` or `LeaderboardDiff` of the same realm
+    /// and period.
+    LeaderboardDiff {
+        period_id: PeriodId,
+        #[serde(default, skip_serializing_if = "is_default")]
+        realm_id: RealmId,
+        diff: LeaderboardDiff,
+        /// Replaces the percentiles of the last update.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        percentiles: Option<Box<[u32]>>,
     },
     /// Sent after [`SendServerMessage`] from another server which is of the
     /// same kind (local/cloud).
@@ -138,6 +244,12 @@ pub enum PlasmaUpdateV1 {
         /// Just stop wrapping in `Some`.
         role: ServerRole,
     },
+    /// Sanctions to add or replace, see [`SanctionIndex::insert`](super::SanctionIndex::insert).
+    /// Every active sanction is sent in response to [`RegisterServer`], and changes
+    /// are sent as they happen.
+    Sanctions {
+        sanctions: Box<[Sanction]>,
+    },
     Snippets {
         snippets: Box<[Snippet]>,
     },
@@ -152,6 +264,17 @@ pub enum PlasmaUpdateV1 {
         /// Proof that the team name was reserved.
         team_token: TeamToken,
     },
+    /// Sent in response to [`ReserveTeamName`] if the team name is reserved by
+    /// another team or otherwise not allowed.
+    TeamNameUnavailable {
+        /// Arena ID of requestor.
+        #[serde(default, skip_serializing_if = "is_default")]
+        arena_id: ArenaId,
+        /// Player ID of requestor.
+        player_id: PlayerId,
+        /// The team name that was requested.
+        team_name: TeamName,
+    },
     /// List of all game servers relevant to the recipient game server.
     /// Relevant means the recipient hosts something that players of
     /// recipient could be redirected or travel to. This implies matching
@@ -177,6 +300,14 @@ pub enum PlasmaUpdateV1 {
     Topology {
         #[serde(default, skip_serializing_if = "HashMap::is_empty")]
         servers: HashMap<ServerId, ServerUseTopology>,
+        /// Sequence number for subsequent [`TopologyDelta`]s, or 0 if they won't be sent.
+        #[serde(default, skip_serializing_if = "is_default")]
+        sequence: u64,
+    },
+    /// Changes since the previous [`Topology`] or [`TopologyDelta`]. If the sequence
+    /// number doesn't follow, the server should send [`RequestTopology`].
+    TopologyDelta {
+        delta: TopologyDelta,
     },
     /// Which items to track in metrics.
     Track {
@@ -0,0 +1,164 @@
+// SPDX-FileCopyrightText: 2024 Softbear, Inc.
+// SPDX-License-Identifier: AGPL-3.0-or-later
+
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+
+/// Version of the envelope around `PlasmaRequest` and `PlasmaUpdate`.
+#[derive(
+    Copy, Clone, Debug, Default, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize,
+)]
+#[cfg_attr(feature = "plasma_bitcode", derive(bitcode::Encode, bitcode::Decode))]
+pub enum ProtocolVersion {
+    /// No request or correlation IDs.
+    #[default]
+    V1,
+    /// Requests carry a `RequestId` that updates may refer to.
+    V2,
+}
+
+impl ProtocolVersion {
+    /// Versions this crate can speak, oldest first.
+    pub const SUPPORTED: &'static [Self] = &[Self::V1, Self::V2];
+
+    pub fn latest() -> Self {
+        *Self::SUPPORTED.last().unwrap()
+    }
+}
+
+/// Optional behavior that either side may lack during a rollout.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
+pub enum PlasmaCapability {
+    /// Frames may be encoded with bitcode, see `FrameEncoding`.
+    Bitcode,
+    /// Server can apply `LeaderboardDiff`, so it is sent instead of `Leaderboard` after
+    /// the first.
+    LeaderboardDiff,
+    /// Server can apply `TopologyDelta` and send `RequestTopology`.
+    TopologyDelta,
+}
+
+impl PlasmaCapability {
+    /// Capabilities this crate implements.
+    #[cfg(not(feature = "plasma_bitcode"))]
+    pub const SUPPORTED: &'static [Self] = &[Self::LeaderboardDiff, Self::TopologyDelta];
+    /// Capabilities this crate implements.
+    #[cfg(feature = "plasma_bitcode")]
+    pub const SUPPORTED: &'static [Self] =
+        &[Self::Bitcode, Self::LeaderboardDiff, Self::TopologyDelta];
+}
+
+/// Sent by a server in `RegisterServer` and answered by Plasma with the negotiated
+/// subset in `Handshake`.
+///
+/// Unknown versions and capabilities are ignored, so either side may be newer.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PlasmaHandshake {
+    #[serde(deserialize_with = "crate::serde_util::box_slice_skip_invalid")]
+    pub versions: Box<[ProtocolVersion]>,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_util::box_slice_skip_invalid",
+        skip_serializing_if = "<[_]>::is_empty"
+    )]
+    pub capabilities: Box<[PlasmaCapability]>,
+}
+
+impl Default for PlasmaHandshake {
+    /// Everything this crate supports.
+    fn default() -> Self {
+        Self {
+            versions: ProtocolVersion::SUPPORTED.into(),
+            capabilities: PlasmaCapability::SUPPORTED.into(),
+        }
+    }
+}
+
+impl PlasmaHandshake {
+    /// Handshake of a peer that predates negotiation.
+    pub fn legacy() -> Self {
+        Self {
+            versions: [ProtocolVersion::V1].into(),
+            capabilities: Default::default(),
+        }
+    }
+
+    /// The newest common version and the common capabilities, or `None` if there
+    /// is no common version.
+    pub fn negotiate(&self, other: &Self) -> Option<NegotiatedProtocol> {
+        let version = self
+            .versions
+            .iter()
+            .filter(|v| other.versions.contains(v))
+            .max()
+            .copied()?;
+        let mut capabilities: Vec<_> = self
+            .capabilities
+            .iter()
+            .filter(|c| other.capabilities.contains(c))
+            .copied()
+            .collect();
+        capabilities.sort();
+        capabilities.dedup();
+        Some(NegotiatedProtocol {
+            version,
+            capabilities: capabilities.into(),
+        })
+    }
+}
+
+/// Result of [`PlasmaHandshake::negotiate`].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NegotiatedProtocol {
+    pub version: ProtocolVersion,
+    #[serde(
+        default,
+        deserialize_with = "crate::serde_util::box_slice_skip_invalid",
+        skip_serializing_if = "<[_]>::is_empty"
+    )]
+    pub capabilities: Box<[PlasmaCapability]>,
+}
+
+impl NegotiatedProtocol {
+    pub fn has(&self, capability: PlasmaCapability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{PlasmaCapability, PlasmaHandshake, ProtocolVersion};
+
+    #[test]
+    fn negotiate() {
+        let ours = PlasmaHandshake::default();
+        let negotiated = ours.negotiate(&ours).unwrap();
+        assert_eq!(negotiated.version, ProtocolVersion::latest());
+        assert!(negotiated.has(PlasmaCapability::TopologyDelta));
+
+        let negotiated = ours.negotiate(&PlasmaHandshake::legacy()).unwrap();
+        assert_eq!(negotiated.version, ProtocolVersion::V1);
+        assert!(!negotiated.has(PlasmaCapability::TopologyDelta));
+
+        let none = PlasmaHandshake {
+            versions: Default::default(),
+            capabilities: Default::default(),
+        };
+        assert!(ours.negotiate(&none).is_none());
+    }
+
+    #[test]
+    fn unknown_values_are_ignored() {
+        let handshake: PlasmaHandshake = serde_json::from_str(
+            r#"{"versions":["V1","V2","V9"],"capabilities":["TopologyDelta","Telepathy"]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            handshake,
+            PlasmaHandshake {
+                versions: [ProtocolVersion::V1, ProtocolVersion::V2].into(),
+                capabilities: [PlasmaCapability::TopologyDelta].into(),
+            }
+        );
+    }
+}
@@ -1,7 +1,7 @@
 // SPDX-FileCopyrightText: 2024 Softbear, Inc.
 // SPDX-License-Identifier: AGPL-3.0-or-later
 
-use serde::Deserializer;
+use serde::{Deserialize, Deserializer};
 
 pub fn box_slice_skip_invalid<'de, T: serde::de::DeserializeOwned, D>(
     deserializer: D,
@@ -39,3 +39,12 @@ where
     let visitor = SeqVisitor::<T>(std::marker::PhantomData);
     deserializer.deserialize_seq(visitor)
 }
+
+/// Keeps a present `null` as `Some(Value::Null)` instead of `None`. Combine with
+/// `#[serde(default)]` so that only a missing field is `None`.
+pub fn some_value<'de, D>(deserializer: D) -> Result<Option<serde_json::Value>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    serde_json::Value::deserialize(deserializer).map(Some)
+}